@@ -0,0 +1,129 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use images::jpeg::encode::Encoder;
+use images::jpeg::{DecodeOptions, JPEGHeader};
+
+const FIXTURES: [&str; 3] = ["cat.jpg", "gradient_1080p.jpg", "gradient_4k.jpg"];
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = format!("{}/benches/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+
+    std::fs::read(path).unwrap()
+}
+
+/// Parsing plus Huffman decoding of the whole scan. Throughput is reported
+/// against the file size, which is dominated by the scan data.
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    group.sample_size(10);
+
+    for name in FIXTURES {
+        let stream = fixture(name);
+
+        group.throughput(Throughput::Bytes(stream.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &stream, |b, stream| {
+            b.iter_batched(
+                || stream.clone(),
+                |stream| JPEGHeader::new(stream).unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+/// Only the segments, the scan is left undecoded. Measures the marker
+/// walk, which for the 4K image is mostly skipping over the scan
+fn header(c: &mut Criterion) {
+    let mut group = c.benchmark_group("header");
+
+    for name in FIXTURES {
+        let stream = fixture(name);
+
+        group.throughput(Throughput::Bytes(stream.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &stream, |b, stream| {
+            b.iter(|| JPEGHeader::new_lazy(stream).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+/// Dequantization, IDCT and color conversion of an already parsed header.
+/// Throughput is reported in pixels
+fn pixels(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pixels");
+    group.sample_size(10);
+
+    for name in FIXTURES {
+        let header = JPEGHeader::from_bytes(&fixture(name)).unwrap();
+        let (width, height) = header.output_dimensions();
+
+        group.throughput(Throughput::Elements((width * height) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &header, |b, header| {
+            b.iter_batched(
+                || header.clone(),
+                |mut header| header.decode_pixels().unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+/// Sequential against parallel decoding of an image with a restart marker
+/// every 64 MCUs
+fn parallel(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parallel");
+    group.sample_size(10);
+
+    let stream = fixture("restart_4096x3072.jpg");
+
+    group.throughput(Throughput::Bytes(stream.len() as u64));
+
+    for parallel in [false, true] {
+        let options = DecodeOptions::default().parallel(parallel);
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(parallel),
+            &stream,
+            |b, stream| b.iter(|| JPEGHeader::with_options(stream, options.clone()).unwrap()),
+        );
+    }
+
+    group.finish();
+}
+
+/// Full decode of an all white image, where every block only has a DC
+/// coefficient and skips the IDCT
+fn flat(c: &mut Criterion) {
+    let mut group = c.benchmark_group("flat");
+    group.sample_size(10);
+
+    let (width, height) = (1920, 1080);
+    let mut stream = Vec::new();
+    Encoder::new(90)
+        .encode(
+            &vec![255; width as usize * height as usize * 3],
+            width,
+            height,
+            3,
+            &mut stream,
+        )
+        .unwrap();
+
+    group.throughput(Throughput::Elements(width as u64 * height as u64));
+    group.bench_function("white_1080p", |b| {
+        b.iter_batched(
+            || JPEGHeader::from_bytes(&stream).unwrap(),
+            |mut header| header.decode_pixels().unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, decode, header, pixels, parallel, flat);
+criterion_main!(benches);
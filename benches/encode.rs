@@ -0,0 +1,71 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use images::jpeg::analyze;
+use images::jpeg::encode::Encoder;
+use images::jpeg::{JPEGHeader, QTable, QTableType};
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = format!("{}/benches/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+
+    std::fs::read(path).unwrap()
+}
+
+/// Encoding decoded pixels from scratch. Throughput is reported against the
+/// size of the pixels
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    group.sample_size(10);
+
+    let mut header = JPEGHeader::new(fixture("gradient_1080p.jpg")).unwrap();
+    let (width, height) = header.output_dimensions();
+    let pixels = header.decode_pixels().unwrap();
+
+    group.throughput(Throughput::Bytes(pixels.len() as u64));
+    group.bench_function("gradient_1080p", |b| {
+        b.iter(|| {
+            let mut bytes = Vec::new();
+            Encoder::new(85)
+                .encode(
+                    black_box(&pixels),
+                    width as u16,
+                    height as u16,
+                    3,
+                    &mut bytes,
+                )
+                .unwrap();
+            bytes
+        })
+    });
+
+    group.finish();
+}
+
+/// Paths that re-encode or measure coefficients with existing Huffman tables
+fn reencode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reencode");
+    group.sample_size(10);
+
+    let header = JPEGHeader::new(fixture("cat.jpg")).unwrap();
+    let tables = [
+        QTable::from_quality(50, QTableType::Luminance),
+        QTable::from_quality(50, QTableType::Chrominance),
+        QTable::default(),
+        QTable::default(),
+    ];
+
+    group.bench_function("requantize", |b| {
+        b.iter_batched(
+            || header.clone(),
+            |mut header| header.requantize(&tables).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.bench_function("estimate_size", |b| {
+        b.iter(|| analyze::estimate_file_size_at_quality(&header, 50).unwrap())
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, encode, reencode);
+criterion_main!(benches);
@@ -0,0 +1,41 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use images::jpeg::{aan_idct_2d, naive_idct_2d};
+
+/// A block with energy spread over the low frequencies, like a typical
+/// dequantized block
+fn block() -> [i32; 64] {
+    let mut block = [0; 64];
+
+    for (i, coefficient) in block.iter_mut().enumerate() {
+        let (u, v) = (i % 8, i / 8);
+        *coefficient = (240 / (1 + u + v)) as i32 * if (u + v) % 2 == 0 { 1 } else { -1 };
+    }
+
+    block
+}
+
+fn idct(c: &mut Criterion) {
+    let mut group = c.benchmark_group("idct");
+    let block = block();
+
+    group.bench_function("aan", |b| {
+        b.iter(|| {
+            let mut block = black_box(block);
+            aan_idct_2d(&mut block);
+            block
+        })
+    });
+
+    group.bench_function("naive", |b| {
+        b.iter(|| {
+            let mut block = black_box(block);
+            naive_idct_2d(&mut block);
+            block
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, idct);
+criterion_main!(benches);
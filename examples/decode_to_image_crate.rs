@@ -0,0 +1,37 @@
+//! Decodes a JPEG and saves it as a PNG through the `image` crate.
+//!
+//! cargo run --example decode_to_image_crate --features image-interop -- cat.jpg cat.png
+
+use images::jpeg::JPEGHeader;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let input = args.next().unwrap_or_else(|| "cat.jpg".to_string());
+    let output = args.next().unwrap_or_else(|| "cat.png".to_string());
+
+    let data = std::fs::read(&input).expect("Could not read the input file");
+
+    let image = match JPEGHeader::from_bytes(&data).and_then(image::RgbImage::try_from) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    image::save_buffer(
+        &output,
+        image.as_raw(),
+        image.width(),
+        image.height(),
+        image::ExtendedColorType::Rgb8,
+    )
+    .expect("Could not save the output file");
+
+    println!(
+        "Saved {}x{} image to {}",
+        image.width(),
+        image.height(),
+        output
+    );
+}
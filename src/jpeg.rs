@@ -1,6 +1,23 @@
-#![allow(unused_imports)]
-mod error;
-mod header;
-
-pub use error::*;
-pub use header::JPEGHeader;
+#![allow(unused_imports)]
+pub mod analyze;
+pub mod diagnostics;
+pub mod encode;
+mod error;
+mod header;
+mod idct;
+#[cfg(feature = "image-interop")]
+mod interop;
+mod metadata;
+#[cfg(feature = "png-output")]
+mod png;
+pub mod quick_parse;
+mod zigzag;
+
+pub use error::*;
+pub use header::{
+    ColorSpace, CorruptionHint, CorruptionReason, DCOverflow, DecodeOptions, DecodeReport,
+    HuffmanTable, HuffmanTableSet, JPEGHeader, JPEGHeaderBuilder, JPEGPixelReader, LazyJPEGHeader,
+    Marker, OutputFormat, PixelFormat, QTable, QTableType, Rect, Rotation, ScaleFactor,
+};
+pub use idct::{aan_idct_1d, aan_idct_2d, naive_idct_2d, reduced_idct_2d};
+pub use metadata::{ImageMetadata, JfifMetadata};
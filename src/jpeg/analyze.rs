@@ -0,0 +1,29 @@
+//! Estimates for planning work on a JPEG before doing it.
+
+use super::error::Result;
+use super::header::JPEGHeader;
+
+/// Approximate size in bytes of the file [`JPEGHeader::requantize`] would
+/// produce with the IJG tables for `quality`, clamped to 1 - 100. The
+/// coefficients are requantized and their Huffman symbols counted against
+/// the Annex K tables, but no scan is written. The estimate is usually
+/// within a few percent of what `requantize` writes. Compared to encoding
+/// the decoded pixels afresh at `quality`, expect about 20% either way for
+/// natural images
+///
+/// # Examples
+///
+/// ```no_run
+/// use images::jpeg::{analyze, JPEGHeader};
+///
+/// let data = std::fs::read("photo.jpg").unwrap();
+/// let jpeg = JPEGHeader::from_bytes(&data).unwrap();
+///
+/// for quality in [90, 75, 50] {
+///     let size = analyze::estimate_file_size_at_quality(&jpeg, quality).unwrap();
+///     println!("{quality}: ~{size} bytes");
+/// }
+/// ```
+pub fn estimate_file_size_at_quality(header: &JPEGHeader, quality: u8) -> Result<usize> {
+    header.estimate_size_at_quality(quality)
+}
@@ -0,0 +1,184 @@
+//! Tools for looking at the structure of a JPEG without decoding it.
+
+use super::error::{Error, Result};
+use super::header::Marker;
+
+/// A marker found in the stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MarkerInfo {
+    /// Offset of the 0xFF starting the marker
+    pub byte_offset: usize,
+    pub marker: u8,
+    /// Segment length, including its own two bytes. `None` for standalone
+    /// markers and for a length cut off by the end of the stream
+    pub length: Option<u16>,
+}
+
+pub struct JPEGDiagnostics;
+
+impl JPEGDiagnostics {
+    /// Lists every marker in a single pass. Segment payloads are skipped
+    /// using their length, stuffed 0xFF00 bytes and fill bytes in the scan
+    /// data are not reported. Never panics, whatever the input
+    pub fn dump_markers(stream: &[u8]) -> Vec<MarkerInfo> {
+        let mut markers = Vec::new();
+        let mut offset = 0;
+
+        while offset + 1 < stream.len() {
+            let marker = stream[offset + 1];
+
+            // Stuffed bytes, fill bytes and scan data
+            if stream[offset] != 0xFF || marker == 0x00 || marker == 0xFF {
+                offset += 1;
+                continue;
+            }
+
+            let length = if Self::is_standalone(marker) {
+                None
+            } else {
+                stream
+                    .get(offset + 2..offset + 4)
+                    .map(|length| u16::from_be_bytes([length[0], length[1]]))
+            };
+
+            markers.push(MarkerInfo {
+                byte_offset: offset,
+                marker,
+                length,
+            });
+
+            // Lengths below 2 are invalid, step over the marker alone
+            offset += 2 + length.filter(|length| *length >= 2).unwrap_or(0) as usize;
+        }
+
+        markers
+    }
+
+    /// SOI, EOI, RSTn and TEM have no length field
+    fn is_standalone(marker: u8) -> bool {
+        matches!(marker, 0x01 | 0xD0..=0xD9)
+    }
+}
+
+/// Lazily walks the markers of a JPEG. Each item is the marker and its raw
+/// bytes: the marker code without the leading 0xFF, followed by the length
+/// and the payload for markers that have one. Entropy coded data, stuffed
+/// 0xFF00 bytes and fill bytes are stepped over. Iteration stops after EOI
+/// or the first error
+///
+/// # Examples
+///
+/// ```
+/// use images::jpeg::{diagnostics::MarkerScanner, Marker};
+///
+/// let data = [0xFF, 0xD8, 0xFF, 0xFE, 0x00, 0x04, b'h', b'i', 0xFF, 0xD9];
+/// let markers: Vec<_> = MarkerScanner::new(&data).collect::<Result<_, _>>().unwrap();
+///
+/// assert_eq!(
+///     markers,
+///     [
+///         (Marker::SOI, &[0xD8][..]),
+///         (Marker::COM, &[0xFE, 0x00, 0x04, b'h', b'i'][..]),
+///         (Marker::EOI, &[0xD9][..]),
+///     ]
+/// );
+/// ```
+///
+/// Pulling out the quantization tables only
+///
+/// ```no_run
+/// use images::jpeg::{diagnostics::MarkerScanner, Marker};
+///
+/// let data = std::fs::read("photo.jpg").unwrap();
+///
+/// for item in MarkerScanner::new(&data) {
+///     let (marker, bytes) = item.unwrap();
+///
+///     if marker == Marker::DQT {
+///         // Skip the code and the length
+///         println!("DQT payload: {:?}", &bytes[3..]);
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MarkerScanner<'a> {
+    data: &'a [u8],
+    offset: usize,
+    is_done: bool,
+}
+
+impl<'a> MarkerScanner<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            is_done: false,
+        }
+    }
+
+    fn next_marker(&mut self) -> Option<Result<(Marker, &'a [u8])>> {
+        // Entropy coded data, stuffed bytes and fill bytes
+        let start = loop {
+            let pair = self.data.get(self.offset..self.offset + 2)?;
+
+            if pair[0] == 0xFF && pair[1] != 0x00 && pair[1] != 0xFF {
+                break self.offset + 1;
+            }
+
+            self.offset += 1;
+        };
+
+        let code = self.data[start];
+
+        let marker = match Marker::new(code) {
+            Some(marker) => marker,
+            None => {
+                return Some(Err(Error::UnknownMarker {
+                    byte: code,
+                    offset: start - 1,
+                }))
+            }
+        };
+
+        let end = if marker.is_standalone() {
+            start + 1
+        } else {
+            let length = match self.data.get(start + 1..start + 3) {
+                Some(length) => u16::from_be_bytes([length[0], length[1]]) as usize,
+                None => return Some(Err(Error::PrematureEnd)),
+            };
+
+            // The length counts its own two bytes
+            if length < 2 {
+                return Some(Err(Error::InvalidMarker));
+            }
+
+            start + 1 + length
+        };
+
+        let bytes = match self.data.get(start..end) {
+            Some(bytes) => bytes,
+            None => return Some(Err(Error::PrematureEnd)),
+        };
+
+        self.offset = end;
+
+        Some(Ok((marker, bytes)))
+    }
+}
+
+impl<'a> Iterator for MarkerScanner<'a> {
+    type Item = Result<(Marker, &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.is_done {
+            return None;
+        }
+
+        let item = self.next_marker();
+
+        self.is_done = !matches!(item, Some(Ok((marker, _))) if marker != Marker::EOI);
+
+        item
+    }
+}
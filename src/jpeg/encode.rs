@@ -0,0 +1,468 @@
+//! Baseline JFIF encoder. Writes 4:4:4 YCbCr or grayscale images using the
+//! example tables from Annex K of the specification.
+
+use super::header::{HuffmanTable, QTable, MCU};
+use super::zigzag::{ZIGZAG, ZIGZAG_INV};
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+use std::io::{self, Write};
+
+/// Annex K.1 luminance quantization table in natural order
+pub(super) const LUMINANCE_QTABLE: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113,
+    92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+/// Annex K.1 chrominance quantization table in natural order
+pub(super) const CHROMINANCE_QTABLE: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+// Annex K.3 Huffman tables. Number of codes of each length followed by the
+// symbols in code order
+pub(super) const DC_LUMINANCE_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+pub(super) const DC_LUMINANCE_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+pub(super) const DC_CHROMINANCE_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+pub(super) const DC_CHROMINANCE_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+pub(super) const AC_LUMINANCE_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D];
+pub(super) const AC_LUMINANCE_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+    0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5,
+    0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2,
+    0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+pub(super) const AC_CHROMINANCE_BITS: [u8; 16] =
+    [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+pub(super) const AC_CHROMINANCE_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52, 0xF0,
+    0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18, 0x19, 0x1A, 0x26,
+    0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5,
+    0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3,
+    0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA,
+    0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+/// Scales a standard table following the IJG quality formula
+#[must_use = "the scaled table is returned and the input is left as is"]
+pub(super) fn scale_qtable(table: &[u16; 64], quality: u8) -> [u16; 64] {
+    let quality = quality.clamp(1, 100) as u32;
+
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - 2 * quality
+    };
+
+    let mut scaled = [0; 64];
+
+    for (entry, standard) in scaled.iter_mut().zip(table) {
+        *entry = ((*standard as u32 * scale + 50) / 100).clamp(1, 255) as u16;
+    }
+
+    scaled
+}
+
+/// Packs bits most significant first, stuffing a 0x00 after every 0xFF
+pub(super) struct BitWriter {
+    data: Vec<u8>,
+    buffer: u32,
+    length: u8,
+    stuff: bool,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            buffer: 0,
+            length: 0,
+            stuff: true,
+        }
+    }
+
+    /// Leaves 0xFF bytes as they are, like the scan data a JPEGHeader keeps
+    pub(super) fn unstuffed() -> Self {
+        Self {
+            stuff: false,
+            ..Self::new()
+        }
+    }
+
+    /// Bytes written so far, not counting a partial last byte
+    pub(super) fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn write_bits(&mut self, bits: u16, length: u8) {
+        let mask = (1 << length) - 1;
+        self.buffer = (self.buffer << length) | (bits as u32 & mask);
+        self.length += length;
+
+        while self.length >= 8 {
+            let byte = (self.buffer >> (self.length - 8)) as u8;
+            self.data.push(byte);
+
+            if byte == 0xFF && self.stuff {
+                self.data.push(0x00);
+            }
+
+            self.length -= 8;
+        }
+
+        self.buffer &= (1 << self.length) - 1;
+    }
+
+    /// Writes nothing for a symbol without a code
+    fn write_code(&mut self, table: &HuffmanTable, symbol: u8) {
+        let (code, length) = table.code_for_symbol(symbol).unwrap_or_default();
+        self.write_bits(code as u16, length);
+    }
+
+    /// Pads the last byte with 1s
+    pub(super) fn align(&mut self) {
+        if self.length > 0 {
+            let padding = 8 - self.length;
+            self.write_bits((1 << padding) - 1, padding);
+        }
+    }
+
+    pub(super) fn finish(mut self) -> Vec<u8> {
+        self.align();
+        self.data
+    }
+}
+
+/// Number of bits needed for `value` and the bits themselves, with negative
+/// values stored as one's complement
+fn magnitude(value: i32) -> (u8, u16) {
+    let size = (32 - value.unsigned_abs().leading_zeros()) as u8;
+
+    let bits = if value < 0 {
+        value + (1 << size) - 1
+    } else {
+        value
+    };
+
+    (size, bits as u16)
+}
+
+fn write_segment(writer: &mut impl Write, marker: u8, payload: &[u8]) -> io::Result<()> {
+    let length = (payload.len() + 2) as u16;
+
+    writer.write_all(&[0xFF, marker])?;
+    writer.write_all(&length.to_be_bytes())?;
+    writer.write_all(payload)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Encoder {
+    quality: u8,
+    luminance: [u16; 64],
+    chrominance: [u16; 64],
+}
+
+impl Encoder {
+    /// Quality is on the IJG scale and clamped to 1 - 100
+    #[must_use = "an Encoder does nothing until `encode` is called"]
+    pub fn new(quality: u8) -> Encoder {
+        let quality = quality.clamp(1, 100);
+
+        Self {
+            quality,
+            luminance: scale_qtable(&LUMINANCE_QTABLE, quality),
+            chrominance: scale_qtable(&CHROMINANCE_QTABLE, quality),
+        }
+    }
+
+    /// Encodes interleaved 8 bit pixels. `channels` is 3 for RGB or 1 for
+    /// grayscale
+    #[must_use = "the image may only be partially written on error"]
+    pub fn encode(
+        &self,
+        pixels: &[u8],
+        width: u16,
+        height: u16,
+        channels: u8,
+        writer: &mut impl Write,
+    ) -> io::Result<()> {
+        fn invalid(message: &str) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, message))
+        }
+
+        if channels != 1 && channels != 3 {
+            return invalid("Only 1 or 3 channels are supported");
+        }
+
+        if width == 0 || height == 0 {
+            return invalid("Image has width or height set to zero");
+        }
+
+        if pixels.len() != width as usize * height as usize * channels as usize {
+            return invalid("Pixel data does not match the image dimensions");
+        }
+
+        let is_color = channels == 3;
+
+        // SOI
+        writer.write_all(&[0xFF, 0xD8])?;
+
+        // APP0. JFIF 1.01, no units, 1:1 density, no thumbnail
+        write_segment(
+            writer,
+            0xE0,
+            &[
+                b'J', b'F', b'I', b'F', 0x00, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00,
+            ],
+        )?;
+
+        // DQT
+        self.write_qtable(writer, 0, &self.luminance)?;
+
+        if is_color {
+            self.write_qtable(writer, 1, &self.chrominance)?;
+        }
+
+        // SOF0
+        let mut sof = vec![0x08];
+        sof.extend_from_slice(&height.to_be_bytes());
+        sof.extend_from_slice(&width.to_be_bytes());
+        sof.push(channels);
+
+        for id in 1..=channels {
+            let qtable = if id == 1 { 0x00 } else { 0x01 };
+            sof.extend_from_slice(&[id, 0x11, qtable]);
+        }
+
+        write_segment(writer, 0xC0, &sof)?;
+
+        // DHT
+        Self::write_htable(writer, 0x00, &DC_LUMINANCE_BITS, &DC_LUMINANCE_VALUES)?;
+        Self::write_htable(writer, 0x10, &AC_LUMINANCE_BITS, &AC_LUMINANCE_VALUES)?;
+
+        if is_color {
+            Self::write_htable(writer, 0x01, &DC_CHROMINANCE_BITS, &DC_CHROMINANCE_VALUES)?;
+            Self::write_htable(writer, 0x11, &AC_CHROMINANCE_BITS, &AC_CHROMINANCE_VALUES)?;
+        }
+
+        // SOS
+        let mut sos = vec![channels];
+
+        for id in 1..=channels {
+            let tables = if id == 1 { 0x00 } else { 0x11 };
+            sos.extend_from_slice(&[id, tables]);
+        }
+
+        sos.extend_from_slice(&[0x00, 0x3F, 0x00]);
+        write_segment(writer, 0xDA, &sos)?;
+
+        writer.write_all(&self.encode_scan(pixels, width, height, is_color))?;
+
+        // EOI
+        writer.write_all(&[0xFF, 0xD9])
+    }
+
+    fn write_qtable(&self, writer: &mut impl Write, id: u8, table: &[u16; 64]) -> io::Result<()> {
+        let mut payload = vec![id];
+
+        for index in ZIGZAG {
+            payload.push(table[index as usize] as u8);
+        }
+
+        write_segment(writer, 0xDB, &payload)
+    }
+
+    fn write_htable(
+        writer: &mut impl Write,
+        info: u8,
+        bits: &[u8; 16],
+        values: &[u8],
+    ) -> io::Result<()> {
+        let mut payload = vec![info];
+        payload.extend_from_slice(bits);
+        payload.extend_from_slice(values);
+
+        write_segment(writer, 0xC4, &payload)
+    }
+
+    fn encode_scan(&self, pixels: &[u8], width: u16, height: u16, is_color: bool) -> Vec<u8> {
+        let dc_luminance = HuffmanTable::new(&DC_LUMINANCE_BITS, &DC_LUMINANCE_VALUES);
+        let ac_luminance = HuffmanTable::new(&AC_LUMINANCE_BITS, &AC_LUMINANCE_VALUES);
+        let dc_chrominance = HuffmanTable::new(&DC_CHROMINANCE_BITS, &DC_CHROMINANCE_VALUES);
+        let ac_chrominance = HuffmanTable::new(&AC_CHROMINANCE_BITS, &AC_CHROMINANCE_VALUES);
+
+        let qtables = [
+            QTable::new(self.luminance),
+            QTable::new(self.chrominance),
+            QTable::new(self.chrominance),
+        ];
+
+        let cosines = dct_cosines();
+        let channels = if is_color { 3 } else { 1 };
+        let (width, height) = (width as usize, height as usize);
+
+        let mut writer = BitWriter::new();
+        let mut previous_dc = [0; 3];
+
+        for mcu_y in 0..height.div_ceil(8) {
+            for mcu_x in 0..width.div_ceil(8) {
+                let mut blocks = [[0.0; 64]; 3];
+                let [luma, blue, red] = &mut blocks;
+
+                let samples = luma.iter_mut().zip(blue.iter_mut()).zip(red.iter_mut());
+
+                for (i, ((luma, blue), red)) in samples.enumerate() {
+                    // Edge MCUs repeat the last row and column
+                    let x = (mcu_x * 8 + i % 8).min(width - 1);
+                    let y = (mcu_y * 8 + i / 8).min(height - 1);
+                    let pixel = &pixels[(y * width + x) * channels..][..channels];
+
+                    if is_color {
+                        let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+
+                        *luma = 0.299 * r + 0.587 * g + 0.114 * b - 128.0;
+                        *blue = -0.168_736 * r - 0.331_264 * g + 0.5 * b;
+                        *red = 0.5 * r - 0.418_688 * g - 0.081_312 * b;
+                    } else {
+                        *luma = pixel[0] as f32 - 128.0;
+                    }
+                }
+
+                let mut mcu = MCU::default();
+
+                for (component, block) in blocks.iter_mut().take(channels).enumerate() {
+                    let (dc_table, ac_table) = if component == 0 {
+                        (&dc_luminance, &ac_luminance)
+                    } else {
+                        (&dc_chrominance, &ac_chrominance)
+                    };
+
+                    forward_dct(block, &cosines);
+
+                    for (coefficient, value) in mcu.component(component).iter_mut().zip(&*block) {
+                        *coefficient = value.round() as i32;
+                    }
+
+                    mcu.quantize(&qtables[component], component);
+
+                    // Stored in zigzag order, ready for entropy coding
+                    let mut quantized = [0; 64];
+
+                    for (i, coefficient) in mcu.channel(component).iter().enumerate() {
+                        quantized[ZIGZAG_INV[i] as usize] = *coefficient;
+                    }
+
+                    Self::encode_block(
+                        &mut writer,
+                        &quantized,
+                        &mut previous_dc[component],
+                        dc_table,
+                        ac_table,
+                    );
+                }
+            }
+        }
+
+        writer.finish()
+    }
+
+    /// `block` holds quantized coefficients in zigzag order
+    pub(super) fn encode_block(
+        writer: &mut BitWriter,
+        block: &[i32; 64],
+        previous_dc: &mut i32,
+        dc_table: &HuffmanTable,
+        ac_table: &HuffmanTable,
+    ) {
+        let (size, bits) = magnitude(block[0] - *previous_dc);
+        *previous_dc = block[0];
+
+        writer.write_code(dc_table, size);
+        writer.write_bits(bits, size);
+
+        let mut zeros = 0;
+
+        for &coefficient in &block[1..] {
+            if coefficient == 0 {
+                zeros += 1;
+                continue;
+            }
+
+            // 0xF0 is a run of 16 zeros
+            while zeros > 15 {
+                writer.write_code(ac_table, 0xF0);
+                zeros -= 16;
+            }
+
+            let (size, bits) = magnitude(coefficient);
+
+            writer.write_code(ac_table, (zeros << 4) | size);
+            writer.write_bits(bits, size);
+
+            zeros = 0;
+        }
+
+        // End of block
+        if zeros > 0 {
+            writer.write_code(ac_table, 0x00);
+        }
+    }
+}
+
+/// Encodes RGB pixels at quality 50, where the Annex K tables are used as
+/// they are. A shorthand for [`Encoder::encode`] when the pixels are already
+/// grouped and the whole file is wanted in memory
+pub fn encode_rgb(pixels: &[[u8; 3]], width: u16, height: u16) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    Encoder::new(50).encode(pixels.as_flattened(), width, height, 3, &mut data)?;
+
+    Ok(data)
+}
+
+/// `C(u) / 2 * cos((2x + 1) * u * PI / 16)` indexed by `[u][x]`
+fn dct_cosines() -> [[f32; 8]; 8] {
+    let mut cosines = [[0.0; 8]; 8];
+
+    for (u, row) in cosines.iter_mut().enumerate() {
+        let scale = if u == 0 { FRAC_1_SQRT_2 } else { 1.0 };
+
+        for (x, cosine) in row.iter_mut().enumerate() {
+            *cosine = scale / 2.0 * (((2 * x + 1) * u) as f32 * PI / 16.0).cos();
+        }
+    }
+
+    cosines
+}
+
+/// Separable forward DCT over the rows then the columns
+fn forward_dct(block: &mut [f32; 64], cosines: &[[f32; 8]; 8]) {
+    let mut rows = [0.0; 64];
+
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).map(|x| cosines[u][x] * block[y * 8 + x]).sum();
+        }
+    }
+
+    for v in 0..8 {
+        for u in 0..8 {
+            block[v * 8 + u] = (0..8).map(|y| cosines[v][y] * rows[y * 8 + u]).sum();
+        }
+    }
+}
@@ -1,236 +1,487 @@
-use std::{error, fmt::Display};
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum SOF0MarkerError {
-    MissingNextByte,
-    InvalidComponentNumber,
-    ZeroDimensions,
-    InvalidComponentID,
-    ComponentAlreadySet,
-    UnsupportedComponentQTable,
-    InvalidMarkerLength,
-    InvalidPrecision,
-    NoComponentSet,
-}
-
-impl Display for SOF0MarkerError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Start of Frame Error: {}",
-            match self {
-                Self::InvalidComponentID => "Component has invalid or unsupported id",
-                Self::ComponentAlreadySet => "Tried to overwrite set component",
-                Self::InvalidMarkerLength =>
-                    "Stated Marker Length does not match actual component length",
-                Self::UnsupportedComponentQTable => "Component uses unsupported QTable",
-                Self::InvalidPrecision => "Marker has invalid precision",
-                Self::ZeroDimensions => "Marker has width or height set to zero",
-                Self::MissingNextByte => "Missing next byte in marker",
-                Self::InvalidComponentNumber => "Number of components is invalid or unsupported",
-                Self::NoComponentSet => "No component was set by marker",
-            }
-        )
-    }
-}
-
-impl error::Error for SOF0MarkerError {}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum DQTError {
-    MissingNextByte,
-    InvalidTableDestination,
-    NoTableSet,
-}
-
-impl Display for DQTError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Define Quantization Table Error: {}",
-            match self {
-                Self::MissingNextByte => "Missing next byte in marker",
-                Self::InvalidTableDestination => "QTable Destination is greater than 0x03",
-                Self::NoTableSet => "Marker did not set any QTable",
-            }
-        )
-    }
-}
-
-impl error::Error for DQTError {}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum DHTError {
-    MissingNextByte,
-    InvalidMarkerLength,
-    InvalidTableId,
-    InvalidSymbolsLength,
-    NoTableSet,
-}
-
-impl Display for DHTError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Define Huffman Table Error: {}.",
-            match self {
-                Self::MissingNextByte => "Missing next byte in marker",
-                Self::InvalidMarkerLength => "Stated marker length does not match actual length",
-                Self::InvalidTableId => "A table has an invalid table ID",
-                Self::InvalidSymbolsLength => "A table has more symbols than allowed",
-                Self::NoTableSet => "No Huffman table was set by marker",
-            }
-        )
-    }
-}
-
-impl error::Error for DHTError {}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum SOSError {
-    MissingNextByte,
-    InvalidMarkerLength,
-    InvalidOrder,
-    InvalidComponentNumber,
-    InvalidComponentID,
-    DuplicateComponentID,
-    InvalidHuffmanTableID,
-    InvalidSpectralSelection,
-    InvalidSuccesiveApproximation,
-}
-
-impl Display for SOSError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Start of Scan Error: {}.",
-            match self {
-                Self::MissingNextByte => "Missing next byte in marker",
-                Self::InvalidMarkerLength => "Stated marker length does not match actual length",
-                Self::InvalidOrder => "Start of Scan reached before Start of Frame",
-                Self::InvalidComponentNumber => "Invalid number of components",
-                Self::InvalidComponentID => "Invalid component ID",
-                Self::DuplicateComponentID => "Multiple components have the same id",
-                Self::InvalidHuffmanTableID => "A Huffman table id greater than 3 was reached",
-                Self::InvalidSpectralSelection =>
-                    "Either the starting or ending spectral selection is out of bounds",
-                Self::InvalidSuccesiveApproximation =>
-                    "The successive approximation is out of bounds",
-            }
-        )
-    }
-}
-
-impl error::Error for SOSError {}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum HuffmanDecodingError {
-    ReadPastLength,
-    SymbolNotFound,
-    InvalidDCCoefficientLength,
-    ZerosExceedMCULength,
-    InvalidACCoefficientLength,
-}
-
-impl Display for HuffmanDecodingError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Self::ReadPastLength => "Entire Huffman bit stream read",
-                Self::SymbolNotFound => "Symbol not found after reading past 16 bits",
-                Self::InvalidDCCoefficientLength => "DC coefficient had length greater than 11",
-                Self::InvalidACCoefficientLength => "AC coefficient had length greater than 10",
-                Self::ZerosExceedMCULength => "AC Table Zeroes exceeded run length of MCU",
-            }
-        )
-    }
-}
-
-impl error::Error for HuffmanDecodingError {}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum Error {
-    StartOfImageNotFound,
-    StartOfFrameNotFound,
-    QTableNotFound,
-    HTableNotFound,
-    SOSNotFound,
-    InvalidMarker,
-    UnknownMarker(u8),
-    MultipleSOI,
-    MultipleSOF,
-    InvalidAPP0Marker,
-    InvalidDQTMarker(DQTError),
-    InvalidSOF0Marker(SOF0MarkerError),
-    InvalidDHTMarker(DHTError),
-    InvalidSOSMarker(SOSError),
-    NoData,
-    InvalidRestartIntervalMarker,
-    RestartMarkerBeforeSOS,
-    EndOfImageBeforeSOS,
-    PrematureEnd,
-    InvalidColorComponent,
-    HuffmanDecode(HuffmanDecodingError),
-}
-
-impl Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "JPEG Error: {}",
-            match self {
-                Self::StartOfImageNotFound => "JPEG has no Start of Image marker".to_string(),
-                Self::StartOfFrameNotFound => "JPEG has no Start of Frame marker".to_string(),
-                Self::QTableNotFound => "JPEG has no DQT marker".to_string(),
-                Self::HTableNotFound => "JPEG has no DHT marker".to_string(),
-                Self::InvalidColorComponent =>
-                    "A color component was not correctly set".to_string(),
-                Self::SOSNotFound => "JPEG has no SOS marker".to_string(),
-                Self::NoData => "No Data after Start of Image marker".to_string(),
-                Self::PrematureEnd => "The file ended prematurely".to_string(),
-                Self::InvalidMarker => "A 0xFF was found with no code after it".to_string(),
-                Self::InvalidAPP0Marker => "The APP0 marker has invalid data".to_string(),
-                Self::InvalidRestartIntervalMarker => "The DRI marker has invalid data".to_string(),
-                Self::InvalidDQTMarker(source) =>
-                    format!("The DQT marker has invalid data. {}", source),
-                Self::InvalidSOF0Marker(source) =>
-                    format!("The baseline SOF marker has invalid data. {}", source),
-                Self::InvalidDHTMarker(source) =>
-                    format!("The DHT marker has invalid data. {}", source),
-                Self::InvalidSOSMarker(source) =>
-                    format!("The SOS marker has invalid data. {}", source),
-                Self::UnknownMarker(marker) =>
-                    format!("An unknown marker 0x{:04X} was encountered", marker),
-                Self::MultipleSOI => "Encountered multiple Start of Image markers".to_string(),
-                Self::MultipleSOF => "Encountered multiple Start of Frame markers".to_string(),
-                Self::RestartMarkerBeforeSOS =>
-                    "Encountered a Restart Marker before a Start of Scan marker".to_string(),
-                Self::EndOfImageBeforeSOS =>
-                    "Encountered an End of Image marker before a Start of Scan marker".to_string(),
-                Self::HuffmanDecode(source) => source.to_string(),
-            }
-        )
-    }
-}
-
-impl error::Error for Error {
-    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        match self {
-            Self::InvalidSOF0Marker(source) => Some(source),
-            Self::InvalidDQTMarker(source) => Some(source),
-            Self::InvalidDHTMarker(source) => Some(source),
-            Self::InvalidSOSMarker(source) => Some(source),
-            _ => None,
-        }
-    }
-}
-
-impl From<HuffmanDecodingError> for Error {
-    fn from(value: HuffmanDecodingError) -> Self {
-        Error::HuffmanDecode(value)
-    }
-}
-
-pub type Result<T> = core::result::Result<T, Error>;
+use std::{error, fmt::Display};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SOF0MarkerError {
+    MissingNextByte,
+    InvalidComponentNumber,
+    /// Width or height is zero
+    ///
+    /// ```
+    /// use images::jpeg::{Error, JPEGHeader, SOF0MarkerError};
+    ///
+    /// // SOI, then a grayscale frame 8 pixels wide and 0 high
+    /// let data = [
+    ///     0xFF, 0xD8, 0xFF, 0xC0, 0x00, 0x0B, 0x08, 0x00, 0x00, 0x00, 0x08, 0x01, 0x01, 0x11,
+    ///     0x00,
+    /// ];
+    ///
+    /// assert_eq!(
+    ///     JPEGHeader::from_bytes(&data).unwrap_err(),
+    ///     Error::InvalidSOF0Marker(SOF0MarkerError::ZeroDimensions)
+    /// );
+    /// ```
+    ZeroDimensions,
+    InvalidComponentID,
+    ComponentAlreadySet,
+    UnsupportedComponentQTable,
+    InvalidMarkerLength,
+    InvalidPrecision(u8),
+    NoComponentSet,
+    /// A component's horizontal or vertical sampling factor is outside 1 - 4
+    InvalidSamplingFactor,
+}
+
+impl Display for SOF0MarkerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Start of Frame Error: {}",
+            match self {
+                Self::InvalidComponentID => "Component has invalid or unsupported id".to_string(),
+                Self::ComponentAlreadySet => "Tried to overwrite set component".to_string(),
+                Self::InvalidMarkerLength =>
+                    "Stated Marker Length does not match actual component length".to_string(),
+                Self::UnsupportedComponentQTable => "Component uses unsupported QTable".to_string(),
+                Self::InvalidPrecision(precision) => format!(
+                    "Marker has precision 0x{:02X}, only 8 bit samples are supported",
+                    precision
+                ),
+                Self::ZeroDimensions => "Marker has width or height set to zero".to_string(),
+                Self::MissingNextByte => "Missing next byte in marker".to_string(),
+                Self::InvalidComponentNumber =>
+                    "Number of components is invalid or unsupported".to_string(),
+                Self::NoComponentSet => "No component was set by marker".to_string(),
+                Self::InvalidSamplingFactor =>
+                    "Component has a sampling factor outside 1 - 4".to_string(),
+            }
+        )
+    }
+}
+
+impl error::Error for SOF0MarkerError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DQTError {
+    MissingNextByte,
+    InvalidTableDestination,
+    NoTableSet,
+}
+
+impl Display for DQTError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Define Quantization Table Error: {}",
+            match self {
+                Self::MissingNextByte => "Missing next byte in marker",
+                Self::InvalidTableDestination => "QTable Destination is greater than 0x03",
+                Self::NoTableSet => "Marker did not set any QTable",
+            }
+        )
+    }
+}
+
+impl error::Error for DQTError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DHTError {
+    MissingNextByte,
+    InvalidMarkerLength,
+    InvalidTableId,
+    InvalidSymbolsLength,
+    NoTableSet,
+    NonCanonicalTable,
+}
+
+impl Display for DHTError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Define Huffman Table Error: {}.",
+            match self {
+                Self::MissingNextByte => "Missing next byte in marker",
+                Self::InvalidMarkerLength => "Stated marker length does not match actual length",
+                Self::InvalidTableId => "A table has an invalid table ID",
+                Self::InvalidSymbolsLength => "A table has more symbols than allowed",
+                Self::NoTableSet => "No Huffman table was set by marker",
+                Self::NonCanonicalTable => "A table has more codes of a length than fit",
+            }
+        )
+    }
+}
+
+impl error::Error for DHTError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SOSError {
+    MissingNextByte,
+    InvalidMarkerLength,
+    MissingSOFBeforeSOS,
+    InvalidComponentNumber,
+    InvalidComponentID,
+    DuplicateComponentID,
+    InvalidHuffmanTableID,
+    InvalidSpectralSelection,
+    InvalidSuccesiveApproximation,
+}
+
+impl Display for SOSError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Start of Scan Error: {}.",
+            match self {
+                Self::MissingNextByte => "Missing next byte in marker",
+                Self::InvalidMarkerLength => "Stated marker length does not match actual length",
+                Self::MissingSOFBeforeSOS =>
+                    "Reached a Start of Scan marker without a Start of Frame before it",
+                Self::InvalidComponentNumber => "Invalid number of components",
+                Self::InvalidComponentID => "Invalid component ID",
+                Self::DuplicateComponentID => "Multiple components have the same id",
+                Self::InvalidHuffmanTableID => "A Huffman table id greater than 3 was reached",
+                Self::InvalidSpectralSelection =>
+                    "Either the starting or ending spectral selection is out of bounds",
+                Self::InvalidSuccesiveApproximation =>
+                    "The successive approximation is out of bounds",
+            }
+        )
+    }
+}
+
+impl error::Error for SOSError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HuffmanDecodingError {
+    ReadPastLength,
+    SymbolNotFound,
+    InvalidDCCoefficientLength,
+    ZerosExceedMCULength,
+    InvalidACCoefficientLength,
+    UnexpectedEndOfMCU { mcu_index: usize, component: usize },
+    DCCoefficientOverflow { mcu: usize, value: i32 },
+}
+
+impl Display for HuffmanDecodingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::ReadPastLength => "Entire Huffman bit stream read".to_string(),
+                Self::SymbolNotFound => "Symbol not found after reading past 16 bits".to_string(),
+                Self::InvalidDCCoefficientLength =>
+                    "DC coefficient had length greater than 11".to_string(),
+                Self::InvalidACCoefficientLength =>
+                    "AC coefficient had length greater than 10".to_string(),
+                Self::ZerosExceedMCULength =>
+                    "AC Table Zeroes exceeded run length of MCU".to_string(),
+                Self::UnexpectedEndOfMCU {
+                    mcu_index,
+                    component,
+                } => format!(
+                    "Huffman bit stream ended in MCU {} while decoding component {}",
+                    mcu_index, component
+                ),
+                Self::DCCoefficientOverflow { mcu, value } => format!(
+                    "DC coefficient {} in MCU {} is outside -1024 - 1023",
+                    value, mcu
+                ),
+            }
+        )
+    }
+}
+
+impl error::Error for HuffmanDecodingError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitReaderError {
+    OffsetOutOfBounds,
+}
+
+impl Display for BitReaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::OffsetOutOfBounds => "Tried to seek past the end of the Huffman data",
+            }
+        )
+    }
+}
+
+impl error::Error for BitReaderError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    StartOfImageNotFound,
+    StartOfFrameNotFound,
+    QTableNotFound,
+    HTableNotFound,
+    SOSNotFound,
+    InvalidMarker,
+    UnknownMarker {
+        byte: u8,
+        /// Offset of the 0xFF starting the marker
+        offset: usize,
+    },
+    MultipleSOI,
+    MultipleSOF,
+    InvalidAPP0Marker,
+    InvalidAPP1Marker,
+    InvalidAPP2Marker,
+    InvalidAPP14Marker,
+    InvalidCOMMarker,
+    InvalidDQTMarker(DQTError),
+    InvalidSOF0Marker(SOF0MarkerError),
+    InvalidDHTMarker(DHTError),
+    InvalidSOSMarker(SOSError),
+    NoData,
+    InvalidRestartIntervalMarker,
+    RestartMarkerBeforeSOS,
+    EndOfImageBeforeSOS,
+    EarlyEndOfImage,
+    PrematureEnd,
+    InvalidColorComponent,
+    ComponentQTableMismatch {
+        component_id: u8,
+        qtable_id: u8,
+    },
+    /// The largest sampling factors aren't a multiple of the component's, so
+    /// its samples can't be repeated to fill the MCU
+    UnsupportedSubsampling {
+        component_id: u8,
+        hfactor: u8,
+        vfactor: u8,
+    },
+    HuffmanDecode(HuffmanDecodingError),
+    BitReader(BitReaderError),
+    ArithmeticCodingNotSupported,
+    InvalidQTableId(usize),
+    InvalidQTable,
+    ImageSmallerThanBlock,
+    InvalidComment,
+    InvalidRegion,
+    InvalidScanData,
+    SegmentTooLong {
+        marker: u8,
+    },
+    Io(std::io::ErrorKind),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "JPEG Error: {}",
+            match self {
+                Self::StartOfImageNotFound => "JPEG has no Start of Image marker".to_string(),
+                Self::StartOfFrameNotFound => "JPEG has no Start of Frame marker".to_string(),
+                Self::QTableNotFound => "JPEG has no DQT marker".to_string(),
+                Self::HTableNotFound => "JPEG has no DHT marker".to_string(),
+                Self::InvalidColorComponent =>
+                    "A color component was not correctly set".to_string(),
+                Self::ComponentQTableMismatch {
+                    component_id,
+                    qtable_id,
+                } => format!(
+                    "Component {} uses QTable {}, which was never defined",
+                    component_id, qtable_id
+                ),
+                Self::UnsupportedSubsampling {
+                    component_id,
+                    hfactor,
+                    vfactor,
+                } => format!(
+                    "Component {} has sampling factors {}x{}, which don't divide the largest ones",
+                    component_id, hfactor, vfactor
+                ),
+                Self::SOSNotFound => "JPEG has no SOS marker".to_string(),
+                Self::NoData => "No Data after Start of Image marker".to_string(),
+                Self::PrematureEnd => "The file ended prematurely".to_string(),
+                Self::InvalidMarker => "A 0xFF was found with no code after it".to_string(),
+                Self::InvalidAPP0Marker => "The APP0 marker has invalid data".to_string(),
+                Self::InvalidAPP1Marker => "The APP1 marker has invalid data".to_string(),
+                Self::InvalidAPP2Marker => "The APP2 marker has invalid data".to_string(),
+                Self::InvalidAPP14Marker => "The APP14 marker has invalid data".to_string(),
+                Self::InvalidCOMMarker => "The COM marker has invalid data".to_string(),
+                Self::InvalidRestartIntervalMarker => "The DRI marker has invalid data".to_string(),
+                Self::InvalidDQTMarker(source) =>
+                    format!("The DQT marker has invalid data. {}", source),
+                Self::InvalidSOF0Marker(source) =>
+                    format!("The baseline SOF marker has invalid data. {}", source),
+                Self::InvalidDHTMarker(source) =>
+                    format!("The DHT marker has invalid data. {}", source),
+                Self::InvalidSOSMarker(source) =>
+                    format!("The SOS marker has invalid data. {}", source),
+                Self::UnknownMarker { byte, offset } => format!(
+                    "An unknown marker 0x{:02X} at byte offset {} was encountered",
+                    byte, offset
+                ),
+                Self::MultipleSOI => "Encountered multiple Start of Image markers".to_string(),
+                Self::MultipleSOF => "Encountered multiple Start of Frame markers".to_string(),
+                Self::RestartMarkerBeforeSOS =>
+                    "Encountered a Restart Marker before a Start of Scan marker".to_string(),
+                Self::EndOfImageBeforeSOS =>
+                    "Encountered an End of Image marker before a Start of Scan marker".to_string(),
+                Self::EarlyEndOfImage =>
+                    "The image ended before a Start of Frame marker".to_string(),
+                Self::HuffmanDecode(source) =>
+                    format!("The scan data could not be Huffman decoded. {}", source),
+                Self::BitReader(source) => source.to_string(),
+                Self::ArithmeticCodingNotSupported =>
+                    "Arithmetic coded JPEGs are not supported, only Huffman coding is".to_string(),
+                Self::InvalidQTableId(id) =>
+                    format!("QTable id {} is out of range, only 0 - 3 exist", id),
+                Self::InvalidQTable =>
+                    "A QTable used by a component is not set or has a zero entry".to_string(),
+                Self::ImageSmallerThanBlock =>
+                    "The image is smaller than a block in a direction that gets mirrored"
+                        .to_string(),
+                Self::InvalidComment =>
+                    "A comment has a null byte or does not fit in a COM segment".to_string(),
+                Self::InvalidRegion => "The region reaches past the image".to_string(),
+                Self::Io(kind) => format!("Writing the decoded image failed: {}", kind),
+                Self::SegmentTooLong { marker } => format!(
+                    "The payload of a 0x{:02X} segment does not fit in 65533 bytes",
+                    marker
+                ),
+                Self::InvalidScanData =>
+                    "The scan data is too short for the frame, holds a marker or has data after an EOI marker"
+                        .to_string(),
+            }
+        )
+    }
+}
+
+impl Error {
+    /// Errors a lenient parser can carry on past, see
+    /// [`DecodeOptions::lenient`](super::DecodeOptions::lenient). Unknown and
+    /// stray restart markers carry no data the image needs. An End of Image
+    /// between the frame and the scan only helps when another scan follows,
+    /// as in some concatenated streams. Everything else leaves the header
+    /// incomplete or the stream out of step
+    pub fn is_recoverable(&self) -> bool {
+        matches!(
+            self,
+            Self::UnknownMarker { .. } | Self::RestartMarkerBeforeSOS | Self::EndOfImageBeforeSOS
+        )
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::InvalidSOF0Marker(source) => Some(source),
+            Self::InvalidDQTMarker(source) => Some(source),
+            Self::InvalidDHTMarker(source) => Some(source),
+            Self::InvalidSOSMarker(source) => Some(source),
+            Self::HuffmanDecode(source) => Some(source),
+            Self::BitReader(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<HuffmanDecodingError> for Error {
+    fn from(value: HuffmanDecodingError) -> Self {
+        Error::HuffmanDecode(value)
+    }
+}
+
+impl From<BitReaderError> for Error {
+    fn from(value: BitReaderError) -> Self {
+        Error::BitReader(value)
+    }
+}
+
+/// Something odd about a stream that doesn't stop it from being decoded.
+/// Reported to [`super::DecodeOptions::warning_handler`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warning {
+    /// Only the first JFIF segment is used
+    MultipleJFIFSegments,
+    /// A JFXX extension segment came before any JFIF segment and was dropped
+    JFIFExtensionWithoutJFIF,
+    /// An APPn segment that isn't understood was skipped
+    UnknownAPPnSkipped { marker: u8 },
+    /// The stream ended, or another marker came, inside the scan before its EOI
+    /// marker. Only [lenient](super::DecodeOptions::lenient) parses carry on
+    TruncatedHuffmanData,
+    /// None of JFIF, the Adobe segment or the component ids tell the color
+    /// space
+    UnknownColorSpace,
+    /// 16 bit quantization tables in an 8 bit frame. Valid, but rare
+    ExtendedQTablesWithBaselinePrecision,
+    /// ICC profile chunks were missing, repeated or disagreed on their
+    /// count, so the profile was dropped
+    IncompleteICCProfile,
+    /// An Exif APP1 segment was skipped, Exif is not parsed
+    ExifSkipped,
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MultipleJFIFSegments => write!(f, "Multiple non-extension JFIF segments"),
+            Self::JFIFExtensionWithoutJFIF => {
+                write!(f, "JFIF extension segment without a JFIF segment")
+            }
+            Self::UnknownAPPnSkipped { marker } => {
+                write!(f, "Skipped unknown APP{} segment", marker - 0xE0)
+            }
+            Self::TruncatedHuffmanData => write!(f, "Scan data ended without an EOI marker"),
+            Self::UnknownColorSpace => {
+                write!(f, "Could not infer the color space from the component ids")
+            }
+            Self::ExtendedQTablesWithBaselinePrecision => {
+                write!(f, "16 bit quantization tables in an 8 bit precision frame")
+            }
+            Self::IncompleteICCProfile => write!(f, "Dropped an ICC profile with missing chunks"),
+            Self::ExifSkipped => write!(f, "Skipped an Exif APP1 segment"),
+        }
+    }
+}
+
+/// An [`Error`] with the byte offset of the stream it was found at. The
+/// offset counts the bytes read so far, so errors found while decoding the
+/// scan point at its end
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LocatedError {
+    error: Error,
+    offset: usize,
+}
+
+impl LocatedError {
+    pub(super) fn new(error: Error, offset: usize) -> Self {
+        Self { error, offset }
+    }
+
+    pub fn error(&self) -> Error {
+        self.error
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl Display for LocatedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at byte offset {}", self.error, self.offset)
+    }
+}
+
+impl error::Error for LocatedError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<LocatedError> for Error {
+    fn from(value: LocatedError) -> Self {
+        value.error
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
@@ -1,1087 +1,5885 @@
-#![allow(dead_code, unused_imports, unused_variables)]
-use super::error::*;
-use std::{iter::Peekable, usize};
-
-const ZIGZAG: [u16; 64] = [
-    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
-    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
-    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
-];
-
-#[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum Marker {
-    SOI,
-    EOI,
-    //Padding,
-    APP0,
-    DQT,
-    SOF0,
-    DRI,
-    APPN,
-    SOFN,
-    DHT,
-    SOS,
-    JPGEXT,
-    DAC,
-    RSTN,
-    DNL,
-    DHP,
-    EXP,
-    APP1,
-    JPG,
-    COM,
-    TEM,
-}
-
-impl Eq for Marker {}
-
-impl Marker {
-    const HEX_SOI: u8 = 0xD8;
-    const HEX_EOI: u8 = 0xD9;
-
-    /// Length without the subtraction
-    fn marker_length(stream: &mut impl Iterator<Item = u8>, error: Error) -> Result<u16> {
-        let x = stream.next().ok_or(error)?;
-        let y = stream.next().ok_or(error)?;
-
-        Ok(((x as u16) << 8) | (y as u16))
-    }
-
-    fn new(byte: u8) -> Option<Self> {
-        match byte {
-            0x01 => Some(Self::TEM),
-            0xD8 => Some(Self::SOI),
-            0xD9 => Some(Self::EOI),
-            0xE0 => Some(Self::APP0),
-            0xDB => Some(Self::DQT),
-            0xC0 => Some(Self::SOF0),
-            0xC4 => Some(Self::DHT),
-            0xDD => Some(Self::DRI),
-            0xDA => Some(Self::SOS),
-            0xC8 => Some(Self::JPGEXT),
-            0xCC => Some(Self::DAC),
-            0xC1..=0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCE..=0xCF => Some(Self::SOFN),
-            0xD0..=0xD7 => Some(Self::RSTN),
-            0xDC => Some(Self::DNL),
-            0xDE => Some(Self::DHP),
-            0xDF => Some(Self::EXP),
-            0xE1 => Some(Self::APP1),
-            0xE2..=0xEF => Some(Self::APPN),
-            0xF0..=0xFD => Some(Self::JPG),
-            0xFE => Some(Self::COM),
-            _ => None,
-        }
-    }
-
-    fn skip_sized_marker(stream: &mut impl Iterator<Item = u8>) -> Result<DecodingOutcome> {
-        let error = Error::InvalidMarker;
-        let length = Self::marker_length(stream, error)? - 2;
-
-        for _ in 0..length {
-            stream.next();
-        }
-
-        Ok(DecodingOutcome::None)
-    }
-
-    fn process(
-        &self,
-        stream: &mut impl Iterator<Item = u8>,
-        jpeg: &mut JPEGHeader,
-    ) -> Result<DecodingOutcome> {
-        match self {
-            //Self::Padding => Ok(()),
-            Self::TEM => Ok(DecodingOutcome::None),
-            Self::SOI => Ok(DecodingOutcome::None),
-            Self::EOI => Err(Error::EndOfImageBeforeSOS),
-            Self::RSTN => Err(Error::RestartMarkerBeforeSOS),
-            Self::APPN => Self::skip_sized_marker(stream),
-            Self::SOFN => Self::skip_sized_marker(stream),
-            Self::JPGEXT => Self::skip_sized_marker(stream),
-            Self::DAC => Self::skip_sized_marker(stream),
-            Self::DNL => Self::skip_sized_marker(stream),
-            Self::DHP => Self::skip_sized_marker(stream),
-            Self::EXP => Self::skip_sized_marker(stream),
-            Self::JPG => Self::skip_sized_marker(stream),
-            Self::COM => Self::skip_sized_marker(stream),
-            Self::APP1 => todo!("EXIF needs implementing"),
-            Self::SOS => {
-                let error = Error::InvalidSOSMarker(SOSError::MissingNextByte);
-
-                fn throw(error: SOSError) -> Result<DecodingOutcome> {
-                    Err(Error::InvalidSOSMarker(error))
-                }
-
-                if !jpeg
-                    .components
-                    .iter()
-                    .any(|component| component.is_used_sof)
-                {
-                    return throw(SOSError::InvalidOrder);
-                }
-
-                let length = Self::marker_length(stream, error)? as i16;
-
-                let component_number = stream.next().ok_or(error)?;
-
-                if component_number == 0x00 || component_number > 0x03 {
-                    return throw(SOSError::InvalidComponentNumber);
-                }
-
-                for _ in 0..component_number {
-                    let mut component_id = stream.next().ok_or(error)?;
-
-                    if jpeg.zero_based_component_id {
-                        component_id += 1;
-                    }
-
-                    if component_id as usize > jpeg.components.len() {
-                        return throw(SOSError::InvalidComponentID);
-                    }
-
-                    let component = &mut jpeg.components[(component_id as usize) - 1];
-
-                    if component.is_used_sos {
-                        return throw(SOSError::DuplicateComponentID);
-                    }
-
-                    component.is_used_sos = true;
-
-                    let htable_ids = stream.next().ok_or(error)?;
-                    let dc_id = htable_ids >> 4;
-                    let ac_id = htable_ids & 0x0F;
-
-                    if dc_id > 0x03 || ac_id > 0x03 {
-                        return throw(SOSError::InvalidHuffmanTableID);
-                    }
-
-                    component.huffman_table_dc_id = dc_id;
-                    component.huffman_table_ac_id = ac_id;
-                }
-
-                let selection_start = stream.next().ok_or(error)?;
-                let selection_end = stream.next().ok_or(error)?;
-
-                if selection_start != 0 || selection_end > 0x3F {
-                    return throw(SOSError::InvalidSpectralSelection);
-                }
-
-                jpeg.start_of_selection = selection_start;
-                jpeg.end_of_selection = selection_end;
-
-                let approximation = stream.next().ok_or(error)?;
-                let high = approximation >> 4;
-                let low = approximation & 0x0F;
-
-                if high != 0 || low != 0 {
-                    return throw(SOSError::InvalidSuccesiveApproximation);
-                }
-
-                jpeg.successive_approximation_high = high;
-                jpeg.successive_approximation_low = low;
-
-                if length - 6 - 2 * (component_number as i16) != 0 {
-                    return throw(SOSError::InvalidMarkerLength);
-                }
-
-                Ok(DecodingOutcome::StartOfScan)
-            }
-            Self::DHT => {
-                let error = Error::InvalidDHTMarker(DHTError::MissingNextByte);
-                fn throw(error: DHTError) -> Result<DecodingOutcome> {
-                    Err(Error::InvalidDHTMarker(error))
-                }
-
-                let mut length = (Self::marker_length(stream, error)? as i16) - 2;
-
-                while length > 0 {
-                    let table_info = stream.next().ok_or(error)?;
-                    let table_id = table_info & 0x0F;
-                    let is_ac = table_info >> 4 == 0x01;
-
-                    if table_id > 0x03 {
-                        return throw(DHTError::InvalidTableId);
-                    }
-
-                    let htable = if is_ac {
-                        &mut jpeg.huffman_tables_ac[table_id as usize]
-                    } else {
-                        &mut jpeg.huffman_tables_dc[table_id as usize]
-                    };
-
-                    let mut total_symbols = 0;
-
-                    for i in 1..17 {
-                        total_symbols += stream.next().ok_or(error)?;
-                        htable.offsets[i] = total_symbols;
-                    }
-
-                    if total_symbols > 0xA2 {
-                        return throw(DHTError::InvalidSymbolsLength);
-                    }
-
-                    for i in 0..total_symbols {
-                        htable.symbols[i as usize] = stream.next().ok_or(error)?;
-                    }
-
-                    htable.is_set = true;
-                    length -= 17 + (total_symbols as i16);
-                }
-
-                if !jpeg
-                    .huffman_tables_ac
-                    .iter()
-                    .chain(jpeg.huffman_tables_dc.iter())
-                    .any(|htable| htable.is_set)
-                {
-                    return throw(DHTError::NoTableSet);
-                }
-
-                if length != 0 {
-                    return throw(DHTError::InvalidMarkerLength);
-                }
-
-                Ok(DecodingOutcome::HuffmanTable)
-            }
-            Self::DRI => {
-                let error = Error::InvalidRestartIntervalMarker;
-                let length = Self::marker_length(stream, error)?;
-
-                if length != 0x04 {
-                    return Err(Error::InvalidRestartIntervalMarker);
-                }
-
-                let rsi = {
-                    let x = stream.next().ok_or(error)?;
-                    let y = stream.next().ok_or(error)?;
-
-                    ((x as u16) << 8) | (y as u16)
-                };
-
-                jpeg.restart_interval = rsi;
-
-                Ok(DecodingOutcome::None)
-            }
-            Self::SOF0 => {
-                if jpeg.is_sof_set {
-                    return Err(Error::MultipleSOF);
-                }
-
-                fn throw(error: SOF0MarkerError) -> Result<DecodingOutcome> {
-                    Err(Error::InvalidSOF0Marker(error))
-                }
-
-                let error = Error::InvalidSOF0Marker(SOF0MarkerError::MissingNextByte);
-
-                let length = Self::marker_length(stream, error)? as i16;
-
-                let precision = stream.next().ok_or(error)?; // Base line SOF0 always has 8 precision
-                if precision != 0x08 {
-                    return throw(SOF0MarkerError::InvalidPrecision);
-                }
-
-                let height = {
-                    let x = stream.next().ok_or(error)?;
-                    let y = stream.next().ok_or(error)?;
-
-                    ((x as u16) << 8) | (y as u16)
-                };
-
-                let width = {
-                    let x = stream.next().ok_or(error)?;
-                    let y = stream.next().ok_or(error)?;
-
-                    ((x as u16) << 8) | (y as u16)
-                };
-
-                if width == 0 || height == 0 {
-                    return throw(SOF0MarkerError::ZeroDimensions);
-                }
-
-                let component_number = stream.next().ok_or(error)?;
-
-                if component_number == 0x00 || component_number == 0x02 {
-                    return throw(SOF0MarkerError::InvalidComponentNumber);
-                }
-
-                let component_number = component_number.clamp(1, 4);
-
-                jpeg.width = width;
-                jpeg.height = height;
-
-                for _ in 0..component_number {
-                    let mut id = stream.next().ok_or(error)?;
-
-                    if id == 0x00 {
-                        jpeg.zero_based_component_id = true;
-                    }
-
-                    if jpeg.zero_based_component_id {
-                        id += 1;
-                    }
-
-                    if id == 0x00 {
-                        return throw(SOF0MarkerError::InvalidComponentID);
-                    }
-
-                    if id > 0x04 {
-                        // larger ids are not supported
-                        return throw(SOF0MarkerError::InvalidComponentID);
-                    }
-
-                    let idx = (id - 1) as usize;
-
-                    let component = jpeg.components.get_mut(idx).unwrap();
-
-                    if component.is_used_sof {
-                        return throw(SOF0MarkerError::ComponentAlreadySet);
-                    }
-
-                    let (hfactor, vfactor) = {
-                        let factor = stream.next().ok_or(error)?;
-                        (factor >> 4, factor & 0x0F)
-                    };
-
-                    let qtable = stream.next().ok_or(error)?;
-
-                    if qtable > 0x03 {
-                        return throw(SOF0MarkerError::UnsupportedComponentQTable);
-                    }
-
-                    component.id = id;
-                    component.hfactor = hfactor;
-                    component.vfactor = vfactor;
-                    component.qtable = qtable;
-                    component.is_used_sof = true;
-                }
-
-                jpeg.is_sof_set = true;
-
-                if length - 8 - (3 * (component_number as i16)) != 0 {
-                    return throw(SOF0MarkerError::InvalidMarkerLength);
-                }
-
-                //Make sure at least 1 component is set
-                if !jpeg
-                    .components
-                    .iter()
-                    .any(|component| component.is_used_sof)
-                {
-                    return throw(SOF0MarkerError::NoComponentSet);
-                }
-
-                Ok(DecodingOutcome::StartOfFrame)
-            }
-            Self::DQT => {
-                let error = Error::InvalidDQTMarker(DQTError::MissingNextByte);
-                let mut length = (Self::marker_length(stream, error)? as i16) - 2;
-
-                // Accumulate tables
-                while length > 0 {
-                    let id = stream.next().ok_or(error)?;
-                    length -= 1;
-
-                    let (is_extended, kind) = { (id >> 4 == 1, id & 0x0F) };
-
-                    let qtable_type = match kind {
-                        0x00 => QTableType::Luminance,
-                        0x01 => QTableType::Chrominance,
-                        0x02 | 3 => QTableType::Other,
-                        _ => {
-                            return Err(Error::InvalidDQTMarker(DQTError::InvalidTableDestination))
-                        }
-                    };
-
-                    let mut data = [0; 64];
-
-                    if is_extended {
-                        for i in 0..64 {
-                            let x = stream.next().ok_or(error)?;
-                            let y = stream.next().ok_or(error)?;
-
-                            data[ZIGZAG[i] as usize] = ((x as u16) << 8) | (y as u16);
-                        }
-
-                        length -= 128;
-                    } else {
-                        for i in 0..64 {
-                            let byte = stream.next().ok_or(error)?;
-                            data[ZIGZAG[i] as usize] = byte as u16;
-                        }
-
-                        length -= 64;
-                    }
-
-                    let qtable = QTable {
-                        is_set: true,
-                        is_extended_mode: is_extended,
-                        kind: qtable_type,
-                        table: data,
-                    };
-
-                    // Kind being out of range should be caught by qtable_type
-                    jpeg.qtables[kind as usize] = qtable;
-                }
-
-                // At least one QTable Must be set
-                if !jpeg.qtables.iter().any(|table| table.is_set) {
-                    return Err(Error::InvalidDQTMarker(DQTError::NoTableSet));
-                }
-
-                Ok(DecodingOutcome::QTableSet)
-            }
-            Self::APP0 => {
-                let error = Error::InvalidAPP0Marker;
-
-                let mut length = (Self::marker_length(stream, error)? as i16) - 2;
-
-                // Skip till 4th byte of identifier
-                for _ in 0..3 {
-                    stream.next();
-                }
-
-                let is_extension = stream.next().ok_or(error)? == 0x58;
-                stream.next();
-                length -= 5; // Reduce by length of identifier
-
-                if !is_extension {
-                    if jpeg.jfif.is_some() {
-                        dbg!("Multiple non-extension JFIF segment markers encountered!");
-                        return Ok(DecodingOutcome::None);
-                    }
-                    let major_version = stream.next().ok_or(error)?;
-                    let minor_version = stream.next().ok_or(error)?;
-
-                    let units = stream.next().ok_or(error)?;
-
-                    let units = match units {
-                        0x00 => JfifUnit::NoUnit,
-                        0x01 => JfifUnit::PerInch,
-                        0x02 => JfifUnit::PerCenti,
-                        _ => return Err(error),
-                    };
-
-                    let x_density = {
-                        let f = stream.next().ok_or(error)?;
-                        let s = stream.next().ok_or(error)?;
-
-                        ((f as u16) << 8) | (s as u16)
-                    };
-
-                    let y_density = {
-                        let f = stream.next().ok_or(error)?;
-                        let s = stream.next().ok_or(error)?;
-
-                        ((f as u16) << 8) | (s as u16)
-                    };
-
-                    let x_thumbnail = stream.next().ok_or(error)?;
-                    let y_thumbnail = stream.next().ok_or(error)?;
-
-                    let mut thumbnail_data = Vec::with_capacity(length as usize);
-
-                    length -= 9;
-
-                    for _ in 0..length {
-                        let byte = stream.next().ok_or(error)?;
-                        thumbnail_data.push(byte);
-                    }
-
-                    let ap = APP0 {
-                        major_version,
-                        minor_version,
-                        units,
-                        x_density,
-                        y_density,
-                        x_thumbnail,
-                        y_thumbnail,
-                        thumbnail_data,
-                    };
-
-                    jpeg.jfif = Some(ap);
-                } else {
-                    for _ in 0..length {
-                        stream.next();
-                    }
-                }
-
-                Ok(DecodingOutcome::None)
-            }
-        }
-    }
-
-    fn scan<I>(stream: &mut Peekable<I>, jpeg: &mut JPEGHeader) -> Result<()>
-    where
-        I: Iterator<Item = u8>,
-    {
-        loop {
-            match stream.next() {
-                None => return Err(Error::PrematureEnd),
-                Some(current) => {
-                    if current == 0xFF {
-                        let next = stream.peek();
-
-                        if next == Some(&Marker::HEX_EOI) {
-                            break;
-                        } else if next == Some(&0x00) {
-                            jpeg.huffman_data.push(current);
-                            stream.next();
-                        } else if &0xD0 <= next.ok_or(Error::PrematureEnd)?
-                            || next.ok_or(Error::PrematureEnd)? <= &0xD7
-                        {
-                            stream.next();
-                        }
-                    } else {
-                        jpeg.huffman_data.push(current);
-                    }
-                }
-            }
-        }
-
-        Ok(())
-    }
-
-    fn read<I>(stream: &mut Peekable<I>, jpeg: &mut JPEGHeader) -> Result<DecodingOutcome>
-    where
-        I: Iterator<Item = u8>,
-    {
-        // Skip repetitions of 0xFF
-        while let Some(marker) = stream.peek() {
-            if *marker != 0xFF {
-                break;
-            } else {
-                stream.next();
-            }
-        }
-
-        let marker = stream.next().ok_or(Error::InvalidMarker)?;
-
-        //println!("Reading 0x{:02X} marker", marker);
-
-        match Self::new(marker) {
-            Some(marker) => {
-                if marker == Self::SOI {
-                    return Err(Error::MultipleSOI);
-                }
-                marker.process(stream, jpeg)
-            }
-            None => Err(Error::UnknownMarker(marker)),
-        }
-    }
-}
-
-#[derive(Clone, Copy, Debug, PartialEq, Default)]
-enum JfifUnit {
-    #[default]
-    NoUnit,
-    PerInch,
-    PerCenti,
-}
-
-#[derive(Clone, Debug, PartialEq, Default)]
-struct APP0 {
-    major_version: u8,
-    minor_version: u8,
-    units: JfifUnit,
-    x_density: u16,
-    y_density: u16,
-    x_thumbnail: u8,
-    y_thumbnail: u8,
-    thumbnail_data: Vec<u8>,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum QTableType {
-    Luminance,
-    Chrominance,
-    Other,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-struct QTable {
-    is_set: bool,
-    is_extended_mode: bool,
-    kind: QTableType,
-    table: [u16; 64],
-}
-
-impl Default for QTable {
-    fn default() -> Self {
-        Self {
-            is_set: false,
-            is_extended_mode: false,
-            kind: QTableType::Other,
-            table: [0; 64],
-        }
-    }
-}
-
-#[derive(Debug, Default, Clone, Copy, PartialEq)]
-struct ColorComponent {
-    id: u8,
-    hfactor: u8,
-    vfactor: u8,
-    qtable: u8,
-    huffman_table_ac_id: u8,
-    huffman_table_dc_id: u8,
-    is_used_sof: bool,
-    is_used_sos: bool,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-struct HuffmanTable {
-    offsets: [u8; 17],
-    symbols: [u8; 162],
-    codes: [u32; 162],
-    is_set: bool,
-}
-
-impl Default for HuffmanTable {
-    fn default() -> Self {
-        Self {
-            offsets: [0; 17],
-            symbols: [0; 162],
-            codes: [0; 162],
-            is_set: false,
-        }
-    }
-}
-
-impl HuffmanTable {
-    fn generate_codes(&mut self) {
-        let mut code = 0;
-
-        for i in 0..16 {
-            let current = self.offsets[i];
-            let next = self.offsets[i + 1];
-
-            for c in current..next {
-                self.codes[c as usize] = code;
-                code += 1;
-            }
-
-            code = code << 1;
-        }
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum DecodingOutcome {
-    None,
-    QTableSet,
-    StartOfFrame,
-    HuffmanTable,
-    StartOfScan,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-struct MCU {
-    r: [i32; 64],
-    g: [i32; 64],
-    b: [i32; 64],
-    is_rbg: bool,
-}
-
-impl MCU {
-    fn component(&mut self, index: usize) -> &mut [i32; 64] {
-        match index {
-            0 => &mut self.r,
-            1 => &mut self.g,
-            2 => &mut self.b,
-            _ => panic!("Invalid MCU component index"),
-        }
-    }
-}
-
-impl Default for MCU {
-    fn default() -> Self {
-        Self {
-            r: [0; 64],
-            g: [0; 64],
-            b: [0; 64],
-            is_rbg: true,
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
-struct BitReader<'a> {
-    data: &'a [u8],
-    bit_position: usize,
-    byte_position: usize,
-}
-
-impl<'a> BitReader<'a> {
-    fn new(data: &'a [u8]) -> Self {
-        Self {
-            data,
-            bit_position: 0,
-            byte_position: 0,
-        }
-    }
-
-    fn read_length(&mut self, length: u8) -> Option<u32> {
-        let mut output = 0;
-
-        for _ in 0..length {
-            let bit = self.read_bit()?;
-            output = (output << 1) | bit;
-        }
-
-        Some(output)
-    }
-
-    fn read_bit(&mut self) -> Option<u32> {
-        let byte = self.data.get(self.byte_position)?;
-
-        // Read bit from most to least significant
-        let bit = ((byte >> (7 - self.bit_position)) & 1) as u32;
-        self.bit_position += 1;
-
-        if self.bit_position == 8 {
-            self.bit_position = 0;
-            self.byte_position += 1;
-        }
-
-        Some(bit)
-    }
-
-    fn align(&mut self) {
-        if self.byte_position < self.data.len() && self.bit_position != 0 {
-            self.bit_position = 0;
-            self.byte_position += 1;
-        }
-    }
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct JPEGHeader {
-    jfif: Option<APP0>,
-    qtables: [QTable; 4],
-    restart_interval: u16,
-    zero_based_component_id: bool,
-    huffman_tables_dc: [HuffmanTable; 4],
-    huffman_tables_ac: [HuffmanTable; 4],
-    components: [ColorComponent; 3],
-    is_sof_set: bool,
-    height: u16,
-    width: u16,
-    start_of_selection: u8,
-    end_of_selection: u8,
-    successive_approximation_high: u8,
-    successive_approximation_low: u8,
-    huffman_data: Vec<u8>,
-}
-
-impl Default for JPEGHeader {
-    fn default() -> Self {
-        Self {
-            jfif: None,
-            qtables: [QTable::default(); 4],
-            restart_interval: 0,
-            zero_based_component_id: false,
-            huffman_tables_dc: [HuffmanTable::default(); 4],
-            huffman_tables_ac: [HuffmanTable::default(); 4],
-            components: [ColorComponent::default(); 3],
-            is_sof_set: false,
-            height: 0,
-            width: 0,
-            start_of_selection: 0,
-            end_of_selection: 63,
-            successive_approximation_low: 0,
-            successive_approximation_high: 0,
-            huffman_data: Vec::default(),
-        }
-    }
-}
-
-impl JPEGHeader {
-    pub fn new(stream: Vec<u8>) -> Result<JPEGHeader> {
-        let mut stream = stream.into_iter();
-
-        let mut has_soi = false;
-        let mut has_sof = false;
-        let mut has_qtable = false;
-        let mut has_htable = false;
-        let mut has_sos = false;
-
-        // Advance until SOI
-        while let Some(byte) = stream.next() {
-            if byte == 0xFF && Some(Marker::HEX_SOI) == stream.next() {
-                has_soi = true;
-                break;
-            }
-        }
-
-        if !has_soi {
-            return Err(Error::StartOfImageNotFound);
-        }
-
-        let mut stream = stream.peekable();
-
-        if stream.peek().is_none() {
-            return Err(Error::NoData);
-        }
-
-        let mut jpeg_header = JPEGHeader::default();
-
-        // Advance until next marker
-        while let Some(byte) = stream.next() {
-            if byte == 0xFF {
-                if stream.peek().is_some() {
-                    match Marker::read(&mut stream, &mut jpeg_header)? {
-                        DecodingOutcome::StartOfFrame => {
-                            has_sof = true;
-                        }
-                        DecodingOutcome::QTableSet => {
-                            has_qtable = true;
-                        }
-                        DecodingOutcome::HuffmanTable => {
-                            has_htable = true;
-                        }
-                        DecodingOutcome::StartOfScan => {
-                            has_sos = true;
-                            break;
-                        }
-                        DecodingOutcome::None => {}
-                    };
-                } else {
-                    return Err(Error::InvalidMarker);
-                }
-            }
-        }
-
-        if !has_sof {
-            return Err(Error::StartOfFrameNotFound);
-        }
-        if !has_qtable {
-            return Err(Error::QTableNotFound);
-        }
-
-        if !has_htable {
-            return Err(Error::HTableNotFound);
-        }
-
-        if !has_sos {
-            return Err(Error::SOSNotFound);
-        }
-
-        Marker::scan(&mut stream, &mut jpeg_header)?;
-
-        // Last validations
-        for component in jpeg_header.components.iter() {
-            if (component.is_used_sos && !component.is_used_sof)
-                || (component.is_used_sof && !component.is_used_sos)
-            {
-                return Err(Error::InvalidColorComponent);
-            }
-
-            match jpeg_header
-                .huffman_tables_dc
-                .get(component.huffman_table_dc_id as usize)
-            {
-                Some(htable) => {
-                    if !htable.is_set {
-                        return Err(Error::InvalidColorComponent);
-                    }
-                }
-                None => return Err(Error::InvalidColorComponent),
-            }
-
-            match jpeg_header
-                .huffman_tables_ac
-                .get(component.huffman_table_ac_id as usize)
-            {
-                Some(htable) if !htable.is_set => return Err(Error::InvalidColorComponent),
-                None => return Err(Error::InvalidColorComponent),
-                _ => {}
-            }
-
-            match jpeg_header.qtables.get(component.qtable as usize) {
-                Some(qtable) => {
-                    if !qtable.is_set {
-                        return Err(Error::InvalidColorComponent);
-                    }
-                }
-                None => return Err(Error::InvalidColorComponent),
-            }
-        }
-
-        jpeg_header.decode_huffman()?;
-
-        //println!("{:?}", jpeg_header.huffman_data.len());
-
-        //{
-        //    println!("Start of Selection: {:?}", jpeg_header.start_of_selection);
-        //    println!("End of Selection: {:?}", jpeg_header.end_of_selection);
-        //    println!(
-        //        "Successive high: {:?}",
-        //        jpeg_header.successive_approximation_high
-        //    );
-        //    println!(
-        //        "Successive low: {:?}",
-        //        jpeg_header.successive_approximation_low
-        //    );
-        //    println!("Color Components");
-        //
-        //    for component in &jpeg_header.components {
-        //        println!("Component ID: {:?}", component.id);
-        //        println!(
-        //            "Component DC Huffman ID: {:?}",
-        //            component.huffman_table_dc_id
-        //        );
-        //        println!(
-        //            "Component AC Huffman ID: {:?}",
-        //            component.huffman_table_ac_id
-        //        );
-        //    }
-        //
-        //    println!("Huffman Size: {:?}", jpeg_header.huffman_data.len());
-        //    println!("Restart Interval: {:?}", jpeg_header.restart_interval);
-        //}
-
-        Ok(jpeg_header)
-    }
-
-    fn decode_huffman(&mut self) -> Result<Vec<MCU>> {
-        let mcu_height = (self.height + 7) / 8;
-        let mcu_width = (self.width + 7) / 8;
-
-        let mut mcus = vec![MCU::default(); (mcu_height * mcu_width) as usize];
-
-        for i in 0..4 {
-            if let Some(table) = self.huffman_tables_dc.get_mut(i) {
-                if table.is_set {
-                    table.generate_codes();
-                }
-            };
-
-            if let Some(table) = self.huffman_tables_ac.get_mut(i) {
-                if table.is_set {
-                    table.generate_codes();
-                }
-            };
-        }
-
-        let mut bit_reader = BitReader::new(&self.huffman_data);
-
-        let mut previous_dc = [0; 3];
-
-        for i in 0..(mcu_height * mcu_width) {
-            // Restart intervals
-            if self.restart_interval != 0 && i % self.restart_interval == 0 {
-                previous_dc = [0; 3];
-                bit_reader.align();
-            }
-
-            for j in 0..self.components.len() {
-                Self::decode_mcus(
-                    &mut bit_reader,
-                    mcus[i as usize].component(j),
-                    &mut previous_dc[j],
-                    &self.huffman_tables_dc[self.components[j].huffman_table_dc_id as usize],
-                    &self.huffman_tables_ac[self.components[j].huffman_table_ac_id as usize],
-                )?;
-            }
-        }
-
-        Ok(mcus)
-    }
-
-    fn decode_mcus(
-        reader: &mut BitReader,
-        component: &mut [i32; 64],
-        previous_dc: &mut i32,
-        dc_table: &HuffmanTable,
-        ac_table: &HuffmanTable,
-    ) -> Result<()> {
-        let length = Self::get_next_symbol(reader, dc_table)?;
-
-        // DC cannot be more than 11
-        if length > 11 {
-            return Err(HuffmanDecodingError::InvalidDCCoefficientLength)?;
-        }
-
-        let mut dc_coeff = reader
-            .read_length(length)
-            .ok_or(HuffmanDecodingError::ReadPastLength)? as i32;
-
-        if length != 0 && dc_coeff < (1 << (length - 1)) {
-            dc_coeff -= (1 << length) - 1;
-        }
-
-        component[0] = dc_coeff + *previous_dc;
-        *previous_dc = component[0];
-
-        // AC now
-        let mut i = 1;
-
-        while i < 64 {
-            let symbol = Self::get_next_symbol(reader, ac_table)?;
-
-            // 0x00 means fill the remaining with 0
-            if symbol == 0x00 {
-                return Ok(());
-            }
-
-            let mut skip_zeros = symbol >> 4;
-            let coeff_len = symbol & 0x0F;
-
-            if symbol == 0xF0 {
-                skip_zeros = 16;
-            }
-
-            if (i + skip_zeros as usize) >= 64 {
-                println!("i:{i}, zeros:{skip_zeros:?}, len:{coeff_len}");
-                return Err(HuffmanDecodingError::ZerosExceedMCULength)?;
-            }
-
-            for _ in 0..skip_zeros {
-                component[ZIGZAG[i] as usize] = 0;
-                i += 1;
-            }
-
-            // Invalid for AC
-            if coeff_len > 10 {
-                return Err(HuffmanDecodingError::InvalidACCoefficientLength)?;
-            }
-
-            if coeff_len != 0 {
-                let mut ac_coeff = reader
-                    .read_length(coeff_len)
-                    .ok_or(HuffmanDecodingError::ReadPastLength)?
-                    as i32;
-
-                if ac_coeff < (1 << (coeff_len - 1)) {
-                    ac_coeff -= (1 << coeff_len) - 1;
-                }
-
-                component[ZIGZAG[i] as usize] = ac_coeff;
-                i += 1;
-            }
-        }
-
-        Ok(())
-    }
-
-    fn get_next_symbol(reader: &mut BitReader, table: &HuffmanTable) -> Result<u8> {
-        let mut code = 0;
-
-        for i in 0..16 {
-            let bit = reader
-                .read_bit()
-                .ok_or(HuffmanDecodingError::ReadPastLength)?;
-
-            code = (code << 1) | bit;
-
-            for j in table.offsets[i]..table.offsets[i + 1] {
-                if code == table.codes[j as usize] {
-                    return Ok(table.symbols[j as usize]);
-                }
-            }
-        }
-
-        Err(HuffmanDecodingError::SymbolNotFound)?
-    }
-}
+use super::encode::{
+    scale_qtable, BitWriter, Encoder, AC_CHROMINANCE_BITS, AC_CHROMINANCE_VALUES,
+    AC_LUMINANCE_BITS, AC_LUMINANCE_VALUES, CHROMINANCE_QTABLE, DC_CHROMINANCE_BITS,
+    DC_CHROMINANCE_VALUES, DC_LUMINANCE_BITS, DC_LUMINANCE_VALUES, LUMINANCE_QTABLE,
+};
+use super::error::*;
+use super::idct::{aan_idct_2d, reduced_idct_2d};
+use super::metadata::{ImageMetadata, JfifMetadata};
+use super::zigzag::ZIGZAG;
+use log::{debug, trace};
+use rayon::prelude::*;
+use std::fmt::Display;
+use std::io::{BufRead, Read, Write};
+use std::iter::Peekable;
+use std::mem::MaybeUninit;
+use std::ops::{Range, RangeInclusive};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Next byte of `stream`, returning `error` from the enclosing function when
+/// the stream has ended
+macro_rules! expect_next {
+    ($stream:expr, $error:expr) => {
+        $stream.next().ok_or($error)?
+    };
+}
+
+/// Number of bits used to index the Huffman lookup table
+const LOOKUP_BITS: u8 = 9;
+
+/// Kind of a marker. Variants like `APPN` or `RSTN` cover a range of codes
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Marker {
+    SOI,
+    EOI,
+    //Padding,
+    APP0,
+    DQT,
+    SOF0,
+    DRI,
+    APPN,
+    SOFN,
+    /// SOF9 - SOF11 and SOF13 - SOF15. Arithmetic coded frames
+    ArithmeticSOF,
+    DHT,
+    SOS,
+    JPGEXT,
+    DAC,
+    RSTN,
+    DNL,
+    DHP,
+    EXP,
+    APP1,
+    APP2,
+    APP14,
+    JPG,
+    COM,
+    TEM,
+}
+
+impl Eq for Marker {}
+
+impl Marker {
+    const HEX_SOI: u8 = 0xD8;
+    const HEX_EOI: u8 = 0xD9;
+    const XMP_IDENTIFIER: &'static [u8] = b"http://ns.adobe.com/xap/1.0/\0";
+    const ICC_IDENTIFIER: &'static [u8] = b"ICC_PROFILE\0";
+    /// JFXX extension code of a thumbnail coded as a JPEG
+    const JFXX_JPEG_THUMBNAIL: u8 = 0x10;
+
+    /// Length without the subtraction
+    fn marker_length(stream: &mut impl Iterator<Item = u8>, error: Error) -> Result<u16> {
+        let x = expect_next!(stream, error);
+        let y = expect_next!(stream, error);
+
+        Ok(((x as u16) << 8) | (y as u16))
+    }
+
+    pub(super) fn new(byte: u8) -> Option<Self> {
+        match byte {
+            0x01 => Some(Self::TEM),
+            0xD8 => Some(Self::SOI),
+            0xD9 => Some(Self::EOI),
+            0xE0 => Some(Self::APP0),
+            0xDB => Some(Self::DQT),
+            0xC0 => Some(Self::SOF0),
+            0xC4 => Some(Self::DHT),
+            0xDD => Some(Self::DRI),
+            0xDA => Some(Self::SOS),
+            0xC8 => Some(Self::JPGEXT),
+            0xCC => Some(Self::DAC),
+            0xC1..=0xC3 | 0xC5..=0xC7 => Some(Self::SOFN),
+            0xC9..=0xCB | 0xCD..=0xCF => Some(Self::ArithmeticSOF),
+            0xD0..=0xD7 => Some(Self::RSTN),
+            0xDC => Some(Self::DNL),
+            0xDE => Some(Self::DHP),
+            0xDF => Some(Self::EXP),
+            0xE1 => Some(Self::APP1),
+            0xE2 => Some(Self::APP2),
+            0xEE => Some(Self::APP14),
+            0xE3..=0xED | 0xEF => Some(Self::APPN),
+            0xF0..=0xFD => Some(Self::JPG),
+            0xFE => Some(Self::COM),
+            _ => None,
+        }
+    }
+
+    /// SOI, EOI, RSTn and TEM have no length field
+    pub(super) fn is_standalone(self) -> bool {
+        matches!(self, Self::SOI | Self::EOI | Self::RSTN | Self::TEM)
+    }
+
+    fn skip_sized_marker(stream: &mut impl Iterator<Item = u8>) -> Result<DecodingOutcome> {
+        let error = Error::InvalidMarker;
+
+        // The length counts its own two bytes
+        let length = Self::marker_length(stream, error)?
+            .checked_sub(2)
+            .ok_or(error)?;
+
+        Self::skip_bytes(stream, length as usize)?;
+
+        Ok(DecodingOutcome::None)
+    }
+
+    /// Skips the rest of a segment. A stream ending first is a truncated
+    /// file, not a segment to carry on after
+    fn skip_bytes(stream: &mut impl Iterator<Item = u8>, count: usize) -> Result<()> {
+        for _ in 0..count {
+            stream.next().ok_or(Error::PrematureEnd)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the 5 byte APP0 identifier. `Some(true)` for a JFXX extension,
+    /// `Some(false)` for JFIF and `None` for anything else
+    fn read_jfif_identifier(stream: &mut impl Iterator<Item = u8>) -> Option<bool> {
+        let mut identifier = [0; 5];
+
+        for byte in identifier.iter_mut() {
+            *byte = stream.next()?;
+        }
+
+        match &identifier {
+            b"JFIF\0" => Some(false),
+            b"JFXX\0" => Some(true),
+            _ => None,
+        }
+    }
+
+    fn process(
+        &self,
+        stream: &mut impl Iterator<Item = u8>,
+        jpeg: &mut JPEGHeader,
+    ) -> Result<DecodingOutcome> {
+        match self {
+            //Self::Padding => Ok(()),
+            Self::TEM => Ok(DecodingOutcome::None),
+            Self::SOI => Ok(DecodingOutcome::None),
+            Self::EOI => Ok(DecodingOutcome::EndOfImage),
+            Self::RSTN => Err(Error::RestartMarkerBeforeSOS),
+            Self::APPN => Self::skip_sized_marker(stream),
+            Self::SOFN => Self::skip_sized_marker(stream),
+            Self::JPGEXT => Self::skip_sized_marker(stream),
+            Self::DAC => Err(Error::ArithmeticCodingNotSupported),
+            Self::ArithmeticSOF => Err(Error::ArithmeticCodingNotSupported),
+            Self::DNL => Self::skip_sized_marker(stream),
+            Self::DHP => Self::skip_sized_marker(stream),
+            Self::EXP => Self::skip_sized_marker(stream),
+            Self::JPG => Self::skip_sized_marker(stream),
+            Self::COM => {
+                let error = Error::InvalidCOMMarker;
+
+                let length = Self::marker_length(stream, error)?
+                    .checked_sub(2)
+                    .ok_or(error)?;
+
+                let mut comment = Vec::with_capacity(length as usize);
+
+                for _ in 0..length {
+                    comment.push(expect_next!(stream, error));
+                }
+
+                // Comments have no declared encoding. Anything that isn't
+                // UTF-8 is converted lossily
+                jpeg.comments
+                    .push(String::from_utf8_lossy(&comment).into_owned());
+
+                Ok(DecodingOutcome::None)
+            }
+            Self::APP1 => {
+                let error = Error::InvalidAPP1Marker;
+
+                let length = Self::marker_length(stream, error)?
+                    .checked_sub(2)
+                    .ok_or(error)?;
+
+                let mut payload = Vec::with_capacity(length as usize);
+
+                for _ in 0..length {
+                    payload.push(expect_next!(stream, error));
+                }
+
+                if let Some(xmp) = payload.strip_prefix(Self::XMP_IDENTIFIER) {
+                    if jpeg.xmp.is_none() {
+                        jpeg.xmp = Some(xmp.to_vec());
+                    }
+                } else if payload.starts_with(b"Exif\0\0") {
+                    jpeg.options.warn(Warning::ExifSkipped);
+                } else {
+                    jpeg.options
+                        .warn(Warning::UnknownAPPnSkipped { marker: 0xE1 });
+                }
+
+                Ok(DecodingOutcome::None)
+            }
+            Self::APP2 => {
+                let error = Error::InvalidAPP2Marker;
+
+                let length = Self::marker_length(stream, error)?
+                    .checked_sub(2)
+                    .ok_or(error)?;
+
+                let mut payload = Vec::with_capacity(length as usize);
+
+                for _ in 0..length {
+                    payload.push(expect_next!(stream, error));
+                }
+
+                // A profile too large for one segment is split over several,
+                // each numbered from 1 with the total count. They are put
+                // back together once every segment before the scan is read
+                match payload.strip_prefix(Self::ICC_IDENTIFIER) {
+                    Some([index, total, chunk @ ..]) => {
+                        jpeg.icc_chunks.push((*index, *total, chunk.to_vec()));
+                    }
+                    Some(_) => return Err(error),
+                    None => jpeg
+                        .options
+                        .warn(Warning::UnknownAPPnSkipped { marker: 0xE2 }),
+                }
+
+                Ok(DecodingOutcome::None)
+            }
+            Self::APP14 => {
+                let error = Error::InvalidAPP14Marker;
+
+                let mut length = (Self::marker_length(stream, error)? as i16) - 2;
+
+                let mut identifier = [0; 5];
+
+                for byte in identifier.iter_mut() {
+                    *byte = expect_next!(stream, error);
+                }
+
+                length -= 5; // Reduce by length of identifier
+
+                // Only the Adobe segment is understood. Others are skipped
+                if &identifier != b"Adobe" {
+                    jpeg.options
+                        .warn(Warning::UnknownAPPnSkipped { marker: 0xEE });
+
+                    Self::skip_bytes(stream, length.max(0) as usize)?;
+
+                    return Ok(DecodingOutcome::None);
+                }
+
+                let version = {
+                    let x = expect_next!(stream, error);
+                    let y = expect_next!(stream, error);
+
+                    ((x as u16) << 8) | (y as u16)
+                };
+
+                let flags0 = {
+                    let x = expect_next!(stream, error);
+                    let y = expect_next!(stream, error);
+
+                    ((x as u16) << 8) | (y as u16)
+                };
+
+                let flags1 = {
+                    let x = expect_next!(stream, error);
+                    let y = expect_next!(stream, error);
+
+                    ((x as u16) << 8) | (y as u16)
+                };
+
+                let color_transform = expect_next!(stream, error);
+
+                if color_transform > 0x02 {
+                    return Err(error);
+                }
+
+                length -= 7;
+
+                if length < 0 {
+                    return Err(error);
+                }
+
+                Self::skip_bytes(stream, length.max(0) as usize)?;
+
+                jpeg.adobe = Some(AdobeApp14 {
+                    version,
+                    flags0,
+                    flags1,
+                    color_transform,
+                });
+
+                Ok(DecodingOutcome::None)
+            }
+            Self::SOS => {
+                let error = Error::InvalidSOSMarker(SOSError::MissingNextByte);
+
+                fn throw(error: SOSError) -> Result<DecodingOutcome> {
+                    Err(Error::InvalidSOSMarker(error))
+                }
+
+                if !jpeg.is_sof_set {
+                    return throw(SOSError::MissingSOFBeforeSOS);
+                }
+
+                let length = Self::marker_length(stream, error)? as i16;
+
+                let component_number = expect_next!(stream, error);
+
+                if component_number == 0x00 || component_number > 0x03 {
+                    return throw(SOSError::InvalidComponentNumber);
+                }
+
+                for _ in 0..component_number {
+                    let mut component_id = expect_next!(stream, error);
+
+                    if jpeg.zero_based_component_id {
+                        component_id += 1;
+                    }
+
+                    let Some(index) = component_index(component_id) else {
+                        return throw(SOSError::InvalidComponentID);
+                    };
+
+                    let component = &mut jpeg.components[index];
+
+                    if component.is_used_sos {
+                        return throw(SOSError::DuplicateComponentID);
+                    }
+
+                    component.is_used_sos = true;
+
+                    let htable_ids = expect_next!(stream, error);
+                    let dc_id = htable_ids >> 4;
+                    let ac_id = htable_ids & 0x0F;
+
+                    if dc_id > 0x03 || ac_id > 0x03 {
+                        return throw(SOSError::InvalidHuffmanTableID);
+                    }
+
+                    component.huffman_table_dc_id = dc_id;
+                    component.huffman_table_ac_id = ac_id;
+                }
+
+                let selection_start = expect_next!(stream, error);
+                let selection_end = expect_next!(stream, error);
+
+                if selection_start != 0 || selection_end > 0x3F {
+                    return throw(SOSError::InvalidSpectralSelection);
+                }
+
+                jpeg.start_of_selection = selection_start;
+                jpeg.end_of_selection = selection_end;
+
+                let approximation = expect_next!(stream, error);
+                let high = approximation >> 4;
+                let low = approximation & 0x0F;
+
+                if high != 0 || low != 0 {
+                    return throw(SOSError::InvalidSuccesiveApproximation);
+                }
+
+                jpeg.successive_approximation_high = high;
+                jpeg.successive_approximation_low = low;
+
+                if length - 6 - 2 * (component_number as i16) != 0 {
+                    return throw(SOSError::InvalidMarkerLength);
+                }
+
+                Ok(DecodingOutcome::StartOfScan)
+            }
+            Self::DHT => {
+                let error = Error::InvalidDHTMarker(DHTError::MissingNextByte);
+                fn throw(error: DHTError) -> Result<DecodingOutcome> {
+                    Err(Error::InvalidDHTMarker(error))
+                }
+
+                let mut length = (Self::marker_length(stream, error)? as i16) - 2;
+                // A segment can hold any mix of DC and AC tables
+                let mut tables_set = 0;
+
+                while length > 0 {
+                    let table_info = expect_next!(stream, error);
+                    let table_id = table_info & 0x0F;
+                    let is_ac = table_info >> 4 == 0x01;
+
+                    if table_id > 0x03 {
+                        return throw(DHTError::InvalidTableId);
+                    }
+
+                    let htable = if is_ac {
+                        &mut jpeg.huffman_tables_ac[table_id as usize]
+                    } else {
+                        &mut jpeg.huffman_tables_dc[table_id as usize]
+                    };
+
+                    let mut total_symbols = 0;
+
+                    for i in 1..17 {
+                        total_symbols += expect_next!(stream, error) as u16;
+
+                        if total_symbols > 0xA2 {
+                            return throw(DHTError::InvalidSymbolsLength);
+                        }
+
+                        htable.offsets[i] = total_symbols as u8;
+                    }
+
+                    for i in 0..total_symbols {
+                        htable.symbols[i as usize] = expect_next!(stream, error);
+                    }
+
+                    htable.is_set = true;
+                    tables_set += 1;
+                    length -= 17 + (total_symbols as i16);
+                }
+
+                // Tables set by earlier segments don't count
+                if tables_set == 0 {
+                    return throw(DHTError::NoTableSet);
+                }
+
+                if length != 0 {
+                    return throw(DHTError::InvalidMarkerLength);
+                }
+
+                Ok(DecodingOutcome::HuffmanTable)
+            }
+            Self::DRI => {
+                let error = Error::InvalidRestartIntervalMarker;
+                let length = Self::marker_length(stream, error)?;
+
+                if length != 0x04 {
+                    return Err(Error::InvalidRestartIntervalMarker);
+                }
+
+                let rsi = {
+                    let x = expect_next!(stream, error);
+                    let y = expect_next!(stream, error);
+
+                    ((x as u16) << 8) | (y as u16)
+                };
+
+                jpeg.restart_interval = rsi;
+
+                Ok(DecodingOutcome::None)
+            }
+            Self::SOF0 => {
+                if jpeg.is_sof_set {
+                    return Err(Error::MultipleSOF);
+                }
+
+                fn throw(error: SOF0MarkerError) -> Result<DecodingOutcome> {
+                    Err(Error::InvalidSOF0Marker(error))
+                }
+
+                let error = Error::InvalidSOF0Marker(SOF0MarkerError::MissingNextByte);
+
+                let length = Self::marker_length(stream, error)? as i16;
+
+                let precision = expect_next!(stream, error); // Base line SOF0 always has 8 precision
+                if precision != 0x08 {
+                    return throw(SOF0MarkerError::InvalidPrecision(precision));
+                }
+
+                jpeg.precision = precision;
+
+                let height = {
+                    let x = expect_next!(stream, error);
+                    let y = expect_next!(stream, error);
+
+                    ((x as u16) << 8) | (y as u16)
+                };
+
+                let width = {
+                    let x = expect_next!(stream, error);
+                    let y = expect_next!(stream, error);
+
+                    ((x as u16) << 8) | (y as u16)
+                };
+
+                if width == 0 || height == 0 {
+                    return throw(SOF0MarkerError::ZeroDimensions);
+                }
+
+                let component_number = expect_next!(stream, error);
+
+                // Only grayscale and three component images are supported.
+                // Four component CMYK or YCCK frames are rejected instead of
+                // silently dropping the last component
+                if component_number != 0x01 && component_number != 0x03 {
+                    return throw(SOF0MarkerError::InvalidComponentNumber);
+                }
+
+                jpeg.width = width;
+                jpeg.height = height;
+
+                for _ in 0..component_number {
+                    let mut id = expect_next!(stream, error);
+
+                    if id == 0x00 {
+                        jpeg.zero_based_component_id = true;
+                    }
+
+                    if jpeg.zero_based_component_id {
+                        id += 1;
+                    }
+
+                    if id == 0x00 {
+                        return throw(SOF0MarkerError::InvalidComponentID);
+                    }
+
+                    let Some(idx) = component_index(id) else {
+                        // larger ids are not supported
+                        return throw(SOF0MarkerError::InvalidComponentID);
+                    };
+
+                    let component = &mut jpeg.components[idx];
+
+                    if component.is_used_sof {
+                        return throw(SOF0MarkerError::ComponentAlreadySet);
+                    }
+
+                    let (hfactor, vfactor) = {
+                        let factor = expect_next!(stream, error);
+                        (factor >> 4, factor & 0x0F)
+                    };
+
+                    if !(1..=4).contains(&hfactor) || !(1..=4).contains(&vfactor) {
+                        return throw(SOF0MarkerError::InvalidSamplingFactor);
+                    }
+
+                    let qtable = expect_next!(stream, error);
+
+                    if qtable > 0x03 {
+                        return throw(SOF0MarkerError::UnsupportedComponentQTable);
+                    }
+
+                    component.id = id;
+                    component.hfactor = hfactor;
+                    component.vfactor = vfactor;
+                    component.qtable = qtable;
+                    component.is_used_sof = true;
+                }
+
+                jpeg.is_sof_set = true;
+
+                if length - 8 - (3 * (component_number as i16)) != 0 {
+                    return throw(SOF0MarkerError::InvalidMarkerLength);
+                }
+
+                //Make sure at least 1 component is set
+                if !jpeg
+                    .components
+                    .iter()
+                    .any(|component| component.is_used_sof)
+                {
+                    return throw(SOF0MarkerError::NoComponentSet);
+                }
+
+                Ok(DecodingOutcome::StartOfFrame)
+            }
+            Self::DQT => {
+                let error = Error::InvalidDQTMarker(DQTError::MissingNextByte);
+                let mut length = (Self::marker_length(stream, error)? as i16) - 2;
+
+                // Accumulate tables
+                while length > 0 {
+                    let id = expect_next!(stream, error);
+                    length -= 1;
+
+                    let (is_extended, kind) = { (id >> 4 == 1, id & 0x0F) };
+
+                    let qtable_type = QTableType::from_destination(kind)
+                        .ok_or(Error::InvalidDQTMarker(DQTError::InvalidTableDestination))?;
+
+                    let mut data = [0; 64];
+
+                    if is_extended {
+                        for i in 0..64 {
+                            let x = expect_next!(stream, error);
+                            let y = expect_next!(stream, error);
+
+                            data[ZIGZAG[i] as usize] = ((x as u16) << 8) | (y as u16);
+                        }
+
+                        length -= 128;
+                    } else {
+                        for i in 0..64 {
+                            let byte = expect_next!(stream, error);
+                            data[ZIGZAG[i] as usize] = byte as u16;
+                        }
+
+                        length -= 64;
+                    }
+
+                    let qtable = QTable {
+                        is_set: true,
+                        is_extended_mode: is_extended,
+                        kind: qtable_type,
+                        table: data,
+                    };
+
+                    debug!(
+                        "QTable {kind}, {:?} with {} bit entries",
+                        qtable_type,
+                        if is_extended { 16 } else { 8 }
+                    );
+
+                    for row in data.chunks_exact(8) {
+                        trace!("{row:>4?}");
+                    }
+
+                    // Kind being out of range should be caught by qtable_type
+                    jpeg.qtables[kind as usize] = qtable;
+                }
+
+                // At least one QTable Must be set
+                if !jpeg.qtables.iter().any(|table| table.is_set) {
+                    return Err(Error::InvalidDQTMarker(DQTError::NoTableSet));
+                }
+
+                Ok(DecodingOutcome::QTableSet)
+            }
+            Self::APP0 => {
+                let error = Error::InvalidAPP0Marker;
+
+                // Less the length bytes and the identifier
+                let mut length = Self::marker_length(stream, error)?
+                    .checked_sub(2 + 5)
+                    .ok_or(error)?;
+
+                let identifier = Self::read_jfif_identifier(stream);
+
+                let is_extension = match identifier {
+                    Some(is_extension) => is_extension,
+                    None => {
+                        // Some cameras put their own tags in APP0
+                        jpeg.options
+                            .warn(Warning::UnknownAPPnSkipped { marker: 0xE0 });
+
+                        Self::skip_bytes(stream, length as usize)?;
+
+                        return Ok(DecodingOutcome::None);
+                    }
+                };
+
+                if !is_extension {
+                    if jpeg.jfif.is_some() {
+                        jpeg.options.warn(Warning::MultipleJFIFSegments);
+
+                        Self::skip_bytes(stream, length as usize)?;
+
+                        return Ok(DecodingOutcome::None);
+                    }
+                    let major_version = expect_next!(stream, error);
+                    let minor_version = expect_next!(stream, error);
+
+                    let units = expect_next!(stream, error);
+
+                    let units = match units {
+                        0x00 => JfifUnit::NoUnit,
+                        0x01 => JfifUnit::PerInch,
+                        0x02 => JfifUnit::PerCenti,
+                        _ => return Err(error),
+                    };
+
+                    let x_density = {
+                        let f = expect_next!(stream, error);
+                        let s = expect_next!(stream, error);
+
+                        ((f as u16) << 8) | (s as u16)
+                    };
+
+                    let y_density = {
+                        let f = expect_next!(stream, error);
+                        let s = expect_next!(stream, error);
+
+                        ((f as u16) << 8) | (s as u16)
+                    };
+
+                    let x_thumbnail = expect_next!(stream, error);
+                    let y_thumbnail = expect_next!(stream, error);
+
+                    length = length.checked_sub(9).ok_or(error)?;
+
+                    let mut thumbnail_data = Vec::with_capacity(length as usize);
+
+                    for _ in 0..length {
+                        let byte = expect_next!(stream, error);
+                        thumbnail_data.push(byte);
+                    }
+
+                    let ap = APP0 {
+                        major_version,
+                        minor_version,
+                        units,
+                        x_density,
+                        y_density,
+                        x_thumbnail,
+                        y_thumbnail,
+                        thumbnail_data,
+                        jpeg_thumbnail: None,
+                    };
+
+                    jpeg.jfif = Some(ap);
+                } else {
+                    let extension_code = expect_next!(stream, error);
+                    length = length.checked_sub(1).ok_or(error)?;
+
+                    // Only JPEG thumbnails are kept, the palette and RGB
+                    // extensions are skipped
+                    if extension_code != Self::JFXX_JPEG_THUMBNAIL {
+                        Self::skip_bytes(stream, length as usize)?;
+
+                        return Ok(DecodingOutcome::None);
+                    }
+
+                    let mut thumbnail = Vec::with_capacity(length as usize);
+
+                    for _ in 0..length {
+                        let byte = expect_next!(stream, error);
+                        thumbnail.push(byte);
+                    }
+
+                    // The extension belongs to the JFIF segment before it
+                    match &mut jpeg.jfif {
+                        Some(jfif) => jfif.jpeg_thumbnail = Some(thumbnail),
+                        None => jpeg.options.warn(Warning::JFIFExtensionWithoutJFIF),
+                    }
+                }
+
+                Ok(DecodingOutcome::None)
+            }
+        }
+    }
+
+    fn scan<I>(stream: &mut ByteStream<I>, jpeg: &mut JPEGHeader) -> Result<()>
+    where
+        I: Iterator<Item = u8>,
+    {
+        loop {
+            match stream.next() {
+                None => {
+                    if !jpeg.options.lenient {
+                        return Err(Error::PrematureEnd);
+                    }
+
+                    // What is there may still cover every MCU
+                    jpeg.options.warn(Warning::TruncatedHuffmanData);
+                    break;
+                }
+                Some(current) => {
+                    if current == 0xFF {
+                        let next = stream.peek();
+
+                        // A 0xFF ending the data is left to the end of data case
+                        if next == Some(&Marker::HEX_EOI) {
+                            break;
+                        } else if next == Some(&0x00) {
+                            jpeg.huffman_data.push(current);
+                            stream.next();
+                        } else if next.is_some_and(|next| (0xD0..=0xD7).contains(next)) {
+                            jpeg.restart_offsets.push(jpeg.huffman_data.len());
+                            stream.next();
+                        } else if next.is_some_and(|next| *next != 0xFF) {
+                            // Any other marker ends the scan before its EOI
+                            if !jpeg.options.lenient {
+                                return Err(Error::InvalidScanData);
+                            }
+
+                            jpeg.options.warn(Warning::TruncatedHuffmanData);
+                            break;
+                        }
+                    } else {
+                        jpeg.huffman_data.push(current);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read<I>(stream: &mut ByteStream<I>, jpeg: &mut JPEGHeader) -> Result<DecodingOutcome>
+    where
+        I: Iterator<Item = u8>,
+    {
+        // Skip repetitions of 0xFF
+        while let Some(marker) = stream.peek() {
+            if *marker != 0xFF {
+                break;
+            } else {
+                stream.next();
+            }
+        }
+
+        // The 0xFF right before the code, the same as MarkerInfo::byte_offset
+        let offset = stream.offset() - 1;
+
+        // Only fill bytes were left
+        let marker = expect_next!(stream, Error::PrematureEnd);
+
+        match Self::new(marker) {
+            Some(kind) => {
+                debug!("Marker 0x{marker:02X} ({kind:?}) at byte offset {offset}");
+
+                if kind == Self::SOI {
+                    return Err(Error::MultipleSOI);
+                }
+
+                if kind == Self::APPN {
+                    jpeg.options.warn(Warning::UnknownAPPnSkipped { marker });
+                }
+
+                kind.process(stream, jpeg)
+            }
+            None => Err(Error::UnknownMarker {
+                byte: marker,
+                offset,
+            }),
+        }
+    }
+}
+
+/// Tallies the DC and AC symbols a baseline encoder writes for one component
+/// of `mcus`, which are the symbols decoding gave back. DC predictions start
+/// over every `restart_interval` MCUs
+fn symbol_frequency_histogram(
+    mcus: &[MCU],
+    layout: BlockLayout,
+    component: usize,
+    restart_interval: usize,
+) -> ([u32; 256], [u32; 256]) {
+    // Number of bits needed for the value
+    fn size(value: i32) -> u8 {
+        (32 - value.unsigned_abs().leading_zeros()) as u8
+    }
+
+    let mut dc = [0; 256];
+    let mut ac = [0; 256];
+    let mut previous_dc = 0;
+
+    for (i, mcu) in mcus.chunks_exact(layout.group).enumerate() {
+        // Markers sit between intervals, the first one starts at 0 anyway
+        if restart_interval != 0 && i > 0 && i % restart_interval == 0 {
+            previous_dc = 0;
+        }
+
+        for block in &mcu[..layout.blocks(component)] {
+            let block = block.channel(component);
+
+            dc[size(block[0] - previous_dc) as usize] += 1;
+            previous_dc = block[0];
+
+            let mut zeros = 0;
+
+            for index in &ZIGZAG[1..] {
+                let coefficient = block[*index as usize];
+
+                if coefficient == 0 {
+                    zeros += 1;
+                    continue;
+                }
+
+                // 0xF0 is a run of 16 zeros
+                while zeros > 15 {
+                    ac[0xF0] += 1;
+                    zeros -= 16;
+                }
+
+                ac[(zeros << 4) | size(coefficient) as usize] += 1;
+                zeros = 0;
+            }
+
+            // End of block
+            if zeros > 0 {
+                ac[0x00] += 1;
+            }
+        }
+    }
+
+    (dc, ac)
+}
+
+/// Shrinks an interleaved RGB image. Each output pixel is the average of the
+/// source pixels it covers
+fn area_average(
+    pixels: &[u8],
+    width: usize,
+    height: usize,
+    new_width: usize,
+    new_height: usize,
+) -> Vec<u8> {
+    let mut output = Vec::with_capacity(new_width * new_height * 3);
+
+    for y in 0..new_height {
+        let top = y * height / new_height;
+        let bottom = ((y + 1) * height / new_height).max(top + 1);
+
+        for x in 0..new_width {
+            let left = x * width / new_width;
+            let right = ((x + 1) * width / new_width).max(left + 1);
+
+            let mut sums = [0; 3];
+
+            for row in top..bottom {
+                for pixel in pixels[(row * width + left) * 3..(row * width + right) * 3].chunks(3) {
+                    for (sum, value) in sums.iter_mut().zip(pixel) {
+                        *sum += *value as usize;
+                    }
+                }
+            }
+
+            let count = (bottom - top) * (right - left);
+
+            output.extend(sums.map(|sum| ((sum + count / 2) / count) as u8));
+        }
+    }
+
+    output
+}
+
+/// Peekable byte iterator that counts the bytes it has given out, so errors
+/// can point at where they happened
+struct ByteStream<I: Iterator<Item = u8>> {
+    bytes: Peekable<I>,
+    offset: usize,
+}
+
+impl<I: Iterator<Item = u8>> ByteStream<I> {
+    fn new(bytes: I) -> Self {
+        Self {
+            bytes: bytes.peekable(),
+            offset: 0,
+        }
+    }
+
+    fn peek(&mut self) -> Option<&u8> {
+        self.bytes.peek()
+    }
+
+    /// Offset of the next byte from the start of the stream
+    fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<I: Iterator<Item = u8>> Iterator for ByteStream<I> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.bytes.next()?;
+        self.offset += 1;
+
+        Some(byte)
+    }
+}
+
+/// Byte iterator over a [`BufRead`]. Only refills when the buffer is empty
+struct BufReadBytes<R> {
+    reader: R,
+}
+
+impl<R: BufRead> Iterator for BufReadBytes<R> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = *self.reader.fill_buf().ok()?.first()?;
+        self.reader.consume(1);
+
+        Some(byte)
+    }
+}
+
+/// Appends a marker segment. The length includes its own two bytes, so a
+/// payload over 65533 bytes fails with [`Error::SegmentTooLong`]
+fn push_segment(bytes: &mut Vec<u8>, marker: u8, payload: &[u8]) -> Result<()> {
+    let length = u16::try_from(payload.len() + 2).map_err(|_| Error::SegmentTooLong { marker })?;
+
+    bytes.extend_from_slice(&[0xFF, marker]);
+    bytes.extend_from_slice(&length.to_be_bytes());
+    bytes.extend_from_slice(payload);
+
+    Ok(())
+}
+
+/// Appends the JFIF APP0 segment with its uncompressed thumbnail. A JFXX
+/// thumbnail goes in a segment of its own
+fn push_jfif_segment(bytes: &mut Vec<u8>, jfif: &APP0) -> Result<()> {
+    let mut payload = b"JFIF\0".to_vec();
+    payload.extend_from_slice(&[jfif.major_version, jfif.minor_version]);
+    payload.push(match jfif.units {
+        JfifUnit::NoUnit => 0x00,
+        JfifUnit::PerInch => 0x01,
+        JfifUnit::PerCenti => 0x02,
+    });
+    payload.extend_from_slice(&jfif.x_density.to_be_bytes());
+    payload.extend_from_slice(&jfif.y_density.to_be_bytes());
+    payload.extend_from_slice(&[jfif.x_thumbnail, jfif.y_thumbnail]);
+    payload.extend_from_slice(&jfif.thumbnail_data);
+
+    push_segment(bytes, 0xE0, &payload)
+}
+
+fn push_adobe_segment(bytes: &mut Vec<u8>, adobe: &AdobeApp14) -> Result<()> {
+    let mut payload = b"Adobe".to_vec();
+    payload.extend_from_slice(&adobe.version.to_be_bytes());
+    payload.extend_from_slice(&adobe.flags0.to_be_bytes());
+    payload.extend_from_slice(&adobe.flags1.to_be_bytes());
+    payload.push(adobe.color_transform);
+
+    push_segment(bytes, 0xEE, &payload)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+enum JfifUnit {
+    #[default]
+    NoUnit,
+    PerInch,
+    PerCenti,
+}
+
+impl From<JfifUnit> for &'static str {
+    fn from(unit: JfifUnit) -> Self {
+        match unit {
+            JfifUnit::NoUnit => "no-unit",
+            JfifUnit::PerInch => "dpi",
+            JfifUnit::PerCenti => "dpcm",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Default)]
+struct APP0 {
+    major_version: u8,
+    minor_version: u8,
+    units: JfifUnit,
+    x_density: u16,
+    y_density: u16,
+    x_thumbnail: u8,
+    y_thumbnail: u8,
+    thumbnail_data: Vec<u8>,
+    /// JPEG stream from a JFXX extension segment
+    jpeg_thumbnail: Option<Vec<u8>>,
+}
+
+impl APP0 {
+    /// The uncompressed thumbnail as `(rgb_bytes, width, height)`. `None` if
+    /// there is no thumbnail or its data doesn't match the dimensions
+    fn thumbnail(&self) -> Option<(&[u8], u8, u8)> {
+        let size = 3 * self.x_thumbnail as usize * self.y_thumbnail as usize;
+
+        if size == 0 || self.thumbnail_data.len() != size {
+            return None;
+        }
+
+        Some((&self.thumbnail_data, self.x_thumbnail, self.y_thumbnail))
+    }
+
+    /// Parses the thumbnail from the JFXX extension. Thumbnails nested in the
+    /// thumbnail are dropped so there is at most one level
+    fn thumbnail_jpeg(&self) -> Option<JPEGHeader> {
+        let mut thumbnail = JPEGHeader::from_bytes(self.jpeg_thumbnail.as_ref()?).ok()?;
+
+        if let Some(jfif) = &mut thumbnail.jfif {
+            jfif.jpeg_thumbnail = None;
+        }
+
+        Some(thumbnail)
+    }
+
+    /// Without units the densities only give the shape of a pixel, as
+    /// width over height
+    fn pixel_aspect_ratio(&self) -> Option<f32> {
+        if self.units != JfifUnit::NoUnit || self.y_density == 0 {
+            return None;
+        }
+
+        Some(self.x_density as f32 / self.y_density as f32)
+    }
+
+    /// Horizontal and vertical dots per inch. Densities per centimetre are
+    /// converted
+    fn dpi(&self) -> Option<(f32, f32)> {
+        let scale = match self.units {
+            JfifUnit::NoUnit => return None,
+            JfifUnit::PerInch => 1.0,
+            JfifUnit::PerCenti => 2.54,
+        };
+
+        Some((self.x_density as f32 * scale, self.y_density as f32 * scale))
+    }
+
+    /// Printed width and height in millimetres of an image with these
+    /// densities
+    fn physical_size_mm(&self, img_width: u16, img_height: u16) -> Option<(f32, f32)> {
+        let (x_dpi, y_dpi) = self.dpi()?;
+
+        if x_dpi == 0.0 || y_dpi == 0.0 {
+            return None;
+        }
+
+        Some((
+            img_width as f32 / x_dpi * 25.4,
+            img_height as f32 / y_dpi * 25.4,
+        ))
+    }
+}
+
+/// Color transform values are 0 = RGB (or CMYK), 1 = YCbCr and 2 = YCCK
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+struct AdobeApp14 {
+    version: u16,
+    flags0: u16,
+    flags1: u16,
+    color_transform: u8,
+}
+
+/// What a table quantizes. A JPEG doesn't record it, so it follows from the
+/// table id when parsing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QTableType {
+    Luminance,
+    Chrominance,
+    Other,
+}
+
+impl QTableType {
+    /// The kind follows from the table id, like in the Annex K examples
+    fn from_destination(id: u8) -> Option<Self> {
+        match id {
+            0x00 => Some(Self::Luminance),
+            0x01 => Some(Self::Chrominance),
+            0x02 | 0x03 => Some(Self::Other),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QTable {
+    is_set: bool,
+    is_extended_mode: bool,
+    kind: QTableType,
+    table: [u16; 64],
+}
+
+impl Default for QTable {
+    fn default() -> Self {
+        Self {
+            is_set: false,
+            is_extended_mode: false,
+            kind: QTableType::Other,
+            table: [0; 64],
+        }
+    }
+}
+
+impl QTable {
+    /// `table` is in natural (row major) order. Entries over 255 are written
+    /// with 16 bit precision
+    #[must_use = "a QTable does nothing until it is given to a JPEGHeader"]
+    pub fn new(table: [u16; 64]) -> QTable {
+        Self {
+            is_set: true,
+            is_extended_mode: table.iter().any(|entry| *entry > 0xFF),
+            kind: QTableType::Other,
+            table,
+        }
+    }
+
+    /// The Annex K table for `kind` scaled with the IJG formula, the same
+    /// tables libjpeg writes for `quality`. Quality is clamped to 1 - 100.
+    /// [`QTableType::Other`] has no table of its own and uses the luminance
+    /// one
+    #[must_use = "a QTable does nothing until it is given to a JPEGHeader"]
+    pub fn from_quality(quality: u8, kind: QTableType) -> QTable {
+        let standard = match kind {
+            QTableType::Chrominance => &CHROMINANCE_QTABLE,
+            QTableType::Luminance | QTableType::Other => &LUMINANCE_QTABLE,
+        };
+
+        Self {
+            kind,
+            ..Self::new(scale_qtable(standard, quality))
+        }
+    }
+
+    /// Multiplies every entry by `factor`. Entries are rounded and kept in
+    /// `1..=255`, or `1..=32767` for a 16 bit table. `None` when `factor` is
+    /// NaN, infinite or not positive
+    #[must_use = "the scaled table is returned and the input is left as is"]
+    pub fn scale(&self, factor: f32) -> Option<QTable> {
+        if !factor.is_finite() || factor <= 0.0 {
+            return None;
+        }
+
+        let max = if self.is_extended_mode {
+            32767.0
+        } else {
+            255.0
+        };
+
+        let mut scaled = *self;
+
+        for (entry, original) in scaled.table.iter_mut().zip(self.table) {
+            *entry = (original as f32 * factor).round().clamp(1.0, max) as u16;
+        }
+
+        Some(scaled)
+    }
+
+    /// Sum of the entries. Larger steps throw away more, so a higher score
+    /// means lower quality
+    #[must_use = "the table is not modified"]
+    pub fn quality_score(&self) -> u64 {
+        self.table.iter().map(|entry| *entry as u64).sum()
+    }
+
+    /// Same entries as the Annex K luminance table
+    #[must_use = "the table is not modified"]
+    pub fn matches_standard_luma(&self) -> bool {
+        self.table == LUMINANCE_QTABLE
+    }
+
+    /// Same entries as the Annex K chrominance table
+    #[must_use = "the table is not modified"]
+    pub fn matches_standard_chroma(&self) -> bool {
+        self.table == CHROMINANCE_QTABLE
+    }
+
+    /// Finds the IJG quality that scales the Annex K table of the same kind
+    /// into this table. Every entry has to be within 1 of the scaled table,
+    /// otherwise the table wasn't made with the IJG formula
+    fn quality_estimate(&self) -> Option<u8> {
+        let standard = match self.kind {
+            QTableType::Luminance => &LUMINANCE_QTABLE,
+            QTableType::Chrominance => &CHROMINANCE_QTABLE,
+            QTableType::Other => return None,
+        };
+
+        let mut best = None;
+
+        for quality in 1..=100 {
+            let scaled = scale_qtable(standard, quality);
+
+            let mut error = 0;
+            let mut is_close = true;
+
+            for (entry, expected) in self.table.iter().zip(scaled) {
+                let difference = entry.abs_diff(expected);
+                is_close &= difference <= 1;
+                error += difference as u32;
+            }
+
+            if !is_close {
+                continue;
+            }
+
+            match best {
+                Some((_, best_error)) if best_error <= error => {}
+                _ => best = Some((quality, error)),
+            }
+        }
+
+        best.map(|(quality, _)| quality)
+    }
+}
+
+/// Ordered by [`QTable::quality_score`]. Ties fall back to the entries so
+/// only equal tables compare as equal
+impl Ord for QTable {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        let key = |qtable: &Self| {
+            (
+                qtable.quality_score(),
+                qtable.table,
+                qtable.is_extended_mode,
+                qtable.is_set,
+                qtable.kind as u8,
+            )
+        };
+
+        key(self).cmp(&key(other))
+    }
+}
+
+impl PartialOrd for QTable {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct ColorComponent {
+    id: u8,
+    hfactor: u8,
+    vfactor: u8,
+    qtable: u8,
+    huffman_table_ac_id: u8,
+    huffman_table_dc_id: u8,
+    is_used_sof: bool,
+    is_used_sos: bool,
+}
+
+impl ColorComponent {
+    /// Declared in the frame and coded in the scan
+    fn is_active(&self) -> bool {
+        self.is_used_sof && self.is_used_sos
+    }
+
+    /// Checks the component's QTable id is in range and the table it names
+    /// was defined. 16 bit tables with 8 bit samples are valid, parsing only
+    /// warns about them, see [`JPEGHeader::has_extended_qtables`]
+    fn validate_against_qtables(&self, qtables: &[QTable; 4]) -> Result<()> {
+        match qtables.get(self.qtable as usize) {
+            Some(qtable) if qtable.is_set => Ok(()),
+            _ => Err(Error::ComponentQTableMismatch {
+                component_id: self.id,
+                qtable_id: self.qtable,
+            }),
+        }
+    }
+
+    fn sampling_mode(&self) -> SamplingMode {
+        match (self.hfactor, self.vfactor) {
+            (1, 1) => SamplingMode::None,
+            (2, 1) => SamplingMode::Horizontal,
+            (1, 2) => SamplingMode::Vertical,
+            (2, 2) => SamplingMode::Both,
+            (h, v) => SamplingMode::NonStandard(h, v),
+        }
+    }
+
+    /// How the component's blocks are stretched over an MCU, which is the
+    /// [`SamplingMode`] of the largest factors over its own. `None` when they
+    /// don't divide evenly
+    fn upsampling(&self, max_hfactor: u8, max_vfactor: u8) -> Option<SamplingMode> {
+        if !max_hfactor.is_multiple_of(self.hfactor) || !max_vfactor.is_multiple_of(self.vfactor) {
+            return None;
+        }
+
+        let ratio = ColorComponent {
+            hfactor: max_hfactor / self.hfactor,
+            vfactor: max_vfactor / self.vfactor,
+            ..*self
+        };
+
+        Some(ratio.sampling_mode())
+    }
+
+    /// Samples per line and lines of this component in an image of the given
+    /// size, where the max factors are the largest among the frame's
+    /// components. A.1.1 rounds up, so a 4:2:0 chroma of a 1x1 image is 1x1
+    fn effective_resolution(
+        &self,
+        img_width: u16,
+        img_height: u16,
+        max_hfactor: u8,
+        max_vfactor: u8,
+    ) -> (u16, u16) {
+        let scale = |size: u16, factor: u8, max: u8| {
+            (size as u32 * factor as u32).div_ceil(max.max(1) as u32) as u16
+        };
+
+        (
+            scale(img_width, self.hfactor, max_hfactor),
+            scale(img_height, self.vfactor, max_vfactor),
+        )
+    }
+}
+
+/// Slot in [`JPEGHeader`]'s components of a frame or scan component id,
+/// after zero based ids are shifted up. Besides 1 - 3, the ASCII ids
+/// 'R', 'G' and 'B' some encoders use for RGB images are accepted
+fn component_index(id: u8) -> Option<usize> {
+    match id {
+        1..=3 => Some(id as usize - 1),
+        b'R' => Some(0),
+        b'G' => Some(1),
+        b'B' => Some(2),
+        _ => None,
+    }
+}
+
+/// Which directions a component's sampling factors double in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SamplingMode {
+    None,
+    Horizontal,
+    Vertical,
+    Both,
+    NonStandard(u8, u8),
+}
+
+/// A Huffman table from a DHT segment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HuffmanTable {
+    /// Symbols with codes of length `n` are `symbols[offsets[n - 1]..offsets[n]]`
+    offsets: [u8; 17],
+    /// In code order, the first `offsets[16]` are used
+    symbols: [u8; 162],
+    /// Code of each symbol, right aligned
+    codes: [u32; 162],
+    /// (symbol, code length) indexed by the next [`LOOKUP_BITS`] bits.
+    /// A length of 0 means the code is longer than the table covers
+    lookup: [(u8, u8); 1 << LOOKUP_BITS],
+    /// (code, code length) indexed by symbol, for encoding. A length of 0
+    /// means the symbol has no code
+    symbol_codes: [(u16, u8); 256],
+    is_set: bool,
+}
+
+impl Default for HuffmanTable {
+    fn default() -> Self {
+        Self {
+            offsets: [0; 17],
+            symbols: [0; 162],
+            codes: [0; 162],
+            lookup: [(0, 0); 1 << LOOKUP_BITS],
+            symbol_codes: [(0, 0); 256],
+            is_set: false,
+        }
+    }
+}
+
+impl HuffmanTable {
+    /// Table from the number of codes of each length and the symbols in code
+    /// order, like in a DHT segment
+    pub(super) fn new(bits: &[u8; 16], values: &[u8]) -> Self {
+        let mut table = Self {
+            is_set: true,
+            ..Self::default()
+        };
+
+        for (i, count) in bits.iter().enumerate() {
+            table.offsets[i + 1] = table.offsets[i] + count;
+        }
+
+        table.symbols[..values.len()].copy_from_slice(values);
+        table.generate_codes();
+        table.build_lookup_table();
+
+        table
+    }
+
+    /// [`Self::new`] for tables that don't come from the Annex K constants.
+    /// The counts have to add up to the number of symbols, at most 162
+    fn checked(bits: &[u8; 16], values: &[u8]) -> core::result::Result<Self, DHTError> {
+        let total: usize = bits.iter().map(|&count| count as usize).sum();
+
+        if total != values.len() || total > 162 {
+            return Err(DHTError::InvalidSymbolsLength);
+        }
+
+        Ok(Self::new(bits, values))
+    }
+
+    /// Set by a DHT segment or the builder. Unset tables are all zeros
+    #[must_use = "the table is not modified"]
+    pub fn is_set(&self) -> bool {
+        self.is_set
+    }
+
+    /// Symbols with codes of length `n` are `symbols()[offsets[n - 1]..offsets[n]]`
+    #[must_use = "the table is not modified"]
+    pub fn offsets(&self) -> &[u8; 17] {
+        &self.offsets
+    }
+
+    /// The symbols in code order
+    #[must_use = "the table is not modified"]
+    pub fn symbols(&self) -> &[u8] {
+        &self.symbols[..self.offsets[16] as usize]
+    }
+
+    /// Code of each symbol of [`Self::symbols`], right aligned. Like
+    /// [`Self::code_for_symbol`] they are only there once generated
+    #[must_use = "the table is not modified"]
+    pub fn codes(&self) -> &[u32] {
+        &self.codes[..self.offsets[16] as usize]
+    }
+
+    /// Payload of a DHT segment holding just this table. `class` is 0 for
+    /// DC and 1 for AC
+    fn dht_payload(&self, class: u8, id: u8) -> Vec<u8> {
+        let mut payload = vec![(class << 4) | id];
+
+        for length in 0..16 {
+            payload.push(self.offsets[length + 1] - self.offsets[length]);
+        }
+
+        payload.extend_from_slice(&self.symbols[..self.offsets[16] as usize]);
+
+        payload
+    }
+
+    /// Code and code length of `symbol`, the reverse of decoding. `None`
+    /// when the table has no code for it. Tables from a parsed stream only
+    /// have their codes once the scan has been decoded
+    #[must_use = "the table is not modified"]
+    pub fn code_for_symbol(&self, symbol: u8) -> Option<(u32, u8)> {
+        match self.symbol_codes[symbol as usize] {
+            (_, 0) => None,
+            (code, length) => Some((code as u32, length)),
+        }
+    }
+
+    /// Zero or one symbol. Legal, but there is nothing to search for
+    fn is_degenerate(&self) -> bool {
+        self.offsets[16] <= 1
+    }
+
+    fn generate_codes(&mut self) {
+        self.symbol_codes = [(0, 0); 256];
+
+        let mut code = 0;
+
+        for i in 0..16 {
+            let current = self.offsets[i];
+            let next = self.offsets[i + 1];
+            let length = i as u8 + 1;
+
+            for c in current..next {
+                self.codes[c as usize] = code;
+
+                // The first code wins for repeated symbols, codes that
+                // overflow their length can't be written
+                let entry = &mut self.symbol_codes[self.symbols[c as usize] as usize];
+
+                if entry.1 == 0 && code >> length == 0 {
+                    *entry = (code as u16, length);
+                }
+
+                code += 1;
+            }
+
+            code <<= 1;
+        }
+    }
+
+    /// Whether the generated codes follow C.2: each length starts right
+    /// after the last code of the length before, shifted left by one, and
+    /// every code fits in its length. Too many codes of a length for the
+    /// bits available overflow into the next length and fail the check
+    fn is_canonical(&self) -> bool {
+        let mut code = 0;
+
+        for length in 1..=16 {
+            let start = self.offsets[length - 1] as usize;
+            let end = self.offsets[length] as usize;
+
+            for j in start..end {
+                if self.codes[j] != code || code >> length != 0 {
+                    return false;
+                }
+
+                code += 1;
+            }
+
+            code <<= 1;
+        }
+
+        true
+    }
+
+    /// Should be called after the codes have been generated
+    fn build_lookup_table(&mut self) {
+        self.lookup = [(0, 0); 1 << LOOKUP_BITS];
+
+        for length in 1..=LOOKUP_BITS {
+            let start = self.offsets[length as usize - 1];
+            let end = self.offsets[length as usize];
+
+            for j in start..end {
+                let j = j as usize;
+
+                // Every index starting with this code maps to its symbol
+                let shift = LOOKUP_BITS - length;
+                let first = (self.codes[j] << shift) as usize;
+                let last = first + (1 << shift);
+
+                // Malformed tables can produce codes wider than their length
+                if last > self.lookup.len() {
+                    continue;
+                }
+
+                for entry in &mut self.lookup[first..last] {
+                    *entry = (self.symbols[j], length);
+                }
+            }
+        }
+    }
+}
+
+#[must_use = "the outcome records which required segments were seen"]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DecodingOutcome {
+    None,
+    QTableSet,
+    StartOfFrame,
+    HuffmanTable,
+    StartOfScan,
+    EndOfImage,
+}
+
+/// JFIF conversion of a Y, Cb and Cr sample to RGB
+fn ycbcr_to_rgb([y, cb, cr]: [i32; 3]) -> [i32; 3] {
+    let y = y as f32;
+    let cb = cb as f32 - 128.0;
+    let cr = cr as f32 - 128.0;
+
+    [
+        (y + 1.402 * cr).round() as i32,
+        (y - 0.344_136 * cb - 0.714_136 * cr).round() as i32,
+        (y + 1.772 * cb).round() as i32,
+    ]
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct MCU {
+    r: [i32; 64],
+    g: [i32; 64],
+    b: [i32; 64],
+    is_rbg: bool,
+}
+
+impl MCU {
+    pub(super) fn component(&mut self, index: usize) -> &mut [i32; 64] {
+        match index {
+            0 => &mut self.r,
+            1 => &mut self.g,
+            2 => &mut self.b,
+            _ => panic!("Invalid MCU component index"),
+        }
+    }
+
+    pub(super) fn channel(&self, index: usize) -> &[i32; 64] {
+        match index {
+            0 => &self.r,
+            1 => &self.g,
+            2 => &self.b,
+            _ => panic!("Invalid MCU component index"),
+        }
+    }
+
+    /// `(min, max, mean)` of the values of one channel
+    fn channel_stats(&self, channel: usize) -> (i32, i32, f64) {
+        let values = self.channel(channel);
+
+        let min = values.iter().copied().min().unwrap_or_default();
+        let max = values.iter().copied().max().unwrap_or_default();
+        let mean = values.iter().map(|value| *value as f64).sum::<f64>() / values.len() as f64;
+
+        (min, max, mean)
+    }
+
+    /// Whether every AC coefficient of a channel is zero, so its IDCT is the
+    /// DC coefficient spread over the block
+    fn is_blank_channel(&self, channel: usize) -> bool {
+        self.channel(channel)[1..].iter().all(|&c| c == 0)
+    }
+
+    /// Whether all three channels are flat, see [`Self::is_blank_channel`]
+    fn is_blank(&self) -> bool {
+        (0..3).all(|channel| self.is_blank_channel(channel))
+    }
+
+    /// Dequantizes each component, runs the IDCT and shifts the samples back
+    /// with [`Self::apply_level_shift`]. Flat channels skip the full IDCT,
+    /// which rounds a lone DC coefficient to the same fill
+    fn reconstruct(&mut self, qtables: &[[u16; 64]; 3], block_size: usize) {
+        // Common in smooth areas, every channel is only its DC
+        if block_size == 8 && self.is_blank() {
+            for (j, qtable) in qtables.iter().enumerate() {
+                let component = self.component(j);
+                let dc = ((component[0] * qtable[0] as i32) as f32 / 8.0).round() as i32;
+                component.fill(dc);
+            }
+
+            self.apply_level_shift();
+            return;
+        }
+
+        for (j, qtable) in qtables.iter().enumerate() {
+            let is_flat = block_size == 8 && self.is_blank_channel(j);
+            let component = self.component(j);
+
+            for (coefficient, step) in component.iter_mut().zip(qtable) {
+                *coefficient *= *step as i32;
+            }
+
+            if is_flat {
+                let dc = (component[0] as f32 / 8.0).round() as i32;
+                component.fill(dc);
+            } else if block_size == 8 {
+                aan_idct_2d(component);
+            } else {
+                reduced_idct_2d(component, block_size);
+            }
+        }
+
+        self.apply_level_shift();
+    }
+
+    /// Divides each coefficient of a component by its step in `qtable`, the
+    /// forward of what [`MCU::reconstruct`] starts with. Halves round away
+    /// from zero, so -1.5 becomes -2 rather than -1
+    pub(super) fn quantize(&mut self, qtable: &QTable, component: usize) {
+        for (coefficient, step) in self.component(component).iter_mut().zip(&qtable.table) {
+            let step = *step as i32;
+            let quotient = (coefficient.abs() + step / 2) / step;
+
+            *coefficient = quotient * coefficient.signum();
+        }
+    }
+
+    /// Multiplies each coefficient of a component by its step in `qtable`
+    #[cfg(test)]
+    fn dequantize(&mut self, qtable: &QTable, component: usize) {
+        for (coefficient, step) in self.component(component).iter_mut().zip(&qtable.table) {
+            *coefficient *= *step as i32;
+        }
+    }
+
+    /// Moves the samples of every component from the signed `-128..=127`
+    /// range the IDCT works in to `0..=255`. Rounding and quantization can
+    /// overshoot, so the result is clamped
+    fn apply_level_shift(&mut self) {
+        for sample in self.r.iter_mut().chain(&mut self.g).chain(&mut self.b) {
+            *sample = (*sample + 128).clamp(0, 255);
+        }
+    }
+
+    /// JFIF conversion of the Y, Cb and Cr samples to RGB
+    fn ycbcr_to_rgb(&mut self) {
+        for i in 0..64 {
+            [self.r[i], self.g[i], self.b[i]] = ycbcr_to_rgb([self.r[i], self.g[i], self.b[i]]);
+        }
+
+        self.is_rbg = true;
+    }
+
+    /// The first coefficient of each component. Only meaningful once the
+    /// coefficients are dequantized, when each is 8 times the average sample
+    /// of its block before the level shift
+    fn dc_average(&self) -> (i32, i32, i32) {
+        (self.r[0], self.g[0], self.b[0])
+    }
+
+    /// Average color of the block from the DC coefficients alone, with the
+    /// same JFIF conversion as [`Self::ycbcr_to_rgb`] unless the components
+    /// already are `is_rgb`. Only valid after dequantization
+    fn average_rgb(&self, is_rgb: bool) -> (u8, u8, u8) {
+        let (y, cb, cr) = self.dc_average();
+
+        let channel = |value: f32| value.round().clamp(0.0, 255.0) as u8;
+
+        if is_rgb {
+            let level = |value: i32| channel(value as f32 / 8.0 + 128.0);
+
+            return (level(y), level(cb), level(cr));
+        }
+
+        let y = y as f32 / 8.0 + 128.0;
+        let cb = cb as f32 / 8.0;
+        let cr = cr as f32 / 8.0;
+
+        (
+            channel(y + 1.402 * cr),
+            channel(y - 0.344_136 * cb - 0.714_136 * cr),
+            channel(y + 1.772 * cb),
+        )
+    }
+
+    /// Writes the pixels of the MCU at (`mcu_x`, `mcu_y`) in the MCU grid into
+    /// an interleaved RGB buffer covering `window` of the image. Pixels
+    /// outside the window are skipped. After a scaled IDCT only the first
+    /// `block_size * block_size` values are pixels
+    fn write_to_buffer(
+        &self,
+        mcu_x: usize,
+        mcu_y: usize,
+        window: Rect,
+        block_size: usize,
+        buf: &mut [u8],
+    ) {
+        let pixels = self.r.iter().zip(self.g.iter()).zip(self.b.iter());
+
+        for (i, ((r, g), b)) in pixels.enumerate().take(block_size * block_size) {
+            let x = mcu_x * block_size + i % block_size;
+            let y = mcu_y * block_size + i / block_size;
+
+            if !window.contains(x, y) {
+                continue;
+            }
+
+            let offset =
+                ((y - window.y as usize) * window.width as usize + x - window.x as usize) * 3;
+
+            buf[offset] = (*r).clamp(0, 255) as u8;
+            buf[offset + 1] = (*g).clamp(0, 255) as u8;
+            buf[offset + 2] = (*b).clamp(0, 255) as u8;
+        }
+    }
+}
+
+impl Display for MCU {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MCU {{ ")?;
+
+        for (channel, name) in ["Y", "Cb", "Cr"].iter().enumerate() {
+            let (min, max, mean) = self.channel_stats(channel);
+
+            if channel != 0 {
+                write!(f, ", ")?;
+            }
+
+            write!(f, "{}: [min={}, max={}, mean={:.2}]", name, min, max, mean)?;
+        }
+
+        write!(f, " }}")
+    }
+}
+
+impl Default for MCU {
+    fn default() -> Self {
+        Self {
+            r: [0; 64],
+            g: [0; 64],
+            b: [0; 64],
+            is_rbg: true,
+        }
+    }
+}
+
+/// Copies a decoded group into its slots of the scan, initializing them
+fn write_group(slots: &mut [MaybeUninit<MCU>], group: &[MCU]) {
+    for (slot, mcu) in slots.iter_mut().zip(group) {
+        slot.write(*mcu);
+    }
+}
+
+/// Where the blocks of each component sit in a decoded scan. An MCU of an
+/// interleaved scan codes `hfactor * vfactor` blocks of every component, left
+/// to right then top to bottom, and takes `group` consecutive [`MCU`]s: the
+/// nth holds block n of each component that codes that many. A scan of a
+/// single component codes one block per MCU whatever its factors (A.2.2)
+#[derive(Debug, Clone, Copy)]
+struct BlockLayout {
+    /// The frame's components, with the factors of inactive ones and of a
+    /// single component scan set to 1x1
+    components: [ColorComponent; 3],
+    /// Largest factors, the size of an MCU in blocks of the largest
+    /// components
+    max_factors: (u8, u8),
+    /// MCUs per line
+    mcus_x: usize,
+    /// Lines of MCUs
+    mcus_y: usize,
+    /// [`MCU`]s per MCU of the scan
+    group: usize,
+}
+
+impl BlockLayout {
+    fn new(header: &JPEGHeader) -> Self {
+        let is_interleaved = header.active_component_count() > 1;
+
+        let components = header.components.map(|component| match is_interleaved {
+            true if component.is_active() => component,
+            _ => ColorComponent {
+                hfactor: 1,
+                vfactor: 1,
+                ..component
+            },
+        });
+
+        let max_hfactor = components.iter().map(|c| c.hfactor).max().unwrap_or(1);
+        let max_vfactor = components.iter().map(|c| c.vfactor).max().unwrap_or(1);
+
+        let mcu_width = 8 * max_hfactor as usize;
+        let mcu_height = 8 * max_vfactor as usize;
+
+        Self {
+            components,
+            max_factors: (max_hfactor, max_vfactor),
+            mcus_x: (header.width as usize).div_ceil(mcu_width),
+            mcus_y: (header.height as usize).div_ceil(mcu_height),
+            group: components
+                .iter()
+                .map(|c| c.hfactor as usize * c.vfactor as usize)
+                .max()
+                .unwrap_or(1),
+        }
+    }
+
+    fn mcu_count(&self) -> usize {
+        self.mcus_x * self.mcus_y
+    }
+
+    /// Whether any component has fewer samples than the image has pixels
+    fn is_subsampled(&self) -> bool {
+        self.max_factors != (1, 1)
+    }
+
+    /// Blocks across and down an MCU of component `j`
+    fn factors(&self, j: usize) -> (usize, usize) {
+        let component = &self.components[j];
+        (component.hfactor as usize, component.vfactor as usize)
+    }
+
+    /// Blocks component `j` codes per MCU
+    fn blocks(&self, j: usize) -> usize {
+        let (h, v) = self.factors(j);
+        h * v
+    }
+
+    /// Blocks of component `j` across and down the scan, padded to whole
+    /// MCUs
+    fn component_grid(&self, j: usize) -> (usize, usize) {
+        let (h, v) = self.factors(j);
+        (self.mcus_x * h, self.mcus_y * v)
+    }
+
+    /// Index in the decoded [`MCU`]s of block (`x`, `y`) of component `j`,
+    /// in its [`Self::component_grid`]
+    fn entry(&self, j: usize, x: usize, y: usize) -> usize {
+        let (h, v) = self.factors(j);
+        let mcu = (y / v) * self.mcus_x + x / h;
+
+        mcu * self.group + (y % v) * h + x % h
+    }
+
+    /// Which [`MCU`] of a group holds the block of component `j` covering
+    /// block (`x`, `y`) of the MCU, in blocks of the largest components.
+    /// Needs factors that divide the largest ones
+    fn covering_block(&self, j: usize, x: usize, y: usize) -> usize {
+        let (h, v) = self.factors(j);
+        let hratio = self.max_factors.0 as usize / h;
+        let vratio = self.max_factors.1 as usize / v;
+
+        (y / vratio) * h + x / hratio
+    }
+}
+
+/// Reconstructed samples of a subsampled frame, one plane per component
+/// sized by [`ColorComponent::effective_resolution`], and the upsampling
+/// that stretches them to the size of the image. Lines of MCUs are added one
+/// at a time and lines no longer needed can be dropped, so a streaming
+/// decode only holds a few
+#[derive(Debug, Clone)]
+struct SamplePlanes {
+    layout: BlockLayout,
+    /// How each component is stretched, see [`ColorComponent::upsampling`]
+    upsampling: [SamplingMode; 3],
+    qtables: [[u16; 64]; 3],
+    block_size: usize,
+    /// Samples per line and lines of each plane, at the decode scale
+    sizes: [(usize, usize); 3],
+    /// Lines of each plane from `tops` on, as wide as the blocks of the
+    /// component's [`BlockLayout::component_grid`]
+    samples: [Vec<i32>; 3],
+    tops: [usize; 3],
+    /// Lines of MCUs added or skipped so far
+    mcu_rows: usize,
+}
+
+impl SamplePlanes {
+    /// Samples per held line of component `j`
+    fn stride(&self, j: usize) -> usize {
+        self.layout.component_grid(j).0 * self.block_size
+    }
+
+    /// Lines of component `j` in a line of MCUs
+    fn mcu_lines(&self, j: usize) -> usize {
+        self.layout.factors(j).1 * self.block_size
+    }
+
+    /// Reconstructs a line of MCUs, the [`BlockLayout::group`]s of
+    /// [`BlockLayout::mcus_x`] MCUs, and adds its samples
+    fn push_mcu_row(&mut self, mcus: &mut [MCU]) {
+        let block_size = self.block_size;
+
+        for j in 0..3 {
+            let length = self.samples[j].len() + self.mcu_lines(j) * self.stride(j);
+            self.samples[j].resize(length, 0);
+        }
+
+        for (mcu_x, group) in mcus.chunks_exact_mut(self.layout.group).enumerate() {
+            for (b, mcu) in group.iter_mut().enumerate() {
+                mcu.reconstruct(&self.qtables, block_size);
+
+                for j in (0..3).filter(|j| b < self.layout.blocks(*j)) {
+                    let (h, v) = self.layout.factors(j);
+                    let stride = self.stride(j);
+
+                    let left = (mcu_x * h + b % h) * block_size;
+                    let top = (self.mcu_rows * v + b / h) * block_size - self.tops[j];
+
+                    let block = mcu.channel(j).chunks_exact(block_size);
+
+                    for (y, line) in block.take(block_size).enumerate() {
+                        let start = (top + y) * stride + left;
+                        self.samples[j][start..start + block_size].copy_from_slice(line);
+                    }
+                }
+            }
+        }
+
+        self.mcu_rows += 1;
+    }
+
+    /// Moves past a line of MCUs without reconstructing it. Only valid
+    /// before any line was added
+    fn skip_mcu_row(&mut self) {
+        self.mcu_rows += 1;
+
+        for j in 0..3 {
+            self.tops[j] = self.mcu_rows * self.mcu_lines(j);
+        }
+    }
+
+    /// Lines of component `j` the upsampling of image line `y` reads
+    fn lines(&self, j: usize, y: usize) -> RangeInclusive<usize> {
+        let vratio = self.layout.max_factors.1 as usize / self.layout.factors(j).1;
+        let last = self.sizes[j].1 - 1;
+
+        match self.upsampling[j] {
+            SamplingMode::Vertical | SamplingMode::Both => {
+                (y / 2).saturating_sub(1).min(last)..=(y / 2 + 1).min(last)
+            }
+            _ => (y / vratio).min(last)..=(y / vratio).min(last),
+        }
+    }
+
+    /// Whether the lines image line `y` needs were added
+    fn holds_line(&self, y: usize) -> bool {
+        (0..3).all(|j| *self.lines(j, y).end() < self.mcu_rows * self.mcu_lines(j))
+    }
+
+    /// First line of MCUs image line `y` needs
+    fn first_mcu_row(&self, y: usize) -> usize {
+        (0..3)
+            .map(|j| self.lines(j, y).start() / self.mcu_lines(j))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Drops the lines before the ones image line `y` needs
+    fn release(&mut self, y: usize) {
+        for j in 0..3 {
+            let first = *self.lines(j, y).start();
+
+            if first > self.tops[j] {
+                let stride = self.stride(j);
+                self.samples[j].drain(..(first - self.tops[j]) * stride);
+                self.tops[j] = first;
+            }
+        }
+    }
+
+    /// Sample (`x`, `y`) of component `j`. Coordinates past the plane are
+    /// clamped to its edge
+    fn sample(&self, j: usize, x: usize, y: usize) -> i32 {
+        let (width, height) = self.sizes[j];
+        let x = x.min(width - 1);
+        let y = y.min(height - 1);
+
+        self.samples[j][(y - self.tops[j]) * self.stride(j) + x]
+    }
+
+    /// Sample of component `j` at pixel (`x`, `y`) of the image. Doubled
+    /// directions weigh the nearest sample 3 to 1 against the next one like
+    /// libjpeg's fancy upsampling, other ratios repeat samples
+    fn upsampled(&self, j: usize, x: usize, y: usize) -> i32 {
+        // The second nearest sample, before for the first pixel of a pair
+        // and after for the second
+        let neighbour = |position: usize| match position % 2 {
+            0 => (position / 2).saturating_sub(1),
+            _ => position / 2 + 1,
+        };
+
+        match self.upsampling[j] {
+            SamplingMode::None => self.sample(j, x, y),
+            SamplingMode::Horizontal => {
+                let sum = 3 * self.sample(j, x / 2, y) + self.sample(j, neighbour(x), y);
+                (sum + 1 + (x % 2) as i32) >> 2
+            }
+            SamplingMode::Vertical => {
+                let sum = 3 * self.sample(j, x, y / 2) + self.sample(j, x, neighbour(y));
+                (sum + 1 + (y % 2) as i32) >> 2
+            }
+            SamplingMode::Both => {
+                let column = |i| 3 * self.sample(j, i, y / 2) + self.sample(j, i, neighbour(y));
+                let sum = 3 * column(x / 2) + column(neighbour(x));
+                (sum + 8 - (x % 2) as i32) >> 4
+            }
+            SamplingMode::NonStandard(h, v) => self.sample(j, x / h as usize, y / v as usize),
+        }
+    }
+
+    /// Writes pixels `columns` of image line `y` to `buf`, as interleaved RGB
+    /// when `to_rgb` and YCbCr otherwise
+    fn write_line(&self, y: usize, columns: Range<usize>, to_rgb: bool, buf: &mut [u8]) {
+        for (x, pixel) in columns.zip(buf.chunks_exact_mut(3)) {
+            let samples = [0, 1, 2].map(|j| self.upsampled(j, x, y));
+            let samples = if to_rgb {
+                ycbcr_to_rgb(samples)
+            } else {
+                samples
+            };
+
+            for (channel, sample) in pixel.iter_mut().zip(samples) {
+                *channel = sample.clamp(0, 255) as u8;
+            }
+        }
+    }
+}
+
+#[must_use = "a BitReader does nothing until it is read from"]
+#[derive(Debug, Clone)]
+struct BitReader<'a> {
+    data: &'a [u8],
+    bit_position: usize,
+    byte_position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            bit_position: 0,
+            byte_position: 0,
+        }
+    }
+
+    #[inline]
+    fn read_length(&mut self, length: u8) -> Option<u32> {
+        let mut output = 0;
+
+        for _ in 0..length {
+            let bit = self.read_bit()?;
+            output = (output << 1) | bit;
+        }
+
+        Some(output)
+    }
+
+    #[inline]
+    fn read_bit(&mut self) -> Option<u32> {
+        let byte = self.data.get(self.byte_position)?;
+
+        // Read bit from most to least significant
+        let bit = ((byte >> (7 - self.bit_position)) & 1) as u32;
+        self.bit_position += 1;
+
+        if self.bit_position == 8 {
+            self.bit_position = 0;
+            self.byte_position += 1;
+        }
+
+        Some(bit)
+    }
+
+    /// Reads up to 16 bits without advancing. `None` if fewer than `length`
+    /// bits are left
+    #[inline]
+    fn peek_bits(&self, length: u8) -> Option<u32> {
+        // 24 bits always cover 16 bits starting at any bit position
+        let window = match self.data.get(self.byte_position..self.byte_position + 3) {
+            Some(bytes) => ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | (bytes[2] as u32),
+            None => self.padded_window(length)?,
+        };
+
+        let shift = 24 - self.bit_position as u32 - length as u32;
+
+        Some((window >> shift) & ((1 << length) - 1))
+    }
+
+    /// The 24 bit window of [`Self::peek_bits`] within the last 2 bytes,
+    /// padded with zeros past the end
+    #[cold]
+    fn padded_window(&self, length: u8) -> Option<u32> {
+        let left = (self.data.len() * 8).checked_sub(self.byte_position * 8 + self.bit_position)?;
+
+        if left < length as usize {
+            return None;
+        }
+
+        let byte = |i: usize| self.data.get(self.byte_position + i).copied().unwrap_or(0) as u32;
+
+        Some((byte(0) << 16) | (byte(1) << 8) | byte(2))
+    }
+
+    /// Advances past bits already seen through [`Self::peek_bits`]
+    #[inline]
+    fn skip_bits(&mut self, length: u8) {
+        let bits = self.bit_position + length as usize;
+
+        self.byte_position += bits / 8;
+        self.bit_position = bits % 8;
+    }
+
+    /// Moves to the start of the byte at `offset`
+    fn seek_to_byte(&mut self, offset: usize) -> core::result::Result<(), BitReaderError> {
+        if offset > self.data.len() {
+            return Err(BitReaderError::OffsetOutOfBounds);
+        }
+
+        self.byte_position = offset;
+        self.bit_position = 0;
+
+        Ok(())
+    }
+
+    fn align(&mut self) {
+        if self.byte_position < self.data.len() && self.bit_position != 0 {
+            self.bit_position = 0;
+            self.byte_position += 1;
+        }
+    }
+
+    /// Current position, to come back to with [`Self::restore`]
+    fn snapshot(&self) -> BitReaderSnapshot {
+        BitReaderSnapshot {
+            byte_position: self.byte_position,
+            bit_position: self.bit_position,
+        }
+    }
+
+    fn restore(&mut self, snapshot: BitReaderSnapshot) {
+        self.byte_position = snapshot.byte_position;
+        self.bit_position = snapshot.bit_position;
+    }
+}
+
+/// Position of a [`BitReader`] without the data it reads from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct BitReaderSnapshot {
+    byte_position: usize,
+    bit_position: usize,
+}
+
+/// Settings for [`JPEGHeader::with_options`]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DecodeOptions {
+    parallel: bool,
+    scale_factor: ScaleFactor,
+    dc_overflow: DCOverflow,
+    on_progress: Option<Callback<f32>>,
+    lenient: bool,
+    on_recoverable_error: Option<Callback<Error>>,
+    warning_handler: Option<Callback<Warning>>,
+}
+
+impl DecodeOptions {
+    /// Decode restart intervals on the rayon thread pool. Images without
+    /// restart markers are always decoded sequentially
+    #[must_use = "builder methods return the updated options"]
+    pub fn parallel(mut self, parallel: bool) -> Self {
+        self.parallel = parallel;
+        self
+    }
+
+    /// Shrink the decoded pixels. Smaller scales only run the IDCT on the
+    /// low frequency coefficients, down to just the DC for [`ScaleFactor::Eighth`]
+    #[must_use = "builder methods return the updated options"]
+    pub fn scale_factor(mut self, scale_factor: ScaleFactor) -> Self {
+        self.scale_factor = scale_factor;
+        self
+    }
+
+    /// What to do with a DC coefficient outside the range 8 bit samples
+    /// allow. Errors by default
+    #[must_use = "builder methods return the updated options"]
+    pub fn dc_overflow(mut self, dc_overflow: DCOverflow) -> Self {
+        self.dc_overflow = dc_overflow;
+        self
+    }
+
+    /// Called with the fraction of MCUs decoded each time another 5% of the
+    /// scan is done, ending with `1.0`. Only [`JPEGHeader::decode_pixels`]
+    /// and [`JPEGHeader::decode_pixels_as`] report, once per call. The check
+    /// of the scan while parsing and the other decodes don't. Images with
+    /// fewer than 20 MCUs skip steps. Parallel decodes call it from the
+    /// worker threads, so steps can arrive out of order
+    #[must_use = "builder methods return the updated options"]
+    pub fn on_progress(mut self, on_progress: impl Fn(f32) + Send + Sync + 'static) -> Self {
+        self.on_progress = Some(Callback(Arc::new(on_progress)));
+        self
+    }
+
+    /// Carry on parsing past errors [`Error::is_recoverable`] accepts instead
+    /// of failing. Off by default
+    #[must_use = "builder methods return the updated options"]
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Called with every error a [lenient](Self::lenient) parse carried on
+    /// past
+    #[must_use = "builder methods return the updated options"]
+    pub fn on_recoverable_error(
+        mut self,
+        on_recoverable_error: impl Fn(Error) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_recoverable_error = Some(Callback(Arc::new(on_recoverable_error)));
+        self
+    }
+
+    /// Called with every [`Warning`] found while parsing. Warnings are
+    /// dropped by default
+    #[must_use = "builder methods return the updated options"]
+    pub fn warning_handler(
+        mut self,
+        warning_handler: impl Fn(Warning) + Send + Sync + 'static,
+    ) -> Self {
+        self.warning_handler = Some(Callback(Arc::new(warning_handler)));
+        self
+    }
+
+    fn warn(&self, warning: Warning) {
+        if let Some(warning_handler) = &self.warning_handler {
+            (warning_handler.0)(warning);
+        }
+    }
+
+    /// Reports `error` and lets parsing continue when lenient and the error
+    /// can be recovered from, otherwise gives it back
+    fn recover(&self, error: Error) -> Result<()> {
+        if !self.lenient || !error.is_recoverable() {
+            return Err(error);
+        }
+
+        if let Some(on_recoverable_error) = &self.on_recoverable_error {
+            (on_recoverable_error.0)(error);
+        }
+
+        Ok(())
+    }
+}
+
+/// Callback stored in [`DecodeOptions`]. Options holding the same callback
+/// compare equal
+struct Callback<A>(Arc<dyn Fn(A) + Send + Sync>);
+
+impl Callback<f32> {
+    /// Reports the last 5% step reached when going from `before` to `after`
+    /// of `total` MCUs, if it is a new one
+    fn advance(&self, before: usize, after: usize, total: usize) {
+        let step = |done: usize| done * 20 / total;
+
+        if step(after) > step(before) {
+            (self.0)(step(after) as f32 / 20.0);
+        }
+    }
+}
+
+impl<A> Clone for Callback<A> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<A> std::fmt::Debug for Callback<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+impl<A> PartialEq for Callback<A> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<A> Eq for Callback<A> {}
+
+/// Handling of quantized DC coefficients outside -1024 - 1023, which only
+/// corrupt data produces
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DCOverflow {
+    /// Fail with [`HuffmanDecodingError::DCCoefficientOverflow`]
+    #[default]
+    Error,
+    /// Clamp the coefficient into range. Later predictions still use the
+    /// decoded value
+    Clamp,
+}
+
+/// Area of an image in pixels, for [`JPEGHeader::decode_partial`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Rect {
+    pub fn new(x: u16, y: u16, width: u16, height: u16) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn contains(&self, x: usize, y: usize) -> bool {
+        (self.x as usize..self.x as usize + self.width as usize).contains(&x)
+            && (self.y as usize..self.y as usize + self.height as usize).contains(&y)
+    }
+}
+
+/// Output size relative to the full image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScaleFactor {
+    #[default]
+    Full,
+    Half,
+    Quarter,
+    Eighth,
+}
+
+impl ScaleFactor {
+    /// Width and height of a decoded 8x8 block
+    fn block_size(self) -> usize {
+        match self {
+            Self::Full => 8,
+            Self::Half => 4,
+            Self::Quarter => 2,
+            Self::Eighth => 1,
+        }
+    }
+}
+
+/// Layout of the buffer returned by [`JPEGHeader::decode_pixels_as`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb8,
+    Rgba8,
+    Bgr8,
+    Bgra8,
+    /// The Y, Cb and Cr planes one after the other, without any color
+    /// conversion. The R, G and B planes for an RGB coded image
+    PlanarYcbcr,
+}
+
+/// File layout written by [`JPEGHeader::decode_to_writer`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Interleaved RGB with no header, like [`JPEGHeader::decode_pixels`]
+    Rgb8Raw,
+    /// Binary PPM (P6) holding RGB
+    Ppm,
+    /// Binary PGM (P5) holding the luma. Grayscale and YCbCr images give
+    /// their Y samples, RGB coded images are converted
+    Pgm,
+}
+
+/// Output of [`JPEGHeader::decode_with_report`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeReport {
+    /// Interleaved RGB
+    pub pixels: Vec<u8>,
+    /// How often each symbol was decoded with DC table n, indexed by symbol
+    pub dc_symbol_frequencies: [[u32; 256]; 4],
+    /// How often each symbol was decoded with AC table n, indexed by symbol
+    pub ac_symbol_frequencies: [[u32; 256]; 4],
+}
+
+/// A sign of a damaged scan found by [`JPEGHeader::detect_corruption`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptionHint {
+    pub mcu_index: usize,
+    pub reason: CorruptionReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionReason {
+    /// The quantized DC coefficient of a component changed by more than
+    /// [`CorruptionReason::MAX_DC_DELTA`] from the neighbouring block
+    DCJump { delta: i32 },
+    /// A quantized AC coefficient is outside -1023..=1023, more than an 8
+    /// bit baseline encoder can produce. Decoding already rejects AC codes
+    /// that long, so only lenient decodes or modified coefficients reach it
+    InvalidACRange { coeff: i32 },
+}
+
+impl CorruptionReason {
+    pub const MAX_DC_DELTA: i32 = 512;
+}
+
+/// The DC and AC Huffman tables of a header, see
+/// [`JPEGHeader::export_huffman_tables`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HuffmanTableSet {
+    pub dc: [HuffmanTable; 4],
+    pub ac: [HuffmanTable; 4],
+}
+
+impl HuffmanTableSet {
+    /// Writes a DHT segment for every set table, DC tables first, the same
+    /// way [`JPEGHeader::to_bytes`] does
+    pub fn write_dht_segments(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        for payload in self.dht_payloads() {
+            writer.write_all(&[0xFF, 0xC4])?;
+            writer.write_all(&(payload.len() as u16 + 2).to_be_bytes())?;
+            writer.write_all(&payload)?;
+        }
+
+        Ok(())
+    }
+
+    fn dht_payloads(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        let dc = self.dc.iter().map(|table| (0, table));
+        let ac = self.ac.iter().map(|table| (1, table));
+
+        dc.enumerate()
+            .chain(ac.enumerate())
+            .filter(|(_, (_, table))| table.is_set)
+            .map(|(id, (class, table))| table.dht_payload(class, id as u8))
+    }
+}
+
+/// Streams the decoded image as interleaved RGB rows through
+/// [`std::io::Read`]. Only one row of MCUs is decoded at a time, so the full
+/// pixel buffer never exists
+#[derive(Debug, Clone)]
+pub struct JPEGPixelReader {
+    header: JPEGHeader,
+    decoder: RowDecoder,
+    /// Pixels of the current row of MCUs
+    rows: Vec<u8>,
+    /// Bytes of `rows` already read
+    offset: usize,
+}
+
+impl JPEGPixelReader {
+    pub fn new(mut header: JPEGHeader) -> Self {
+        header.build_huffman_lookups();
+
+        Self {
+            header,
+            decoder: RowDecoder::default(),
+            rows: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    /// The header being decoded, to get the dimensions for example
+    #[must_use = "the reader is not modified"]
+    pub fn header(&self) -> &JPEGHeader {
+        &self.header
+    }
+
+    /// Decodes the next row of MCUs into `rows`. False once every row was read
+    fn decode_row(&mut self) -> Result<bool> {
+        self.offset = 0;
+
+        self.decoder.decode_row(&self.header, &mut self.rows, true)
+    }
+}
+
+/// A parsed header whose scan has not been Huffman decoded yet, as returned
+/// by [`JPEGHeader::new_lazy`]. Enough for the dimensions, the color space
+/// and the metadata. A broken scan is only found by [`Self::decode`]
+#[derive(Debug, Clone)]
+pub struct LazyJPEGHeader {
+    header: JPEGHeader,
+}
+
+impl LazyJPEGHeader {
+    /// The parsed segments, to get the dimensions for example
+    #[must_use = "the header is not modified"]
+    pub fn header(&self) -> &JPEGHeader {
+        &self.header
+    }
+
+    /// Huffman decodes the scan, which [`JPEGHeader::new`] does while parsing,
+    /// and returns the checked header
+    #[must_use = "decoding errors are only reported through the returned Result"]
+    pub fn decode(mut self) -> Result<JPEGHeader> {
+        self.header.check_scan()?;
+
+        Ok(self.header)
+    }
+}
+
+/// How far a row by row decode of the scan got, see [`JPEGPixelReader`]
+#[derive(Debug, Clone, Default)]
+struct RowDecoder {
+    /// Next row of MCUs to decode
+    mcu_row: usize,
+    /// Where the scan stopped after the last row
+    position: BitReaderSnapshot,
+    previous_dc: [i32; 3],
+    /// Samples of subsampled frames, which are decoded a row ahead since
+    /// upsampling reads the line below
+    planes: Option<SamplePlanes>,
+}
+
+impl RowDecoder {
+    /// Decodes the next row of MCUs into `rows` as interleaved RGB. Without
+    /// `to_rgb` YCbCr is kept as it is. False once every row was decoded.
+    /// The header's Huffman lookups have to be built first
+    fn decode_row(
+        &mut self,
+        header: &JPEGHeader,
+        rows: &mut Vec<u8>,
+        to_rgb: bool,
+    ) -> Result<bool> {
+        let layout = BlockLayout::new(header);
+
+        if layout.is_subsampled() {
+            return self.decode_subsampled_row(header, layout, rows, to_rgb);
+        }
+
+        let (width, height) = header.output_dimensions();
+        let block_size = header.options.scale_factor.block_size();
+        let mcu_width = (header.width as usize).div_ceil(8);
+
+        let top = self.mcu_row * block_size;
+
+        if top >= height {
+            return Ok(false);
+        }
+
+        let row_height = block_size.min(height - top);
+        let qtables = header.component_qtables();
+        let is_rgb = header.color_space() == ColorSpace::RGB;
+
+        let mut reader = BitReader::new(&header.huffman_data);
+        reader.restore(self.position);
+
+        rows.clear();
+        rows.resize(width * row_height * 3, 0);
+
+        let mut mcu = MCU::default();
+
+        for x in 0..mcu_width {
+            let index = self.mcu_row * mcu_width + x;
+            let group = std::slice::from_mut(&mut mcu);
+
+            header.decode_next_mcu(&mut reader, index, group, &mut self.previous_dc)?;
+
+            mcu.reconstruct(&qtables, block_size);
+
+            if to_rgb && !is_rgb {
+                mcu.ycbcr_to_rgb();
+            }
+
+            let window = Rect::new(0, 0, width as u16, row_height as u16);
+            mcu.write_to_buffer(x, 0, window, block_size, rows);
+        }
+
+        self.position = reader.snapshot();
+        self.mcu_row += 1;
+
+        Ok(true)
+    }
+
+    /// [`Self::decode_row`] for frames with subsampled components, which
+    /// goes through [`SamplePlanes`]
+    fn decode_subsampled_row(
+        &mut self,
+        header: &JPEGHeader,
+        layout: BlockLayout,
+        rows: &mut Vec<u8>,
+        to_rgb: bool,
+    ) -> Result<bool> {
+        let (width, height) = header.output_dimensions();
+        let block_size = header.options.scale_factor.block_size();
+        let mcu_height = layout.max_factors.1 as usize * block_size;
+
+        let top = self.mcu_row * mcu_height;
+
+        if top >= height {
+            return Ok(false);
+        }
+
+        let row_height = mcu_height.min(height - top);
+        let is_rgb = header.color_space() == ColorSpace::RGB;
+
+        let planes = match &mut self.planes {
+            Some(planes) => planes,
+            None => self.planes.insert(header.sample_planes()?),
+        };
+
+        let mut reader = BitReader::new(&header.huffman_data);
+        reader.restore(self.position);
+
+        let mut mcus = vec![MCU::default(); layout.mcus_x * layout.group];
+
+        while !planes.holds_line(top + row_height - 1) {
+            header.decode_mcu_row(
+                &mut reader,
+                planes.mcu_rows,
+                &mut mcus,
+                &mut self.previous_dc,
+            )?;
+            planes.push_mcu_row(&mut mcus);
+        }
+
+        rows.clear();
+        rows.resize(width * row_height * 3, 0);
+
+        for (y, line) in rows.chunks_exact_mut(width * 3).enumerate() {
+            planes.write_line(top + y, 0..width, to_rgb && !is_rgb, line);
+        }
+
+        planes.release(top + row_height);
+
+        self.position = reader.snapshot();
+        self.mcu_row += 1;
+
+        Ok(true)
+    }
+}
+
+impl Read for JPEGPixelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.offset == self.rows.len() {
+            let has_row = self
+                .decode_row()
+                .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+
+            if !has_row {
+                return Ok(0);
+            }
+        }
+
+        let available = &self.rows[self.offset..];
+        let length = available.len().min(buf.len());
+
+        buf[..length].copy_from_slice(&available[..length]);
+        self.offset += length;
+
+        Ok(length)
+    }
+}
+
+/// Puts together a [`JPEGHeader`] from its tables and components, for test
+/// fixtures for example. The scan codes every block as flat mid gray, so the
+/// result decodes and serializes like a parsed header
+#[derive(Debug, Clone, Default)]
+pub struct JPEGHeaderBuilder {
+    header: JPEGHeader,
+    components: Vec<ColorComponent>,
+    /// First invalid table passed in, returned by [`Self::build`]
+    error: Option<Error>,
+}
+
+impl JPEGHeaderBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use = "builder methods return the updated builder"]
+    pub fn with_qtable(mut self, id: usize, table: QTable) -> Self {
+        if let (Some(slot), Some(kind)) = (
+            self.header.qtables.get_mut(id),
+            QTableType::from_destination(id as u8),
+        ) {
+            *slot = QTable { kind, ..table };
+        }
+
+        self
+    }
+
+    /// `bits` is the number of codes of each length and `values` the symbols
+    /// in code order, like in a DHT segment. When the counts don't add up to
+    /// the number of values, or there are more than 162, [`Self::build`]
+    /// fails with [`DHTError::InvalidSymbolsLength`]
+    #[must_use = "builder methods return the updated builder"]
+    pub fn with_huffman_table_dc(mut self, id: usize, bits: [u8; 16], values: &[u8]) -> Self {
+        let table = self.checked_table(&bits, values);
+
+        if let (Some(slot), Some(table)) = (self.header.huffman_tables_dc.get_mut(id), table) {
+            *slot = table;
+        }
+
+        self
+    }
+
+    /// See [`Self::with_huffman_table_dc`]
+    #[must_use = "builder methods return the updated builder"]
+    pub fn with_huffman_table_ac(mut self, id: usize, bits: [u8; 16], values: &[u8]) -> Self {
+        let table = self.checked_table(&bits, values);
+
+        if let (Some(slot), Some(table)) = (self.header.huffman_tables_ac.get_mut(id), table) {
+            *slot = table;
+        }
+
+        self
+    }
+
+    /// The table, or `None` after keeping the error for [`Self::build`]
+    fn checked_table(&mut self, bits: &[u8; 16], values: &[u8]) -> Option<HuffmanTable> {
+        match HuffmanTable::checked(bits, values) {
+            Ok(table) => Some(table),
+            Err(error) => {
+                self.error.get_or_insert(Error::InvalidDHTMarker(error));
+                None
+            }
+        }
+    }
+
+    /// Adds a component to both the frame and the scan. `sampling` is the
+    /// horizontal and vertical sampling factor
+    #[must_use = "builder methods return the updated builder"]
+    pub fn with_component(
+        mut self,
+        id: u8,
+        sampling: (u8, u8),
+        qtable: u8,
+        huffman_table_dc_id: u8,
+        huffman_table_ac_id: u8,
+    ) -> Self {
+        self.components.push(ColorComponent {
+            id,
+            hfactor: sampling.0,
+            vfactor: sampling.1,
+            qtable,
+            huffman_table_ac_id,
+            huffman_table_dc_id,
+            is_used_sof: true,
+            is_used_sos: true,
+        });
+
+        self
+    }
+
+    #[must_use = "builder methods return the updated builder"]
+    pub fn with_dimensions(mut self, width: u16, height: u16) -> Self {
+        self.header.width = width;
+        self.header.height = height;
+
+        self
+    }
+
+    /// Checks the same things parsing does: the dimensions, 1 or 3
+    /// components with ids 1 - 3, and that every table a component uses is
+    /// set. Table ids out of range are never set
+    #[must_use = "the built header is only returned"]
+    pub fn build(self) -> Result<JPEGHeader> {
+        fn throw(error: SOF0MarkerError) -> Result<JPEGHeader> {
+            Err(Error::InvalidSOF0Marker(error))
+        }
+
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        let mut header = self.header;
+
+        if header.width == 0 || header.height == 0 {
+            return throw(SOF0MarkerError::ZeroDimensions);
+        }
+
+        if !matches!(self.components.len(), 1 | 3) {
+            return throw(SOF0MarkerError::InvalidComponentNumber);
+        }
+
+        for component in self.components {
+            if component.id == 0 || component.id as usize > header.components.len() {
+                return throw(SOF0MarkerError::InvalidComponentID);
+            }
+
+            if !(1..=4).contains(&component.hfactor) || !(1..=4).contains(&component.vfactor) {
+                return throw(SOF0MarkerError::InvalidSamplingFactor);
+            }
+
+            let slot = &mut header.components[component.id as usize - 1];
+
+            if slot.is_used_sof {
+                return throw(SOF0MarkerError::ComponentAlreadySet);
+            }
+
+            let is_qtable_set = header
+                .qtables
+                .get(component.qtable as usize)
+                .is_some_and(|table| table.is_set);
+
+            if !is_qtable_set {
+                return Err(Error::InvalidQTable);
+            }
+
+            let dc_table = header
+                .huffman_tables_dc
+                .get(component.huffman_table_dc_id as usize);
+            let ac_table = header
+                .huffman_tables_ac
+                .get(component.huffman_table_ac_id as usize);
+
+            for table in [dc_table, ac_table] {
+                // Gray blocks need the DC difference 0 and end of block,
+                // which are both symbol 0
+                match table {
+                    Some(table) if table.is_set => {
+                        if table.code_for_symbol(0).is_none() {
+                            return Err(HuffmanDecodingError::SymbolNotFound)?;
+                        }
+                    }
+                    _ => return Err(Error::HTableNotFound),
+                }
+            }
+
+            *slot = component;
+        }
+
+        header.is_sof_set = true;
+
+        let tables: Vec<(usize, HuffmanTable, HuffmanTable)> = header
+            .scan_components()
+            .into_iter()
+            .map(|j| {
+                let component = &header.components[j];
+
+                (
+                    j,
+                    header.huffman_tables_dc[component.huffman_table_dc_id as usize],
+                    header.huffman_tables_ac[component.huffman_table_ac_id as usize],
+                )
+            })
+            .collect();
+
+        let layout = BlockLayout::new(&header);
+
+        let mut writer = BitWriter::unstuffed();
+        let mut previous_dc = [0; 3];
+
+        for _ in 0..layout.mcu_count() {
+            for (j, dc_table, ac_table) in &tables {
+                for _ in 0..layout.blocks(*j) {
+                    Encoder::encode_block(
+                        &mut writer,
+                        &[0; 64],
+                        &mut previous_dc[*j],
+                        dc_table,
+                        ac_table,
+                    );
+                }
+            }
+        }
+
+        header.huffman_data = writer.finish();
+
+        Ok(header)
+    }
+}
+
+/// Transform applied by [`JPEGHeader::rotate_lossless`]. Rotations are
+/// clockwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Degrees90,
+    Degrees180,
+    Degrees270,
+    FlipHorizontal,
+    FlipVertical,
+}
+
+/// Color space of the components in a frame. JPEG doesn't record it
+/// directly, so it is inferred by [`JPEGHeader::color_space`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    Grayscale,
+    YCbCr,
+    RGB,
+    Unknown,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct JPEGHeader {
+    jfif: Option<APP0>,
+    adobe: Option<AdobeApp14>,
+    qtables: [QTable; 4],
+    restart_interval: u16,
+    zero_based_component_id: bool,
+    huffman_tables_dc: [HuffmanTable; 4],
+    huffman_tables_ac: [HuffmanTable; 4],
+    components: [ColorComponent; 3],
+    is_sof_set: bool,
+    /// Bits per sample from the frame header
+    precision: u8,
+    height: u16,
+    width: u16,
+    start_of_selection: u8,
+    end_of_selection: u8,
+    successive_approximation_high: u8,
+    successive_approximation_low: u8,
+    huffman_data: Vec<u8>,
+    /// Offsets into `huffman_data` where restart markers were removed
+    restart_offsets: Vec<usize>,
+    comments: Vec<String>,
+    xmp: Option<Vec<u8>>,
+    icc_profile: Option<Vec<u8>>,
+    /// (index, total, data) of each ICC APP2 segment, only while parsing
+    icc_chunks: Vec<(u8, u8, Vec<u8>)>,
+    options: DecodeOptions,
+}
+
+impl Default for JPEGHeader {
+    fn default() -> Self {
+        Self {
+            jfif: None,
+            adobe: None,
+            qtables: [QTable::default(); 4],
+            restart_interval: 0,
+            zero_based_component_id: false,
+            huffman_tables_dc: [HuffmanTable::default(); 4],
+            huffman_tables_ac: [HuffmanTable::default(); 4],
+            components: [ColorComponent::default(); 3],
+            is_sof_set: false,
+            precision: 8,
+            height: 0,
+            width: 0,
+            start_of_selection: 0,
+            end_of_selection: 63,
+            successive_approximation_low: 0,
+            successive_approximation_high: 0,
+            huffman_data: Vec::default(),
+            restart_offsets: Vec::default(),
+            comments: Vec::default(),
+            xmp: None,
+            icc_profile: None,
+            icc_chunks: Vec::default(),
+            options: DecodeOptions::default(),
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for JPEGHeader {
+    type Error = Error;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        Self::from_bytes(value)
+    }
+}
+
+impl TryFrom<Vec<u8>> for JPEGHeader {
+    type Error = Error;
+
+    fn try_from(value: Vec<u8>) -> Result<Self> {
+        Self::new(value)
+    }
+}
+
+impl JPEGHeader {
+    #[must_use = "parsing errors are only reported through the returned Result"]
+    pub fn new(stream: Vec<u8>) -> Result<JPEGHeader> {
+        Ok(Self::from_stream(
+            stream.into_iter(),
+            DecodeOptions::default(),
+        )?)
+    }
+
+    /// Same as [`Self::new`] but reads straight from the slice, so memory
+    /// mapped files are never copied into a `Vec`
+    #[must_use = "parsing errors are only reported through the returned Result"]
+    pub fn from_bytes(data: &[u8]) -> Result<JPEGHeader> {
+        Ok(Self::from_stream(
+            data.iter().copied(),
+            DecodeOptions::default(),
+        )?)
+    }
+
+    #[must_use = "parsing errors are only reported through the returned Result"]
+    pub fn with_options(data: &[u8], options: DecodeOptions) -> Result<JPEGHeader> {
+        Ok(Self::from_stream(data.iter().copied(), options)?)
+    }
+
+    /// Same as [`Self::with_options`] but the error says where in `data` it
+    /// was found
+    #[must_use = "parsing errors are only reported through the returned Result"]
+    pub fn parse_located(
+        data: &[u8],
+        options: DecodeOptions,
+    ) -> core::result::Result<JPEGHeader, LocatedError> {
+        Self::from_stream(data.iter().copied(), options)
+    }
+
+    /// Parses every segment but leaves the scan for
+    /// [`LazyJPEGHeader::decode`], for when only the metadata is needed
+    #[must_use = "parsing errors are only reported through the returned Result"]
+    pub fn new_lazy(data: &[u8]) -> Result<LazyJPEGHeader> {
+        let mut stream = ByteStream::new(data.iter().copied());
+        let header = Self::parse_headers(&mut stream, DecodeOptions::default())?;
+
+        Ok(LazyJPEGHeader { header })
+    }
+
+    /// Reads straight from a buffered reader, without loading the whole file
+    /// first. An I/O error is treated as the end of the data
+    #[must_use = "parsing errors are only reported through the returned Result"]
+    pub fn from_bufread<R: BufRead>(reader: R) -> Result<JPEGHeader> {
+        Ok(Self::from_stream(
+            BufReadBytes { reader },
+            DecodeOptions::default(),
+        )?)
+    }
+
+    /// Infers the color space the same way libjpeg-turbo does. JFIF implies
+    /// YCbCr, then the Adobe color transform is trusted and finally the
+    /// component ids are checked for 1, 2, 3 or 'R', 'G', 'B'
+    #[must_use = "the header is not modified"]
+    pub fn color_space(&self) -> ColorSpace {
+        let components: Vec<&ColorComponent> = self.active_components().collect();
+
+        if components.len() == 1 {
+            return ColorSpace::Grayscale;
+        }
+
+        if self.jfif.is_some() {
+            return ColorSpace::YCbCr;
+        }
+
+        match self.adobe.map(|adobe| adobe.color_transform) {
+            Some(0) => return ColorSpace::RGB,
+            Some(1) => return ColorSpace::YCbCr,
+            _ => {}
+        }
+
+        self.infer_color_space()
+    }
+
+    /// Infers the color space from the component ids alone, ignoring the
+    /// JFIF and Adobe segments. A single component is grayscale, 1, 2, 3 is
+    /// YCbCr and 'R', 'G', 'B' is RGB, as written by encoders that skip the
+    /// color conversion
+    #[must_use = "the header is not modified"]
+    pub fn infer_color_space(&self) -> ColorSpace {
+        let ids: Vec<u8> = self
+            .active_components()
+            .map(|component| component.id)
+            .collect();
+
+        match ids.as_slice() {
+            [_] => ColorSpace::Grayscale,
+            [1, 2, 3] => ColorSpace::YCbCr,
+            [b'R', b'G', b'B'] => ColorSpace::RGB,
+            _ => ColorSpace::Unknown,
+        }
+    }
+
+    /// Bits per sample from the frame header. Only 8 is supported, anything
+    /// else fails parsing with [`SOF0MarkerError::InvalidPrecision`]
+    #[must_use = "the header is not modified"]
+    pub fn precision(&self) -> u8 {
+        self.precision
+    }
+
+    /// Checks every component of the frame names a QTable that was set,
+    /// including components the scan leaves out. Parsing runs this before
+    /// any other component check
+    pub fn validate_qtable_completeness(&self) -> Result<()> {
+        self.components
+            .iter()
+            .filter(|component| component.is_used_sof)
+            .try_for_each(|component| component.validate_against_qtables(&self.qtables))
+    }
+
+    /// Whether a table an active component uses has 16 bit entries. Those
+    /// are meant for 12 bit samples. With 8 bit samples they are valid but
+    /// rare, so parsing warns about them. [`JPEGHeader::to_bytes`] keeps
+    /// them 16 bit
+    #[must_use = "the header is not modified"]
+    pub fn has_extended_qtables(&self) -> bool {
+        self.active_components().any(|component| {
+            self.qtables
+                .get(component.qtable as usize)
+                .is_some_and(|qtable| qtable.is_extended_mode)
+        })
+    }
+
+    /// The chroma subsampling as `(4, 4)` for 4:4:4, `(4, 2)` for 4:2:2 and
+    /// `(4, 0)` for 4:2:0, from how the largest sampling factors compare to
+    /// the chroma ones. `None` without chroma, when Cb and Cr differ or for
+    /// any other ratio
+    #[must_use = "the header is not modified"]
+    pub fn chroma_subsampling_ratio(&self) -> Option<(u8, u8)> {
+        let [_, cb, cr] = self.components;
+
+        if !cb.is_active()
+            || !cr.is_active()
+            || (cb.hfactor, cb.vfactor) != (cr.hfactor, cr.vfactor)
+        {
+            return None;
+        }
+
+        let max_hfactor = self.active_components().map(|c| c.hfactor).max()?;
+        let max_vfactor = self.active_components().map(|c| c.vfactor).max()?;
+
+        if cb.hfactor == 0
+            || cb.vfactor == 0
+            || !max_hfactor.is_multiple_of(cb.hfactor)
+            || !max_vfactor.is_multiple_of(cb.vfactor)
+        {
+            return None;
+        }
+
+        match (max_hfactor / cb.hfactor, max_vfactor / cb.vfactor) {
+            (1, 1) => Some((4, 4)),
+            (2, 1) => Some((4, 2)),
+            (2, 2) => Some((4, 0)),
+            _ => None,
+        }
+    }
+
+    /// Width and height of the decoded pixels, after the scale factor of the
+    /// [`DecodeOptions`]
+    #[must_use = "the header is not modified"]
+    pub fn output_dimensions(&self) -> (usize, usize) {
+        let scale = 8 / self.options.scale_factor.block_size();
+
+        (
+            (self.width as usize).div_ceil(scale),
+            (self.height as usize).div_ceil(scale),
+        )
+    }
+
+    /// Decodes the image into interleaved RGB
+    #[must_use = "the decoded pixels are only returned"]
+    pub fn decode_pixels(&mut self) -> Result<Vec<u8>> {
+        self.decode_pixels_as(PixelFormat::Rgb8)
+    }
+
+    #[must_use = "the decoded pixels are only returned"]
+    pub fn decode_pixels_as(&mut self, format: PixelFormat) -> Result<Vec<u8>> {
+        let on_progress = self.options.on_progress.clone();
+        let mcus = self.decode_huffman_reporting(on_progress.as_ref())?;
+
+        self.render(mcus, format)
+    }
+
+    /// Decodes the image into `writer` one row of MCUs at a time, so only
+    /// `width * 8` pixels are held at once instead of the whole image. The
+    /// PPM and PGM headers only need the dimensions and are written first.
+    /// A decoding error leaves the rows before it in `writer`
+    pub fn decode_to_writer<W: Write>(
+        &mut self,
+        mut writer: W,
+        format: OutputFormat,
+    ) -> Result<()> {
+        let io = |error: std::io::Error| Error::Io(error.kind());
+        let (width, height) = self.output_dimensions();
+
+        match format {
+            OutputFormat::Rgb8Raw => {}
+            OutputFormat::Ppm => write!(writer, "P6\n{width} {height}\n255\n").map_err(io)?,
+            OutputFormat::Pgm => write!(writer, "P5\n{width} {height}\n255\n").map_err(io)?,
+        }
+
+        self.build_huffman_lookups();
+
+        let is_gray = format == OutputFormat::Pgm;
+        let is_rgb = self.color_space() == ColorSpace::RGB;
+
+        let mut decoder = RowDecoder::default();
+        let mut rows = Vec::new();
+        let mut luma = Vec::new();
+
+        while decoder.decode_row(self, &mut rows, !is_gray)? {
+            if !is_gray {
+                writer.write_all(&rows).map_err(io)?;
+                continue;
+            }
+
+            luma.clear();
+            // Rows of YCbCr images were left unconverted, so the first
+            // channel is Y already
+            luma.extend(rows.chunks_exact(3).map(|pixel| {
+                if !is_rgb {
+                    return pixel[0];
+                }
+
+                let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(f32::from);
+                (0.299 * r + 0.587 * g + 0.114 * b).round() as u8
+            }));
+
+            writer.write_all(&luma).map_err(io)?;
+        }
+
+        writer.flush().map_err(io)
+    }
+
+    /// Decodes the pixels in `region` of the output image into interleaved
+    /// RGB. Each DC coefficient is coded as a difference to the one before,
+    /// so the scan is still Huffman decoded up to the last block the region
+    /// touches, but only those blocks are transformed and kept
+    #[must_use = "the decoded pixels are only returned"]
+    pub fn decode_partial(&mut self, region: Rect) -> Result<Vec<u8>> {
+        let (width, height) = self.output_dimensions();
+
+        if region.x as usize + region.width as usize > width
+            || region.y as usize + region.height as usize > height
+        {
+            return Err(Error::InvalidRegion);
+        }
+
+        let mut buffer = vec![0; region.width as usize * region.height as usize * 3];
+
+        if buffer.is_empty() {
+            return Ok(buffer);
+        }
+
+        let block_size = self.options.scale_factor.block_size();
+        let mcu_width = (self.width as usize).div_ceil(8);
+        let qtables = self.component_qtables();
+        let is_rgb = self.color_space() == ColorSpace::RGB;
+
+        self.build_huffman_lookups();
+
+        let mut reader = BitReader::new(&self.huffman_data);
+        let mut previous_dc = [0; 3];
+
+        let layout = BlockLayout::new(self);
+
+        // Upsampling needs the lines around the region, so whole lines of
+        // MCUs are transformed
+        if layout.is_subsampled() {
+            let mut planes = self.sample_planes()?;
+            let mut mcus = vec![MCU::default(); layout.mcus_x * layout.group];
+
+            let top = region.y as usize;
+            let bottom = top + region.height as usize - 1;
+            let first_row = planes.first_mcu_row(top);
+
+            while !planes.holds_line(bottom) {
+                self.decode_mcu_row(&mut reader, planes.mcu_rows, &mut mcus, &mut previous_dc)?;
+
+                match planes.mcu_rows < first_row {
+                    true => planes.skip_mcu_row(),
+                    false => planes.push_mcu_row(&mut mcus),
+                }
+            }
+
+            let columns = region.x as usize..region.x as usize + region.width as usize;
+
+            for (y, line) in buffer.chunks_exact_mut(columns.len() * 3).enumerate() {
+                planes.write_line(top + y, columns.clone(), !is_rgb, line);
+            }
+
+            return Ok(buffer);
+        }
+
+        let blocks = |start: u16, length: u16| {
+            start as usize / block_size..=(start as usize + length as usize - 1) / block_size
+        };
+        let columns = blocks(region.x, region.width);
+        let rows = blocks(region.y, region.height);
+        let last = rows.end() * mcu_width + columns.end();
+
+        let mut mcu = MCU::default();
+
+        for i in 0..=last {
+            let group = std::slice::from_mut(&mut mcu);
+            self.decode_next_mcu(&mut reader, i, group, &mut previous_dc)?;
+
+            let (mcu_x, mcu_y) = (i % mcu_width, i / mcu_width);
+
+            if !columns.contains(&mcu_x) || !rows.contains(&mcu_y) {
+                continue;
+            }
+
+            mcu.reconstruct(&qtables, block_size);
+
+            if !is_rgb {
+                mcu.ycbcr_to_rgb();
+            }
+
+            mcu.write_to_buffer(mcu_x, mcu_y, region, block_size, &mut buffer);
+        }
+
+        Ok(buffer)
+    }
+
+    /// The dequantized DC coefficient of every block, row by row, with one
+    /// plane per active component. Each value is 8 times the average of the
+    /// block's samples less 128, so the planes are the image at 1/8 scale,
+    /// and subsampled components at 1/8 of their effective resolution.
+    /// AC codes have no length prefix and still have to be read to find the
+    /// next DC, but no IDCT or color conversion is done
+    #[must_use = "the DC planes are only returned"]
+    pub fn decode_dc_plane(&mut self) -> Result<Vec<Vec<i32>>> {
+        let layout = BlockLayout::new(self);
+        let components = self.scan_components();
+        let qtables = self.component_qtables();
+
+        self.build_huffman_lookups();
+
+        let (max_hfactor, max_vfactor) = layout.max_factors;
+
+        // Blocks past the effective resolution only pad the last MCUs
+        let sizes: Vec<(usize, usize)> = components
+            .iter()
+            .map(|&j| {
+                let (width, height) = layout.components[j].effective_resolution(
+                    self.width,
+                    self.height,
+                    max_hfactor,
+                    max_vfactor,
+                );
+
+                ((width as usize).div_ceil(8), (height as usize).div_ceil(8))
+            })
+            .collect();
+
+        let mut planes: Vec<Vec<i32>> = sizes
+            .iter()
+            .map(|(width, height)| vec![0; width * height])
+            .collect();
+
+        let mut reader = BitReader::new(&self.huffman_data);
+        let mut previous_dc = [0; 3];
+        let mut mcu = vec![MCU::default(); layout.group];
+
+        for i in 0..layout.mcu_count() {
+            self.decode_next_mcu(&mut reader, i, &mut mcu, &mut previous_dc)?;
+
+            let (mcu_x, mcu_y) = (i % layout.mcus_x, i / layout.mcus_x);
+
+            for ((plane, &(width, height)), &j) in planes.iter_mut().zip(&sizes).zip(&components) {
+                let (h, v) = layout.factors(j);
+
+                for (b, block) in mcu[..layout.blocks(j)].iter().enumerate() {
+                    let x = mcu_x * h + b % h;
+                    let y = mcu_y * v + b / h;
+
+                    if x < width && y < height {
+                        plane[y * width + x] = block.channel(j)[0] * qtables[j][0] as i32;
+                    }
+                }
+            }
+        }
+
+        Ok(planes)
+    }
+
+    /// Average RGB color of every 8x8 block, row by row. Only the DC
+    /// coefficients are used, so no IDCT runs. Subsampled chroma gives the
+    /// average of the chroma block covering the 8x8 one
+    #[must_use = "the colors are only returned"]
+    pub fn block_colors(&mut self) -> Result<Vec<(u8, u8, u8)>> {
+        let qtables = self.component_qtables();
+        let is_rgb = self.color_space() == ColorSpace::RGB;
+
+        let layout = BlockLayout::new(self);
+        self.upsampling(&layout)?;
+
+        let (max_h, max_v) = layout.max_factors;
+        let (max_h, max_v) = (max_h as usize, max_v as usize);
+
+        let mcus = self.decode_huffman()?;
+
+        let blocks_x = self.width.div_ceil(8) as usize;
+        let blocks_y = self.height.div_ceil(8) as usize;
+
+        let colors = (0..blocks_x * blocks_y)
+            .map(|i| {
+                let (x, y) = (i % blocks_x, i / blocks_x);
+                let group = ((y / max_v) * layout.mcus_x + x / max_h) * layout.group;
+                let mut block = MCU::default();
+
+                for (j, qtable) in qtables.iter().enumerate() {
+                    let entry = group + layout.covering_block(j, x % max_h, y % max_v);
+                    block.component(j)[0] = mcus[entry].channel(j)[0] * qtable[0] as i32;
+                }
+
+                block.average_rgb(is_rgb)
+            })
+            .collect();
+
+        Ok(colors)
+    }
+
+    /// Looks for blocks that are unlikely in a valid image. A DC coefficient
+    /// is compared to the block to its left, or the one above for the first
+    /// block of a row, and each component gives at most one hint of each
+    /// kind per block. Photos rarely give any, but a hard edge between flat
+    /// blocks at a high quality can give a DC jump too
+    #[must_use = "the hints are only returned"]
+    pub fn detect_corruption(&mut self) -> Result<Vec<CorruptionHint>> {
+        let mcus = self.decode_huffman()?;
+
+        Ok(self.corruption_hints(&mcus))
+    }
+
+    fn corruption_hints(&self, mcus: &[MCU]) -> Vec<CorruptionHint> {
+        let layout = BlockLayout::new(self);
+        let mut hints = Vec::new();
+
+        // Subsampled components are compared within their own blocks
+        for j in self.scan_components() {
+            let (width, height) = layout.component_grid(j);
+
+            for i in 0..width * height {
+                let (x, y) = (i % width, i / width);
+                let entry = layout.entry(j, x, y);
+                let mcu_index = entry / layout.group;
+
+                let neighbour = match x {
+                    0 => y.checked_sub(1).map(|y| (0, y)),
+                    _ => Some((x - 1, y)),
+                };
+
+                if let Some((neighbour_x, neighbour_y)) = neighbour {
+                    let neighbour = layout.entry(j, neighbour_x, neighbour_y);
+                    let delta = mcus[entry].channel(j)[0] - mcus[neighbour].channel(j)[0];
+
+                    if delta.abs() > CorruptionReason::MAX_DC_DELTA {
+                        hints.push(CorruptionHint {
+                            mcu_index,
+                            reason: CorruptionReason::DCJump { delta },
+                        });
+                    }
+                }
+
+                let mut ac = mcus[entry].channel(j)[1..].iter().copied();
+
+                if let Some(coeff) = ac.find(|coeff| !(-1023..=1023).contains(coeff)) {
+                    hints.push(CorruptionHint {
+                        mcu_index,
+                        reason: CorruptionReason::InvalidACRange { coeff },
+                    });
+                }
+            }
+        }
+
+        // Stable, so the components of an MCU stay in order
+        hints.sort_by_key(|hint| hint.mcu_index);
+
+        hints
+    }
+
+    /// Decodes the image into interleaved RGB and counts how often every
+    /// Huffman symbol was decoded
+    #[must_use = "the decoded pixels are only returned"]
+    pub fn decode_with_report(&mut self) -> Result<DecodeReport> {
+        let mcus = self.decode_huffman()?;
+
+        let mut dc_symbol_frequencies = [[0; 256]; 4];
+        let mut ac_symbol_frequencies = [[0; 256]; 4];
+
+        for j in self.scan_components() {
+            let component = &self.components[j];
+            let (dc, ac) = symbol_frequency_histogram(
+                &mcus,
+                BlockLayout::new(self),
+                j,
+                self.restart_interval as usize,
+            );
+
+            let tables = [
+                (
+                    &mut dc_symbol_frequencies[component.huffman_table_dc_id as usize],
+                    dc,
+                ),
+                (
+                    &mut ac_symbol_frequencies[component.huffman_table_ac_id as usize],
+                    ac,
+                ),
+            ];
+
+            for (total, histogram) in tables {
+                for (total, count) in total.iter_mut().zip(histogram) {
+                    *total += count;
+                }
+            }
+        }
+
+        Ok(DecodeReport {
+            pixels: self.render(mcus, PixelFormat::Rgb8)?,
+            dc_symbol_frequencies,
+            ac_symbol_frequencies,
+        })
+    }
+
+    /// IJG quality the luminance table was made with, if it was made with the
+    /// IJG formula at all
+    #[must_use = "the header is not modified"]
+    pub fn quality_estimate(&self) -> Option<u8> {
+        let component = self.active_components().next()?;
+
+        self.qtables[component.qtable as usize].quality_estimate()
+    }
+
+    /// Replaces QTable `id` and requantizes the components using it. See
+    /// [`JPEGHeader::requantize`]
+    pub fn set_qtable(&mut self, id: usize, table: QTable) -> Result<()> {
+        if id >= self.qtables.len() {
+            return Err(Error::InvalidQTableId(id));
+        }
+
+        if !table.is_set {
+            return Err(Error::InvalidQTable);
+        }
+
+        let mut tables = self.qtables;
+        tables[id] = table;
+
+        self.requantize(&tables)
+    }
+
+    /// Swaps in new entropy coded data, as it appears in a file between the
+    /// SOS segment and EOI, for the scan. All the other segments are kept, so
+    /// the data has to be coded with the current Huffman and quantization
+    /// tables. Every block takes at least two bits, one for its DC symbol
+    /// and one for an end of block, and the data has to decode. On any error
+    /// the old scan is kept
+    pub fn replace_scan_data(&mut self, new_data: Vec<u8>) -> Result<()> {
+        let mut scan = JPEGHeader::default();
+        let mut stream = ByteStream::new(new_data.into_iter().chain([0xFF, Marker::HEX_EOI]));
+
+        Marker::scan(&mut stream, &mut scan)?;
+
+        let layout = BlockLayout::new(self);
+        let blocks: usize = self
+            .scan_components()
+            .iter()
+            .map(|&j| layout.blocks(j))
+            .sum();
+        let min_bits = 2 * layout.mcu_count() * blocks;
+
+        // Anything left means the data had its own EOI
+        if stream.nth(1).is_some()
+            || scan.huffman_data.is_empty()
+            || scan.huffman_data.len() * 8 < min_bits
+        {
+            return Err(Error::InvalidScanData);
+        }
+
+        let old_data = std::mem::replace(&mut self.huffman_data, scan.huffman_data);
+        let old_offsets = std::mem::replace(&mut self.restart_offsets, scan.restart_offsets);
+
+        if let Err(error) = self.decode_huffman() {
+            self.huffman_data = old_data;
+            self.restart_offsets = old_offsets;
+
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// Moves the scan over to `new_tables` without going through pixels.
+    /// Every coefficient is multiplied by its old step and divided by the new
+    /// one. Unset entries keep the current table. The scan is re-encoded with
+    /// the Annex K Huffman tables since the old ones may lack symbols the new
+    /// coefficients need
+    pub fn requantize(&mut self, new_tables: &[QTable; 4]) -> Result<()> {
+        let (tables, mut mcus) = self.requantized_mcus(new_tables)?;
+
+        self.qtables = tables;
+        self.encode_huffman(&mut mcus);
+
+        Ok(())
+    }
+
+    /// Merges `new_tables` into the current tables like [`Self::requantize`]
+    /// and gives back the merged tables with the requantized blocks
+    fn requantized_mcus(&mut self, new_tables: &[QTable; 4]) -> Result<([QTable; 4], Vec<MCU>)> {
+        let mut tables = self.qtables;
+
+        for (id, (table, new_table)) in tables.iter_mut().zip(new_tables).enumerate() {
+            if let (true, Some(kind)) = (new_table.is_set, QTableType::from_destination(id as u8)) {
+                *table = QTable { kind, ..*new_table };
+            }
+        }
+
+        let components = self.scan_components();
+
+        for j in &components {
+            let table = &tables[self.components[*j].qtable as usize];
+
+            if !table.is_set || table.table.contains(&0) {
+                return Err(Error::InvalidQTable);
+            }
+        }
+
+        let mut mcus = self.decode_huffman()?;
+
+        for mcu in &mut mcus {
+            for &j in &components {
+                let qtable = self.components[j].qtable as usize;
+                let old = &self.qtables[qtable].table;
+                let new = &tables[qtable].table;
+
+                for (coefficient, (old, new)) in
+                    mcu.component(j).iter_mut().zip(old.iter().zip(new))
+                {
+                    let value = (*coefficient * *old as i32) as f32 / *new as f32;
+
+                    // Keeps DC differences within 11 bits and AC values
+                    // within 10, as baseline requires
+                    *coefficient = (value.round() as i32).clamp(-1023, 1023);
+                }
+            }
+        }
+
+        Ok((tables, mcus))
+    }
+
+    /// See [`super::analyze::estimate_file_size_at_quality`]
+    pub(super) fn estimate_size_at_quality(&self, quality: u8) -> Result<usize> {
+        let luminance = QTable::from_quality(quality, QTableType::Luminance);
+        let chrominance = QTable::from_quality(quality, QTableType::Chrominance);
+
+        let components = self.scan_components();
+        let mut tables = [QTable::default(); 4];
+
+        for &j in &components {
+            tables[self.components[j].qtable as usize] =
+                if j == 0 { luminance } else { chrominance };
+        }
+
+        // Decoding builds the Huffman lookups, which needs a mutable header
+        let mut header = self.clone();
+        let (tables, mcus) = header.requantized_mcus(&tables)?;
+
+        let dc_tables = [
+            HuffmanTable::new(&DC_LUMINANCE_BITS, &DC_LUMINANCE_VALUES),
+            HuffmanTable::new(&DC_CHROMINANCE_BITS, &DC_CHROMINANCE_VALUES),
+        ];
+        let ac_tables = [
+            HuffmanTable::new(&AC_LUMINANCE_BITS, &AC_LUMINANCE_VALUES),
+            HuffmanTable::new(&AC_CHROMINANCE_BITS, &AC_CHROMINANCE_VALUES),
+        ];
+
+        // A symbol's code is followed by as many bits as the size in its low
+        // nibble
+        let bits = |table: &HuffmanTable, frequencies: &[u32; 256]| -> usize {
+            frequencies
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| **count != 0)
+                .map(|(symbol, count)| {
+                    let code_length = table
+                        .code_for_symbol(symbol as u8)
+                        .map_or(16, |(_, length)| length as usize);
+
+                    *count as usize * (code_length + (symbol & 0x0F))
+                })
+                .sum()
+        };
+
+        let mut scan_bits = 0;
+
+        for &j in &components {
+            let kind = (j != 0) as usize;
+            let (dc, ac) = symbol_frequency_histogram(
+                &mcus,
+                BlockLayout::new(self),
+                j,
+                self.restart_interval as usize,
+            );
+
+            scan_bits += bits(&dc_tables[kind], &dc) + bits(&ac_tables[kind], &ac);
+        }
+
+        // Entropy coded bytes are close to random, so about one in 256 is a
+        // 0xFF that gets stuffed
+        let scan = scan_bits.div_ceil(8);
+        let scan = scan + scan / 256 + 2 * self.restart_offsets.len();
+
+        // The segments around an empty scan, with the new tables and the
+        // Annex K Huffman tables a requantized file gets
+        header.qtables = tables;
+        header.encode_huffman(&mut []);
+
+        Ok(header.to_bytes()?.len() + scan)
+    }
+
+    /// Rotates or flips the image by moving the quantized DCT blocks around
+    /// and transforming their coefficients, so no quality is lost. Like
+    /// `jpegtran -trim`, partial MCUs on an edge that would end up on the
+    /// other side are dropped. The JFIF thumbnail is left as is
+    pub fn rotate_lossless(&mut self, rotation: Rotation) -> Result<()> {
+        // Which source axes get mirrored, then whether the result is
+        // transposed
+        let (mirror_x, mirror_y, transpose) = match rotation {
+            Rotation::Degrees90 => (false, true, true),
+            Rotation::Degrees180 => (true, true, false),
+            Rotation::Degrees270 => (true, false, true),
+            Rotation::FlipHorizontal => (true, false, false),
+            Rotation::FlipVertical => (false, true, false),
+        };
+
+        let layout = BlockLayout::new(self);
+        let mcu_width = 8 * layout.max_factors.0 as u16;
+        let mcu_height = 8 * layout.max_factors.1 as u16;
+
+        let mut width = self.width;
+        let mut height = self.height;
+
+        if mirror_x {
+            width -= width % mcu_width;
+        }
+
+        if mirror_y {
+            height -= height % mcu_height;
+        }
+
+        if width == 0 || height == 0 {
+            return Err(Error::ImageSmallerThanBlock);
+        }
+
+        let mcus = self.decode_huffman()?;
+        let components = self.scan_components();
+
+        let mcus_x = width.div_ceil(mcu_width) as usize;
+        let mcus_y = height.div_ceil(mcu_height) as usize;
+
+        if transpose {
+            (width, height) = (height, width);
+
+            for qtable in &mut self.qtables {
+                let table = qtable.table;
+                qtable.table = std::array::from_fn(|i| table[(i % 8) * 8 + i / 8]);
+            }
+
+            for component in &mut self.components {
+                (component.hfactor, component.vfactor) = (component.vfactor, component.hfactor);
+            }
+        }
+
+        self.width = width;
+        self.height = height;
+
+        let output = BlockLayout::new(self);
+        let mut rotated = vec![MCU::default(); output.mcu_count() * output.group];
+
+        for &j in &components {
+            let (hfactor, vfactor) = layout.factors(j);
+            let blocks_x = mcus_x * hfactor;
+            let blocks_y = mcus_y * vfactor;
+
+            for y in 0..blocks_y {
+                for x in 0..blocks_x {
+                    let source = mcus[layout.entry(j, x, y)].channel(j);
+
+                    let x = if mirror_x { blocks_x - 1 - x } else { x };
+                    let y = if mirror_y { blocks_y - 1 - y } else { y };
+                    let (x, y) = if transpose { (y, x) } else { (x, y) };
+
+                    let block = rotated[output.entry(j, x, y)].component(j);
+
+                    for (i, coefficient) in block.iter_mut().enumerate() {
+                        let (v, u) = if transpose {
+                            (i % 8, i / 8)
+                        } else {
+                            (i / 8, i % 8)
+                        };
+
+                        // Mirroring a block negates its odd frequencies
+                        let is_negated = (mirror_x && u % 2 == 1) ^ (mirror_y && v % 2 == 1);
+
+                        *coefficient = source[v * 8 + u];
+
+                        if is_negated {
+                            *coefficient = -*coefficient;
+                        }
+                    }
+                }
+            }
+        }
+
+        self.encode_huffman(&mut rotated);
+
+        Ok(())
+    }
+
+    /// Serializes the parsed segments and the scan back into a JPEG. Parsing
+    /// the result gives back an equal header. Segments that aren't stored,
+    /// like EXIF or unknown APPn segments, are dropped. Fails with
+    /// [`Error::SegmentTooLong`] when a segment can't hold its payload
+    #[must_use = "the serialized bytes are only returned"]
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![0xFF, Marker::HEX_SOI];
+
+        if let Some(jfif) = &self.jfif {
+            push_jfif_segment(&mut bytes, jfif)?;
+
+            if let Some(thumbnail) = &jfif.jpeg_thumbnail {
+                let mut payload = b"JFXX\0".to_vec();
+                payload.push(Marker::JFXX_JPEG_THUMBNAIL);
+                payload.extend_from_slice(thumbnail);
+
+                push_segment(&mut bytes, 0xE0, &payload)?;
+            }
+        }
+
+        if let Some(xmp) = &self.xmp {
+            let mut payload = Marker::XMP_IDENTIFIER.to_vec();
+            payload.extend_from_slice(xmp);
+
+            push_segment(&mut bytes, 0xE1, &payload)?;
+        }
+
+        if let Some(profile) = &self.icc_profile {
+            // What is left of a segment after the identifier, index and count
+            let chunks = profile.chunks(u16::MAX as usize - 2 - Marker::ICC_IDENTIFIER.len() - 2);
+            let total = chunks.len() as u8;
+
+            for (i, chunk) in chunks.enumerate() {
+                let mut payload = Marker::ICC_IDENTIFIER.to_vec();
+                payload.extend_from_slice(&[i as u8 + 1, total]);
+                payload.extend_from_slice(chunk);
+
+                push_segment(&mut bytes, 0xE2, &payload)?;
+            }
+        }
+
+        if let Some(adobe) = &self.adobe {
+            push_adobe_segment(&mut bytes, adobe)?;
+        }
+
+        for comment in &self.comments {
+            push_segment(&mut bytes, 0xFE, comment.as_bytes())?;
+        }
+
+        self.push_frame(&mut bytes)?;
+
+        Ok(bytes)
+    }
+
+    /// Same as [`JPEGHeader::to_bytes`] without the APPn and COM segments,
+    /// so only the tables, the frame and the scan are left. The scan is
+    /// copied as is. When the component ids alone would give another color
+    /// space, the JFIF segment without its thumbnails or the Adobe segment
+    /// that sets it is kept
+    #[must_use = "the stripped bytes are only returned"]
+    pub fn strip_metadata(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![0xFF, Marker::HEX_SOI];
+
+        if self.color_space() != self.infer_color_space() {
+            if let Some(jfif) = &self.jfif {
+                let jfif = APP0 {
+                    x_thumbnail: 0,
+                    y_thumbnail: 0,
+                    thumbnail_data: Vec::new(),
+                    jpeg_thumbnail: None,
+                    ..jfif.clone()
+                };
+
+                push_jfif_segment(&mut bytes, &jfif)?;
+            } else if let Some(adobe) = &self.adobe {
+                push_adobe_segment(&mut bytes, adobe)?;
+            }
+        }
+
+        self.push_frame(&mut bytes)?;
+
+        Ok(bytes)
+    }
+
+    /// Serializes the JPEG like [`JPEGHeader::to_bytes`] with a new COM
+    /// segment right after SOI. The comment can't have null bytes and has to
+    /// fit in the 65533 bytes a segment holds
+    #[must_use = "the serialized bytes are only returned"]
+    pub fn splice_comment(&self, comment: &str) -> Result<Vec<u8>> {
+        if comment.contains('\0') || comment.len() > u16::MAX as usize - 2 {
+            return Err(Error::InvalidComment);
+        }
+
+        let mut bytes = vec![0xFF, Marker::HEX_SOI];
+        push_segment(&mut bytes, 0xFE, comment.as_bytes())?;
+
+        // Everything after the SOI of the plain serialization
+        bytes.extend_from_slice(&self.to_bytes()?[2..]);
+
+        Ok(bytes)
+    }
+
+    /// Text of every COM segment in the order they appear
+    #[must_use = "the header is not modified"]
+    pub fn comments(&self) -> &[String] {
+        &self.comments
+    }
+
+    /// The XMP packet of the first XMP APP1 segment, without its identifier
+    #[must_use = "the header is not modified"]
+    pub fn xmp_bytes(&self) -> Option<&[u8]> {
+        self.xmp.as_deref()
+    }
+
+    /// The embedded ICC profile, with the chunks of its APP2 segments joined
+    /// in index order. `None` without one, or when chunks were missing or
+    /// numbered inconsistently
+    #[must_use = "the header is not modified"]
+    pub fn extract_icc_profile(&self) -> Option<&[u8]> {
+        self.icc_profile.as_deref()
+    }
+
+    /// Joins the ICC chunks read so far. Chunks may come in any order, but
+    /// all of them have to be there exactly once
+    fn assemble_icc_profile(&mut self) {
+        let mut chunks = std::mem::take(&mut self.icc_chunks);
+
+        let Some(&(_, total, _)) = chunks.first() else {
+            return;
+        };
+
+        chunks.sort_by_key(|(index, _, _)| *index);
+
+        let is_complete = chunks.len() == total as usize
+            && chunks
+                .iter()
+                .enumerate()
+                .all(|(i, (index, count, _))| *index as usize == i + 1 && *count == total);
+
+        if !is_complete {
+            self.options.warn(Warning::IncompleteICCProfile);
+            return;
+        }
+
+        self.icc_profile = Some(chunks.into_iter().flat_map(|(_, _, chunk)| chunk).collect());
+    }
+
+    /// The XMP packet as a string. `None` if it isn't valid UTF-8
+    #[cfg(feature = "xmp")]
+    #[must_use = "the header is not modified"]
+    pub fn xmp_document(&self) -> Option<String> {
+        String::from_utf8(self.xmp.clone()?).ok()
+    }
+
+    /// The 24-bit RGB thumbnail stored in the JFIF segment, if any
+    #[must_use = "the header is not modified"]
+    pub fn extract_thumbnail(&self) -> Option<Vec<u8>> {
+        let (data, _, _) = self.jfif.as_ref()?.thumbnail()?;
+
+        Some(data.to_vec())
+    }
+
+    /// The JPEG thumbnail stored in a JFIF extension segment, if any. `None`
+    /// if it doesn't parse
+    #[must_use = "the header is not modified"]
+    pub fn thumbnail_jpeg(&self) -> Option<JPEGHeader> {
+        self.jfif.as_ref()?.thumbnail_jpeg()
+    }
+
+    /// Width over height of a pixel, when the JFIF segment gives its
+    /// densities without units
+    #[must_use = "the header is not modified"]
+    pub fn pixel_aspect_ratio(&self) -> Option<f32> {
+        self.jfif.as_ref()?.pixel_aspect_ratio()
+    }
+
+    /// Horizontal and vertical dots per inch from the JFIF segment, if it
+    /// gives its densities in a unit
+    #[must_use = "the header is not modified"]
+    pub fn dpi(&self) -> Option<(f32, f32)> {
+        self.jfif.as_ref()?.dpi()
+    }
+
+    /// Printed width and height in millimetres, when the JFIF segment gives
+    /// its densities in a unit
+    #[must_use = "the header is not modified"]
+    pub fn physical_size_mm(&self) -> Option<(f32, f32)> {
+        self.jfif
+            .as_ref()?
+            .physical_size_mm(self.width, self.height)
+    }
+
+    /// A multi-line dump of everything parsed, for bug reports. Tables are
+    /// printed in full, quantization tables as 8x8 grids in natural order.
+    /// EXIF segments aren't parsed, so there is no orientation to report
+    #[must_use = "the header is not modified"]
+    pub fn summary(&self) -> String {
+        use std::fmt::Write;
+
+        const WIDTH: usize = 18;
+
+        let mut summary = String::new();
+        let mut line = |label: &str, value: String| {
+            let _ = writeln!(summary, "{:<WIDTH$}{}", format!("{label}:"), value);
+        };
+
+        line("Dimensions", format!("{}x{}", self.width, self.height));
+        line("Color space", format!("{:?}", self.color_space()));
+        line("Restart interval", self.restart_interval.to_string());
+        line("Scan data", format!("{} bytes", self.huffman_data.len()));
+
+        if let Some(jfif) = &self.jfif {
+            line(
+                "JFIF version",
+                format!("{}.{:02}", jfif.major_version, jfif.minor_version),
+            );
+            line(
+                "Density",
+                format!(
+                    "{}x{} {}",
+                    jfif.x_density,
+                    jfif.y_density,
+                    <&str>::from(jfif.units)
+                ),
+            );
+        }
+
+        let _ = writeln!(summary, "Components:");
+        let _ = writeln!(
+            summary,
+            "  {:>4} {:>8} {:>6} {:>3} {:>3}",
+            "id", "sampling", "qtable", "dc", "ac"
+        );
+
+        for component in self.active_components() {
+            let _ = writeln!(
+                summary,
+                "  {:>4} {:>8} {:>6} {:>3} {:>3}",
+                component.id,
+                format!("{}x{}", component.hfactor, component.vfactor),
+                component.qtable,
+                component.huffman_table_dc_id,
+                component.huffman_table_ac_id
+            );
+        }
+
+        for (id, qtable) in self.qtables.iter().enumerate().filter(|(_, qt)| qt.is_set) {
+            let _ = writeln!(summary, "Quantization table {id}:");
+
+            for row in qtable.table.chunks_exact(8) {
+                let row: Vec<String> = row.iter().map(|value| format!("{value:>5}")).collect();
+                let _ = writeln!(summary, "  {}", row.concat());
+            }
+        }
+
+        let _ = writeln!(summary, "Huffman tables:");
+
+        let tables = [
+            ("DC", &self.huffman_tables_dc),
+            ("AC", &self.huffman_tables_ac),
+        ];
+
+        for (class, tables) in tables {
+            for (id, table) in tables.iter().enumerate().filter(|(_, table)| table.is_set) {
+                let _ = writeln!(summary, "  {class} {id}: {:>3} symbols", table.offsets[16]);
+            }
+        }
+
+        summary
+    }
+
+    /// `(id, table)` of every quantization table a DQT segment or the
+    /// builder set, whether or not a component uses it
+    pub fn quantization_tables(&self) -> impl Iterator<Item = (usize, &QTable)> {
+        self.qtables
+            .iter()
+            .enumerate()
+            .filter(|(_, table)| table.is_set)
+    }
+
+    /// `(id, table)` of every DC Huffman table that was set
+    pub fn huffman_tables_dc(&self) -> impl Iterator<Item = (usize, &HuffmanTable)> {
+        self.huffman_tables_dc
+            .iter()
+            .enumerate()
+            .filter(|(_, table)| table.is_set)
+    }
+
+    /// `(id, table)` of every AC Huffman table that was set
+    pub fn huffman_tables_ac(&self) -> impl Iterator<Item = (usize, &HuffmanTable)> {
+        self.huffman_tables_ac
+            .iter()
+            .enumerate()
+            .filter(|(_, table)| table.is_set)
+    }
+
+    /// Copies of the DC and AC Huffman tables. Codes are generated when the
+    /// scan is decoded, which parsing already does
+    #[must_use = "the header is not modified"]
+    pub fn export_huffman_tables(&self) -> HuffmanTableSet {
+        HuffmanTableSet {
+            dc: self.huffman_tables_dc,
+            ac: self.huffman_tables_ac,
+        }
+    }
+
+    /// Copies of all the metadata parsed from the file in one place
+    #[must_use = "the header is not modified"]
+    pub fn metadata(&self) -> ImageMetadata {
+        let jfif = self.jfif.as_ref().map(|jfif| JfifMetadata {
+            version: (jfif.major_version, jfif.minor_version),
+            dpi: jfif.dpi(),
+            pixel_aspect_ratio: jfif.pixel_aspect_ratio(),
+            thumbnail: jfif
+                .thumbnail()
+                .map(|(data, width, height)| (data.to_vec(), width, height)),
+            jpeg_thumbnail: jfif.jpeg_thumbnail.clone(),
+        });
+
+        ImageMetadata {
+            width: self.width,
+            height: self.height,
+            color_space: self.color_space(),
+            quality_estimate: self.quality_estimate(),
+            jfif,
+            adobe_color_transform: self.adobe.map(|adobe| adobe.color_transform),
+            comments: self.comments.clone(),
+            xmp: self.xmp.clone(),
+        }
+    }
+
+    /// Decodes the image, shrinks it to fit in `max_size` x `max_size` while
+    /// keeping the aspect ratio and encodes the result. Images that already
+    /// fit keep their size
+    #[must_use = "the encoded thumbnail is only returned"]
+    pub fn generate_thumbnail(&mut self, max_size: u32, quality: u8) -> Result<Vec<u8>> {
+        let pixels = self.decode_pixels()?;
+        let (width, height) = self.output_dimensions();
+
+        let max_size = max_size.max(1) as usize;
+        let largest = width.max(height);
+
+        let (thumb_width, thumb_height) = if largest <= max_size {
+            (width, height)
+        } else {
+            (
+                (width * max_size).div_ceil(largest).max(1),
+                (height * max_size).div_ceil(largest).max(1),
+            )
+        };
+
+        let thumbnail = area_average(&pixels, width, height, thumb_width, thumb_height);
+
+        let mut bytes = Vec::new();
+
+        Encoder::new(quality)
+            .encode(
+                &thumbnail,
+                thumb_width as u16,
+                thumb_height as u16,
+                3,
+                &mut bytes,
+            )
+            .expect("Writing to a Vec can't fail");
+
+        Ok(bytes)
+    }
+
+    fn from_stream(
+        stream: impl Iterator<Item = u8>,
+        options: DecodeOptions,
+    ) -> core::result::Result<JPEGHeader, LocatedError> {
+        let mut stream = ByteStream::new(stream);
+
+        Self::parse_stream(&mut stream, options)
+            .map_err(|error| LocatedError::new(error, stream.offset()))
+    }
+
+    fn parse_stream<I>(stream: &mut ByteStream<I>, options: DecodeOptions) -> Result<JPEGHeader>
+    where
+        I: Iterator<Item = u8>,
+    {
+        let mut jpeg_header = Self::parse_headers(stream, options)?;
+
+        jpeg_header.check_scan()?;
+
+        Ok(jpeg_header)
+    }
+
+    /// Everything [`Self::parse_stream`] does short of decoding the scan
+    fn parse_headers<I>(stream: &mut ByteStream<I>, options: DecodeOptions) -> Result<JPEGHeader>
+    where
+        I: Iterator<Item = u8>,
+    {
+        let mut has_soi = false;
+        let mut has_sof = false;
+        let mut has_qtable = false;
+        let mut has_htable = false;
+        let mut has_sos = false;
+
+        // Advance until SOI
+        while let Some(byte) = stream.next() {
+            if byte == 0xFF && Some(Marker::HEX_SOI) == stream.next() {
+                has_soi = true;
+                break;
+            }
+        }
+
+        if !has_soi {
+            return Err(Error::StartOfImageNotFound);
+        }
+
+        if stream.peek().is_none() {
+            return Err(Error::NoData);
+        }
+
+        let mut jpeg_header = JPEGHeader {
+            options,
+            ..Default::default()
+        };
+
+        // Advance until next marker
+        while let Some(byte) = stream.next() {
+            if byte == 0xFF {
+                if stream.peek().is_some() {
+                    let outcome = match Marker::read(stream, &mut jpeg_header) {
+                        Ok(outcome) => outcome,
+                        Err(error) => {
+                            jpeg_header.options.recover(error)?;
+                            continue;
+                        }
+                    };
+
+                    match outcome {
+                        DecodingOutcome::StartOfFrame => {
+                            has_sof = true;
+                        }
+                        DecodingOutcome::QTableSet => {
+                            has_qtable = true;
+                        }
+                        DecodingOutcome::HuffmanTable => {
+                            has_htable = true;
+                        }
+                        DecodingOutcome::StartOfScan => {
+                            has_sos = true;
+                            break;
+                        }
+                        DecodingOutcome::EndOfImage => {
+                            // Abbreviated streams that only define tables end
+                            // before any frame. A frame without its scan was
+                            // cut short
+                            jpeg_header.options.recover(if has_sof {
+                                Error::EndOfImageBeforeSOS
+                            } else {
+                                Error::EarlyEndOfImage
+                            })?;
+                        }
+                        DecodingOutcome::None => {}
+                    };
+                } else {
+                    // The stream ended on a 0xFF
+                    return Err(Error::PrematureEnd);
+                }
+            }
+        }
+
+        if !has_sof {
+            return Err(Error::StartOfFrameNotFound);
+        }
+        if !has_qtable {
+            return Err(Error::QTableNotFound);
+        }
+
+        if !has_htable {
+            return Err(Error::HTableNotFound);
+        }
+
+        if !has_sos {
+            return Err(Error::SOSNotFound);
+        }
+
+        jpeg_header.assemble_icc_profile();
+
+        Marker::scan(stream, &mut jpeg_header)?;
+
+        // Last validations. Tables come first, so a component the scan
+        // leaves out still has its table checked
+        jpeg_header.validate_qtable_completeness()?;
+
+        for component in jpeg_header.components.iter() {
+            if (component.is_used_sos && !component.is_used_sof)
+                || (component.is_used_sof && !component.is_used_sos)
+            {
+                return Err(Error::InvalidColorComponent);
+            }
+
+            match jpeg_header
+                .huffman_tables_dc
+                .get(component.huffman_table_dc_id as usize)
+            {
+                Some(htable) => {
+                    if !htable.is_set {
+                        return Err(Error::InvalidColorComponent);
+                    }
+                }
+                None => return Err(Error::InvalidColorComponent),
+            }
+
+            match jpeg_header
+                .huffman_tables_ac
+                .get(component.huffman_table_ac_id as usize)
+            {
+                Some(htable) if !htable.is_set => return Err(Error::InvalidColorComponent),
+                None => return Err(Error::InvalidColorComponent),
+                _ => {}
+            }
+        }
+
+        // Per image rather than per component, so it is reported once
+        if jpeg_header.has_extended_qtables() && jpeg_header.precision == 8 {
+            jpeg_header
+                .options
+                .warn(Warning::ExtendedQTablesWithBaselinePrecision);
+        }
+
+        if jpeg_header.color_space() == ColorSpace::Unknown {
+            jpeg_header.options.warn(Warning::UnknownColorSpace);
+        }
+
+        Ok(jpeg_header)
+    }
+
+    /// Decodes the scan once while parsing to find errors early
+    fn check_scan(&mut self) -> Result<()> {
+        let mut mcus = Vec::with_capacity(self.block_count());
+
+        self.decode_huffman_into(mcus.spare_capacity_mut(), None)
+    }
+
+    fn decode_huffman(&mut self) -> Result<Vec<MCU>> {
+        self.decode_huffman_reporting(None)
+    }
+
+    /// [`Self::decode_huffman`] reporting to `on_progress`
+    fn decode_huffman_reporting(
+        &mut self,
+        on_progress: Option<&Callback<f32>>,
+    ) -> Result<Vec<MCU>> {
+        let count = self.block_count();
+
+        // Left uninitialized, the decode writes every block anyway
+        let mut mcus = Vec::with_capacity(count);
+        self.decode_huffman_into(&mut mcus.spare_capacity_mut()[..count], on_progress)?;
+
+        // SAFETY: decode_huffman_into initialized all `count` MCUs
+        unsafe { mcus.set_len(count) };
+
+        Ok(mcus)
+    }
+
+    /// [`MCU`]s a decode of the scan fills, see [`BlockLayout`]
+    fn block_count(&self) -> usize {
+        let layout = BlockLayout::new(self);
+        layout.mcu_count() * layout.group
+    }
+
+    /// How each component of `layout` is stretched to the size of the
+    /// image. Fails with [`Error::UnsupportedSubsampling`] when a
+    /// component's factors don't divide the largest ones
+    fn upsampling(&self, layout: &BlockLayout) -> Result<[SamplingMode; 3]> {
+        let (max_hfactor, max_vfactor) = layout.max_factors;
+        let mut upsampling = [SamplingMode::None; 3];
+
+        for (j, component) in layout.components.iter().enumerate() {
+            let unsupported = Error::UnsupportedSubsampling {
+                component_id: self.components[j].id,
+                hfactor: self.components[j].hfactor,
+                vfactor: self.components[j].vfactor,
+            };
+
+            upsampling[j] = component
+                .upsampling(max_hfactor, max_vfactor)
+                .ok_or(unsupported)?;
+        }
+
+        Ok(upsampling)
+    }
+
+    /// Empty [`SamplePlanes`] at the scale of the [`DecodeOptions`], see
+    /// [`Self::upsampling`] for the errors
+    fn sample_planes(&self) -> Result<SamplePlanes> {
+        let layout = BlockLayout::new(self);
+        let (max_hfactor, max_vfactor) = layout.max_factors;
+        let block_size = self.options.scale_factor.block_size();
+
+        let sizes = layout.components.map(|component| {
+            let (width, height) =
+                component.effective_resolution(self.width, self.height, max_hfactor, max_vfactor);
+            let scale = 8 / block_size;
+
+            (
+                (width as usize).div_ceil(scale),
+                (height as usize).div_ceil(scale),
+            )
+        });
+
+        Ok(SamplePlanes {
+            layout,
+            upsampling: self.upsampling(&layout)?,
+            qtables: self.component_qtables(),
+            block_size,
+            sizes,
+            samples: Default::default(),
+            tops: [0; 3],
+            mcu_rows: 0,
+        })
+    }
+
+    /// Decodes line `row` of MCUs into `mcus`, which holds
+    /// [`BlockLayout::mcus_x`] groups, in a sequential pass over the scan
+    fn decode_mcu_row(
+        &self,
+        reader: &mut BitReader,
+        row: usize,
+        mcus: &mut [MCU],
+        previous_dc: &mut [i32; 3],
+    ) -> Result<()> {
+        let layout = BlockLayout::new(self);
+
+        for (x, group) in mcus.chunks_exact_mut(layout.group).enumerate() {
+            self.decode_next_mcu(reader, row * layout.mcus_x + x, group, previous_dc)?;
+        }
+
+        Ok(())
+    }
+
+    /// Decodes the scan into `mcus`, laid out as [`BlockLayout`] describes.
+    /// Every block is written whole when this returns `Ok`, so `mcus` can be
+    /// uninitialized or a reused buffer
+    fn decode_huffman_into(
+        &mut self,
+        mcus: &mut [MaybeUninit<MCU>],
+        on_progress: Option<&Callback<f32>>,
+    ) -> Result<()> {
+        self.build_huffman_lookups();
+
+        if cfg!(debug_assertions) {
+            let mut tables = self.huffman_tables_dc.iter().chain(&self.huffman_tables_ac);
+
+            if tables.any(|table| table.is_set && !table.is_canonical()) {
+                return Err(Error::InvalidDHTMarker(DHTError::NonCanonicalTable));
+            }
+        }
+
+        let restart_interval = self.restart_interval as usize;
+        let group = BlockLayout::new(self).group;
+
+        let total = mcus.len() / group;
+
+        // Every interval needs its marker to know where it starts
+        if self.options.parallel
+            && restart_interval != 0
+            && self.restart_offsets.len() + 1 >= total.div_ceil(restart_interval)
+        {
+            debug!("Decoding {total} MCUs in parallel, {restart_interval} per interval");
+
+            let done = AtomicUsize::new(0);
+
+            mcus.par_chunks_mut(restart_interval * group)
+                .enumerate()
+                .try_for_each(|(interval, chunk)| -> Result<()> {
+                    let start = match interval.checked_sub(1) {
+                        Some(marker) => self.restart_offsets[marker],
+                        None => 0,
+                    };
+
+                    trace!("Restart interval {interval} starts at byte {start}");
+
+                    let mut bit_reader = BitReader::new(&self.huffman_data[start..]);
+                    let mut previous_dc = [0; 3];
+                    let mut mcu = vec![MCU::default(); group];
+
+                    for (i, slots) in chunk.chunks_exact_mut(group).enumerate() {
+                        let index = interval * restart_interval + i;
+                        self.decode_mcu(&mut bit_reader, index, &mut mcu, &mut previous_dc)?;
+                        write_group(slots, &mcu);
+                    }
+
+                    if let Some(on_progress) = on_progress {
+                        let decoded = chunk.len() / group;
+                        let before = done.fetch_add(decoded, Ordering::Relaxed);
+                        on_progress.advance(before, before + decoded, total);
+                    }
+
+                    Ok(())
+                })?;
+
+            debug!("Decoded {total} MCUs");
+
+            return Ok(());
+        }
+
+        debug!("Decoding {total} MCUs");
+
+        let mut bit_reader = BitReader::new(&self.huffman_data);
+
+        let mut previous_dc = [0; 3];
+        let mut mcu = vec![MCU::default(); group];
+
+        for (i, slots) in mcus.chunks_exact_mut(group).enumerate() {
+            self.decode_next_mcu(&mut bit_reader, i, &mut mcu, &mut previous_dc)?;
+            write_group(slots, &mcu);
+
+            if let Some(on_progress) = on_progress {
+                on_progress.advance(i, i + 1, total);
+            }
+        }
+
+        debug!("Decoded {total} MCUs");
+
+        Ok(())
+    }
+
+    fn build_huffman_lookups(&mut self) {
+        let dc = self.huffman_tables_dc.iter_mut().map(|table| ("DC", table));
+        let ac = self.huffman_tables_ac.iter_mut().map(|table| ("AC", table));
+
+        for (id, (class, table)) in dc.enumerate().chain(ac.enumerate()) {
+            if !table.is_set {
+                continue;
+            }
+
+            table.generate_codes();
+            table.build_lookup_table();
+
+            debug!(
+                "Generated {} codes for {class} Huffman table {id}",
+                table.offsets[16]
+            );
+        }
+    }
+
+    /// Decodes MCU `index` of a sequential pass over the scan into the
+    /// [`BlockLayout::group`] MCUs of `mcu`, moving to the next restart
+    /// interval when one starts
+    fn decode_next_mcu(
+        &self,
+        reader: &mut BitReader,
+        index: usize,
+        mcu: &mut [MCU],
+        previous_dc: &mut [i32; 3],
+    ) -> Result<()> {
+        let restart_interval = self.restart_interval as usize;
+
+        // Markers sit between intervals, the first one starts at 0 anyway
+        if restart_interval != 0 && index > 0 && index.is_multiple_of(restart_interval) {
+            trace!(
+                "Restart interval {} starts at MCU {index}",
+                index / restart_interval
+            );
+
+            *previous_dc = [0; 3];
+
+            // The nth interval starts where the (n - 1)th marker was
+            match (index / restart_interval)
+                .checked_sub(1)
+                .and_then(|marker| self.restart_offsets.get(marker))
+            {
+                Some(offset) => reader.seek_to_byte(*offset)?,
+                None => reader.align(),
+            }
+        }
+
+        self.decode_mcu(reader, index, mcu, previous_dc)
+    }
+
+    /// Appends DQT, SOF0, DHT, DRI, SOS, the scan and EOI
+    fn push_frame(&self, bytes: &mut Vec<u8>) -> Result<()> {
+        for (id, qtable) in self.qtables.iter().enumerate() {
+            if !qtable.is_set {
+                continue;
+            }
+
+            let mut payload = vec![((qtable.is_extended_mode as u8) << 4) | id as u8];
+
+            for index in ZIGZAG {
+                let value = qtable.table[index as usize];
+
+                if qtable.is_extended_mode {
+                    payload.extend_from_slice(&value.to_be_bytes());
+                } else {
+                    payload.push(value as u8);
+                }
+            }
+
+            push_segment(bytes, 0xDB, &payload)?;
+        }
+
+        // Ids were shifted up by one when the file used zero based ids
+        let id_shift = self.zero_based_component_id as u8;
+
+        let mut sof = vec![self.precision];
+        sof.extend_from_slice(&self.height.to_be_bytes());
+        sof.extend_from_slice(&self.width.to_be_bytes());
+        sof.push(self.active_component_count() as u8);
+
+        for component in self.active_components() {
+            sof.extend_from_slice(&[
+                component.id - id_shift,
+                (component.hfactor << 4) | component.vfactor,
+                component.qtable,
+            ]);
+        }
+
+        push_segment(bytes, 0xC0, &sof)?;
+
+        for payload in self.export_huffman_tables().dht_payloads() {
+            push_segment(bytes, 0xC4, &payload)?;
+        }
+
+        if self.restart_interval != 0 {
+            push_segment(bytes, 0xDD, &self.restart_interval.to_be_bytes())?;
+        }
+
+        let mut sos = vec![self.active_component_count() as u8];
+
+        for component in self.active_components() {
+            sos.extend_from_slice(&[
+                component.id - id_shift,
+                (component.huffman_table_dc_id << 4) | component.huffman_table_ac_id,
+            ]);
+        }
+
+        sos.extend_from_slice(&[
+            self.start_of_selection,
+            self.end_of_selection,
+            (self.successive_approximation_high << 4) | self.successive_approximation_low,
+        ]);
+
+        push_segment(bytes, 0xDA, &sos)?;
+
+        // Put back the byte stuffing and the restart markers
+        let mut restart_offsets = self.restart_offsets.iter().peekable();
+        let mut marker = 0;
+
+        for (i, byte) in self.huffman_data.iter().enumerate() {
+            while restart_offsets.next_if(|offset| **offset == i).is_some() {
+                bytes.extend_from_slice(&[0xFF, 0xD0 + marker]);
+                marker = (marker + 1) % 8;
+            }
+
+            bytes.push(*byte);
+
+            if *byte == 0xFF {
+                bytes.push(0x00);
+            }
+        }
+
+        for _ in restart_offsets {
+            bytes.extend_from_slice(&[0xFF, 0xD0 + marker]);
+            marker = (marker + 1) % 8;
+        }
+
+        bytes.extend_from_slice(&[0xFF, Marker::HEX_EOI]);
+
+        Ok(())
+    }
+
+    /// Turns quantized coefficients into pixels of the given format
+    fn render(&self, mut mcus: Vec<MCU>, format: PixelFormat) -> Result<Vec<u8>> {
+        let (width, height) = self.output_dimensions();
+        let mcu_width = (self.width as usize).div_ceil(8);
+        let block_size = self.options.scale_factor.block_size();
+
+        let qtables = self.component_qtables();
+        let is_rgb = self.color_space() == ColorSpace::RGB;
+        let to_rgb = format != PixelFormat::PlanarYcbcr && !is_rgb;
+
+        // Interleaved RGB, or YCbCr for the planar format. RGB coded images
+        // are left as they are
+        let mut buffer = vec![0; width * height * 3];
+
+        let layout = BlockLayout::new(self);
+
+        if layout.is_subsampled() {
+            let mut planes = self.sample_planes()?;
+
+            for row in mcus.chunks_exact_mut(layout.mcus_x * layout.group) {
+                planes.push_mcu_row(row);
+            }
+
+            for (y, line) in buffer.chunks_exact_mut(width * 3).enumerate() {
+                planes.write_line(y, 0..width, to_rgb, line);
+            }
+        } else {
+            for (i, mcu) in mcus.iter_mut().enumerate() {
+                mcu.reconstruct(&qtables, block_size);
+
+                if to_rgb {
+                    mcu.ycbcr_to_rgb();
+                }
+
+                mcu.write_to_buffer(
+                    i % mcu_width,
+                    i / mcu_width,
+                    Rect::new(0, 0, width as u16, height as u16),
+                    block_size,
+                    &mut buffer,
+                );
+            }
+        }
+
+        let pixels = buffer.chunks_exact(3);
+
+        let output = match format {
+            PixelFormat::Rgb8 => buffer,
+            PixelFormat::Rgba8 => pixels.flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+            PixelFormat::Bgr8 => pixels.flat_map(|p| [p[2], p[1], p[0]]).collect(),
+            PixelFormat::Bgra8 => pixels.flat_map(|p| [p[2], p[1], p[0], 255]).collect(),
+            PixelFormat::PlanarYcbcr => (0..3)
+                .flat_map(|plane| buffer[plane..].iter().step_by(3).copied())
+                .collect(),
+        };
+
+        Ok(output)
+    }
+
+    /// Table each component is dequantized with
+    fn component_qtables(&self) -> [[u16; 64]; 3] {
+        self.components
+            .map(|component| self.qtables[component.qtable as usize].table)
+    }
+
+    /// Replaces the scan with `mcus`, which hold quantized coefficients. Uses
+    /// the Annex K Huffman tables and keeps the restart interval
+    fn encode_huffman(&mut self, mcus: &mut [MCU]) {
+        let components = self.scan_components();
+
+        let dc_luminance = HuffmanTable::new(&DC_LUMINANCE_BITS, &DC_LUMINANCE_VALUES);
+        let ac_luminance = HuffmanTable::new(&AC_LUMINANCE_BITS, &AC_LUMINANCE_VALUES);
+        let dc_chrominance = HuffmanTable::new(&DC_CHROMINANCE_BITS, &DC_CHROMINANCE_VALUES);
+        let ac_chrominance = HuffmanTable::new(&AC_CHROMINANCE_BITS, &AC_CHROMINANCE_VALUES);
+
+        let restart_interval = self.restart_interval as usize;
+        let layout = BlockLayout::new(self);
+
+        let mut writer = BitWriter::unstuffed();
+        let mut restart_offsets = Vec::new();
+        let mut previous_dc = [0; 3];
+
+        for (i, mcu) in mcus.chunks_exact_mut(layout.group).enumerate() {
+            if restart_interval != 0 && i != 0 && i % restart_interval == 0 {
+                writer.align();
+                restart_offsets.push(writer.len());
+                previous_dc = [0; 3];
+            }
+
+            for &j in &components {
+                let (dc_table, ac_table) = if j == 0 {
+                    (&dc_luminance, &ac_luminance)
+                } else {
+                    (&dc_chrominance, &ac_chrominance)
+                };
+
+                for block in &mut mcu[..layout.blocks(j)] {
+                    let block = block.component(j);
+                    let zigzag = ZIGZAG.map(|index| block[index as usize]);
+
+                    Encoder::encode_block(
+                        &mut writer,
+                        &zigzag,
+                        &mut previous_dc[j],
+                        dc_table,
+                        ac_table,
+                    );
+                }
+            }
+        }
+
+        self.huffman_data = writer.finish();
+        self.restart_offsets = restart_offsets;
+
+        self.huffman_tables_dc = [HuffmanTable::default(); 4];
+        self.huffman_tables_ac = [HuffmanTable::default(); 4];
+        self.huffman_tables_dc[0] = HuffmanTable::new(&DC_LUMINANCE_BITS, &DC_LUMINANCE_VALUES);
+        self.huffman_tables_ac[0] = HuffmanTable::new(&AC_LUMINANCE_BITS, &AC_LUMINANCE_VALUES);
+
+        if components.iter().any(|j| *j != 0) {
+            self.huffman_tables_dc[1] =
+                HuffmanTable::new(&DC_CHROMINANCE_BITS, &DC_CHROMINANCE_VALUES);
+            self.huffman_tables_ac[1] =
+                HuffmanTable::new(&AC_CHROMINANCE_BITS, &AC_CHROMINANCE_VALUES);
+        }
+
+        for (j, component) in self.components.iter_mut().enumerate() {
+            let id = (j != 0) as u8;
+            component.huffman_table_dc_id = id;
+            component.huffman_table_ac_id = id;
+        }
+    }
+
+    /// Components in both the frame and the scan. Grayscale images only
+    /// use the first slot
+    fn active_components(&self) -> impl Iterator<Item = &ColorComponent> {
+        self.components
+            .iter()
+            .filter(|component| component.is_active())
+    }
+
+    fn active_component_count(&self) -> usize {
+        self.active_components().count()
+    }
+
+    /// Indices of the active components
+    fn scan_components(&self) -> Vec<usize> {
+        (0..self.components.len())
+            .filter(|j| self.components[*j].is_active())
+            .collect()
+    }
+
+    /// Decodes the blocks of MCU `index` into `mcu`, one group of
+    /// [`BlockLayout`]
+    fn decode_mcu(
+        &self,
+        reader: &mut BitReader,
+        index: usize,
+        mcu: &mut [MCU],
+        previous_dc: &mut [i32; 3],
+    ) -> Result<()> {
+        let layout = BlockLayout::new(self);
+
+        for (j, component) in self.components.iter().enumerate() {
+            let blocks = match component.is_active() {
+                true => layout.blocks(j),
+                false => 0,
+            };
+
+            // Left over from an earlier MCU when the group is reused
+            for block in &mut mcu[blocks..] {
+                *block.component(j) = [0; 64];
+            }
+
+            for block in &mut mcu[..blocks] {
+                Self::decode_mcus(
+                    reader,
+                    index,
+                    j,
+                    block.component(j),
+                    &mut previous_dc[j],
+                    &self.huffman_tables_dc[component.huffman_table_dc_id as usize],
+                    &self.huffman_tables_ac[component.huffman_table_ac_id as usize],
+                )?;
+
+                // The prediction for the next block keeps the decoded value
+                let dc = &mut block.component(j)[0];
+
+                if !(-1024..=1023).contains(dc) {
+                    match self.options.dc_overflow {
+                        DCOverflow::Clamp => *dc = (*dc).clamp(-1024, 1023),
+                        DCOverflow::Error => {
+                            return Err(HuffmanDecodingError::DCCoefficientOverflow {
+                                mcu: index,
+                                value: *dc,
+                            })?
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn decode_mcus(
+        reader: &mut BitReader,
+        mcu_index: usize,
+        component_index: usize,
+        component: &mut [i32; 64],
+        previous_dc: &mut i32,
+        dc_table: &HuffmanTable,
+        ac_table: &HuffmanTable,
+    ) -> Result<()> {
+        let truncated = HuffmanDecodingError::UnexpectedEndOfMCU {
+            mcu_index,
+            component: component_index,
+        };
+
+        // Running out of bits means the data ended inside this MCU
+        let locate = |error: Error| match error {
+            Error::HuffmanDecode(HuffmanDecodingError::ReadPastLength) => Error::from(truncated),
+            error => error,
+        };
+
+        let length = Self::get_next_symbol(reader, dc_table).map_err(locate)?;
+
+        // DC cannot be more than 11
+        if length > 11 {
+            return Err(HuffmanDecodingError::InvalidDCCoefficientLength)?;
+        }
+
+        let mut dc_coeff = reader.read_length(length).ok_or(truncated)? as i32;
+
+        if length != 0 && dc_coeff < (1 << (length - 1)) {
+            dc_coeff -= (1 << length) - 1;
+        }
+
+        component[0] = dc_coeff + *previous_dc;
+        *previous_dc = component[0];
+
+        // AC now
+        let mut i = 1;
+
+        while i < 64 {
+            let symbol = Self::get_next_symbol(reader, ac_table).map_err(locate)?;
+
+            // 0x00 means fill the remaining with 0
+            if symbol == 0x00 {
+                for index in &ZIGZAG[i..] {
+                    component[*index as usize] = 0;
+                }
+
+                return Ok(());
+            }
+
+            let mut skip_zeros = symbol >> 4;
+            let coeff_len = symbol & 0x0F;
+
+            if symbol == 0xF0 {
+                skip_zeros = 16;
+            }
+
+            if (i + skip_zeros as usize) >= 64 {
+                trace!(
+                    "{skip_zeros} zeros from coefficient {i} run past the block, size {coeff_len}"
+                );
+                return Err(HuffmanDecodingError::ZerosExceedMCULength)?;
+            }
+
+            for _ in 0..skip_zeros {
+                component[ZIGZAG[i] as usize] = 0;
+                i += 1;
+            }
+
+            // Invalid for AC
+            if coeff_len > 10 {
+                return Err(HuffmanDecodingError::InvalidACCoefficientLength)?;
+            }
+
+            if coeff_len != 0 {
+                let mut ac_coeff = reader.read_length(coeff_len).ok_or(truncated)? as i32;
+
+                if ac_coeff < (1 << (coeff_len - 1)) {
+                    ac_coeff -= (1 << coeff_len) - 1;
+                }
+
+                component[ZIGZAG[i] as usize] = ac_coeff;
+                i += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn get_next_symbol(reader: &mut BitReader, table: &HuffmanTable) -> Result<u8> {
+        if table.is_degenerate() {
+            return Self::get_only_symbol(reader, table);
+        }
+
+        Self::search_symbol(reader, table)
+    }
+
+    /// A table with a single symbol still gives it a code in the stream, so
+    /// the code is read and checked without going through the lookup
+    fn get_only_symbol(reader: &mut BitReader, table: &HuffmanTable) -> Result<u8> {
+        let Some(length) = (1..=16).find(|&length| table.offsets[length] == 1) else {
+            // An empty table matches nothing
+            Err(HuffmanDecodingError::SymbolNotFound)?
+        };
+
+        let code = reader
+            .read_length(length as u8)
+            .ok_or(HuffmanDecodingError::ReadPastLength)?;
+
+        if code != table.codes[0] {
+            Err(HuffmanDecodingError::SymbolNotFound)?
+        }
+
+        Ok(table.symbols[0])
+    }
+
+    // Without the hint the fast path is not inlined into decode_mcus and ends
+    // up slower than the plain loop
+    #[inline]
+    fn search_symbol(reader: &mut BitReader, table: &HuffmanTable) -> Result<u8> {
+        // Keeps the error exits out of the way of the lookup
+        #[cold]
+        fn throw(error: HuffmanDecodingError) -> Result<u8> {
+            Err(error)?
+        }
+
+        let mut code = 0;
+        let mut start = 0;
+
+        // Fast path. Misses for long codes and near the end of the data
+        if let Some(prefix) = reader.peek_bits(LOOKUP_BITS) {
+            let (symbol, length) = table.lookup[prefix as usize];
+
+            if length != 0 {
+                reader.skip_bits(length);
+                return Ok(symbol);
+            }
+
+            // The code is longer than the lookup covers so continue from there
+            reader.skip_bits(LOOKUP_BITS);
+            code = prefix;
+            start = LOOKUP_BITS as usize;
+        }
+
+        for i in start..16 {
+            let Some(bit) = reader.read_bit() else {
+                return throw(HuffmanDecodingError::ReadPastLength);
+            };
+
+            code = (code << 1) | bit;
+
+            for j in table.offsets[i]..table.offsets[i + 1] {
+                if code == table.codes[j as usize] {
+                    return Ok(table.symbols[j as usize]);
+                }
+            }
+        }
+
+        throw(HuffmanDecodingError::SymbolNotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::encode::Encoder;
+    use crate::jpeg::idct::naive_idct_2d;
+    use proptest::prelude::*;
+
+    fn scan(data: &[u8]) -> JPEGHeader {
+        let mut jpeg = JPEGHeader::default();
+        let mut stream = ByteStream::new(data.iter().copied());
+
+        Marker::scan(&mut stream, &mut jpeg).unwrap();
+
+        jpeg
+    }
+
+    #[test]
+    fn restart_markers_are_not_scan_data() {
+        let jpeg = scan(&[0x12, 0xFF, 0xD3, 0x34, 0xFF, 0xD9]);
+
+        assert_eq!(jpeg.huffman_data, [0x12, 0x34]);
+        assert_eq!(jpeg.restart_offsets, [1]);
+    }
+
+    #[test]
+    fn stuffed_bytes_keep_the_ff() {
+        let jpeg = scan(&[0xAB, 0xFF, 0x00, 0xCD, 0xFF, 0xD9]);
+
+        assert_eq!(jpeg.huffman_data, [0xAB, 0xFF, 0xCD]);
+        assert!(jpeg.restart_offsets.is_empty());
+    }
+
+    #[test]
+    fn markers_inside_the_scan_are_rejected() {
+        let mut jpeg = JPEGHeader::default();
+        let mut stream = ByteStream::new([0x12, 0xFF, 0xC4, 0x34, 0xFF, 0xD9].into_iter());
+
+        assert_eq!(
+            Marker::scan(&mut stream, &mut jpeg),
+            Err(Error::InvalidScanData)
+        );
+
+        let mut jpeg = JPEGHeader::default();
+        jpeg.options.lenient = true;
+        let mut stream = ByteStream::new([0x12, 0xFF, 0xC4, 0x34, 0xFF, 0xD9].into_iter());
+
+        assert_eq!(Marker::scan(&mut stream, &mut jpeg), Ok(()));
+        assert_eq!(jpeg.huffman_data, [0x12]);
+    }
+
+    #[test]
+    fn fill_bytes_before_the_eoi_are_skipped() {
+        let jpeg = scan(&[0x12, 0xFF, 0xFF, 0xD9]);
+
+        assert_eq!(jpeg.huffman_data, [0x12]);
+    }
+
+    #[test]
+    fn sampling_modes() {
+        let mode = |hfactor, vfactor| {
+            ColorComponent {
+                hfactor,
+                vfactor,
+                ..Default::default()
+            }
+            .sampling_mode()
+        };
+
+        assert_eq!(mode(1, 1), SamplingMode::None);
+        assert_eq!(mode(2, 1), SamplingMode::Horizontal);
+        assert_eq!(mode(1, 2), SamplingMode::Vertical);
+        assert_eq!(mode(2, 2), SamplingMode::Both);
+        assert_eq!(mode(4, 1), SamplingMode::NonStandard(4, 1));
+        assert_eq!(mode(0, 0), SamplingMode::NonStandard(0, 0));
+    }
+
+    #[test]
+    fn upsampling_follows_the_ratio_to_the_largest_factors() {
+        let upsampling = |hfactor, vfactor, max_hfactor, max_vfactor| {
+            ColorComponent {
+                hfactor,
+                vfactor,
+                ..Default::default()
+            }
+            .upsampling(max_hfactor, max_vfactor)
+        };
+
+        assert_eq!(upsampling(2, 2, 2, 2), Some(SamplingMode::None));
+        assert_eq!(upsampling(1, 1, 2, 1), Some(SamplingMode::Horizontal));
+        assert_eq!(upsampling(1, 1, 1, 2), Some(SamplingMode::Vertical));
+        assert_eq!(upsampling(1, 1, 2, 2), Some(SamplingMode::Both));
+        assert_eq!(upsampling(2, 1, 4, 2), Some(SamplingMode::Both));
+        assert_eq!(
+            upsampling(1, 1, 4, 1),
+            Some(SamplingMode::NonStandard(4, 1))
+        );
+        assert_eq!(upsampling(2, 1, 3, 1), None);
+    }
+
+    #[test]
+    fn chroma_is_upsampled_by_its_sampling_mode() {
+        // A 4x2 image with one line of two chroma samples, 10 and 50
+        let line = |hfactor, vfactor| {
+            let component = |id, hfactor, vfactor| ColorComponent {
+                id,
+                hfactor,
+                vfactor,
+                is_used_sof: true,
+                is_used_sos: true,
+                ..Default::default()
+            };
+
+            let header = JPEGHeader {
+                width: 4,
+                height: 2,
+                components: [
+                    component(1, hfactor, vfactor),
+                    component(2, 1, 1),
+                    component(3, 1, 1),
+                ],
+                ..Default::default()
+            };
+
+            let mut planes = header.sample_planes().unwrap();
+            let stride = planes.stride(1);
+
+            planes.samples[1] = vec![0; stride * planes.mcu_lines(1)];
+            planes.samples[1][..2].copy_from_slice(&[10, 50]);
+
+            (0..4)
+                .map(|x| planes.upsampled(1, x, 0))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(line(2, 1), [10, 20, 40, 50]);
+        assert_eq!(line(2, 2), [10, 20, 40, 50]);
+        assert_eq!(line(4, 1), [10, 10, 10, 10]);
+    }
+
+    #[test]
+    fn effective_resolutions() {
+        let resolution = |hfactor, vfactor, width, height| {
+            ColorComponent {
+                hfactor,
+                vfactor,
+                ..Default::default()
+            }
+            .effective_resolution(width, height, 2, 2)
+        };
+
+        // 4:2:0
+        assert_eq!(resolution(2, 2, 1920, 1080), (1920, 1080));
+        assert_eq!(resolution(1, 1, 1920, 1080), (960, 540));
+        assert_eq!(resolution(1, 1, 1921, 1), (961, 1));
+
+        let component = ColorComponent {
+            hfactor: 1,
+            vfactor: 1,
+            ..Default::default()
+        };
+
+        // 4:4:4
+        assert_eq!(
+            component.effective_resolution(1920, 1080, 1, 1),
+            (1920, 1080)
+        );
+        // 4:2:2
+        assert_eq!(
+            component.effective_resolution(1920, 1080, 2, 1),
+            (960, 1080)
+        );
+    }
+
+    #[test]
+    fn out_of_range_ac_coefficients_are_hinted() {
+        let solid = include_bytes!("../../tests/fixtures/solid_8x8.jpg");
+        let mut jpeg = JPEGHeader::from_bytes(solid).unwrap();
+
+        let mut mcus = jpeg.decode_huffman().unwrap();
+        assert!(jpeg.corruption_hints(&mcus).is_empty());
+
+        mcus[0].g[5] = -1024;
+        mcus[0].g[6] = 2000;
+
+        assert_eq!(
+            jpeg.corruption_hints(&mcus),
+            [CorruptionHint {
+                mcu_index: 0,
+                reason: CorruptionReason::InvalidACRange { coeff: -1024 },
+            }]
+        );
+    }
+
+    #[test]
+    fn dc_overflow_errors_or_clamps() {
+        let solid = include_bytes!("../../tests/fixtures/solid_8x8.jpg");
+        let mut jpeg = JPEGHeader::from_bytes(solid).unwrap();
+
+        let mut mcus = jpeg.decode_huffman().unwrap();
+        mcus[0].r[0] = 1500;
+        jpeg.encode_huffman(&mut mcus);
+
+        let data = jpeg.to_bytes().unwrap();
+
+        assert_eq!(
+            JPEGHeader::from_bytes(&data),
+            Err(Error::HuffmanDecode(
+                HuffmanDecodingError::DCCoefficientOverflow {
+                    mcu: 0,
+                    value: 1500
+                }
+            ))
+        );
+
+        let options = DecodeOptions::default().dc_overflow(DCOverflow::Clamp);
+        let mut clamped = JPEGHeader::with_options(&data, options).unwrap();
+
+        assert_eq!(clamped.decode_huffman().unwrap()[0].r[0], 1023);
+    }
+
+    #[test]
+    fn jfif_densities() {
+        let jfif = |units, x_density, y_density| APP0 {
+            units,
+            x_density,
+            y_density,
+            ..Default::default()
+        };
+
+        let no_unit = jfif(JfifUnit::NoUnit, 2, 1);
+        assert_eq!(no_unit.pixel_aspect_ratio(), Some(2.0));
+        assert_eq!(no_unit.dpi(), None);
+
+        assert_eq!(jfif(JfifUnit::NoUnit, 1, 0).pixel_aspect_ratio(), None);
+
+        let per_inch = jfif(JfifUnit::PerInch, 72, 96);
+        assert_eq!(per_inch.pixel_aspect_ratio(), None);
+        assert_eq!(per_inch.dpi(), Some((72.0, 96.0)));
+
+        let per_centi = jfif(JfifUnit::PerCenti, 100, 0);
+        assert_eq!(per_centi.pixel_aspect_ratio(), None);
+        assert_eq!(per_centi.dpi(), Some((254.0, 0.0)));
+    }
+
+    #[test]
+    fn encoded_symbols_decode_back() {
+        let table = HuffmanTable::new(&AC_LUMINANCE_BITS, &AC_LUMINANCE_VALUES);
+
+        for symbol in 0..=u8::MAX {
+            let Some((code, length)) = table.code_for_symbol(symbol) else {
+                assert!(!AC_LUMINANCE_VALUES.contains(&symbol));
+                continue;
+            };
+
+            // The code at the top of the stream, padded with ones like a scan
+            let bits = (code << (32 - length)) | (u32::MAX >> length);
+            let data = bits.to_be_bytes();
+            let mut reader = BitReader::new(&data);
+
+            assert_eq!(JPEGHeader::get_next_symbol(&mut reader, &table), Ok(symbol));
+
+            let position = reader.snapshot();
+            assert_eq!(
+                position.byte_position * 8 + position.bit_position,
+                length as usize
+            );
+        }
+    }
+
+    #[test]
+    fn oversubscribed_tables_are_not_canonical() {
+        assert!(HuffmanTable::new(&DC_LUMINANCE_BITS, &DC_LUMINANCE_VALUES).is_canonical());
+        assert!(HuffmanTable::new(&AC_LUMINANCE_BITS, &AC_LUMINANCE_VALUES).is_canonical());
+
+        // Three 1-bit codes
+        let mut bits = [0; 16];
+        bits[0] = 3;
+        let table = HuffmanTable::new(&bits, &[0, 1, 2]);
+        assert!(!table.is_canonical());
+
+        let solid = include_bytes!("../../tests/fixtures/solid_8x8.jpg");
+        let mut jpeg = JPEGHeader::from_bytes(solid).unwrap();
+        jpeg.huffman_tables_dc[0] = table;
+
+        if cfg!(debug_assertions) {
+            assert_eq!(
+                jpeg.decode_huffman().unwrap_err(),
+                Error::InvalidDHTMarker(DHTError::NonCanonicalTable)
+            );
+        }
+    }
+
+    #[test]
+    fn quantizing_rounds_halves_away_from_zero() {
+        let mut mcu = MCU::default();
+        mcu.g[..4].copy_from_slice(&[3, -3, 5, -5]);
+
+        let mut table = [1; 64];
+        table[..4].copy_from_slice(&[2, 2, 10, 10]);
+
+        mcu.quantize(&QTable::new(table), 1);
+
+        assert_eq!(mcu.g[..4], [2, -2, 1, -1]);
+    }
+
+    #[test]
+    fn quality_tables_estimate_their_quality() {
+        for quality in 1..=100 {
+            for kind in [QTableType::Luminance, QTableType::Chrominance] {
+                let table = QTable::from_quality(quality, kind);
+                let estimate = table.quality_estimate().unwrap();
+
+                // Low qualities clamp to the same tables, any of them fits
+                assert_eq!(QTable::from_quality(estimate, kind).table, table.table);
+                assert!(estimate.abs_diff(quality) <= 2, "{kind:?} {quality}");
+            }
+        }
+
+        let other = QTable::from_quality(75, QTableType::Other);
+        assert_eq!(
+            other.table,
+            QTable::from_quality(75, QTableType::Luminance).table
+        );
+        assert_eq!(other.quality_estimate(), None);
+    }
+
+    #[test]
+    fn physical_sizes() {
+        let jfif = |units, density| APP0 {
+            units,
+            x_density: density,
+            y_density: density,
+            ..Default::default()
+        };
+
+        let (width, height) = jfif(JfifUnit::PerInch, 300)
+            .physical_size_mm(3000, 2000)
+            .unwrap();
+        assert!((width - 254.0).abs() < 0.1 && (height - 169.3).abs() < 0.1);
+
+        let (width, height) = jfif(JfifUnit::PerCenti, 100)
+            .physical_size_mm(3000, 2000)
+            .unwrap();
+        assert!((width - 300.0).abs() < 0.1 && (height - 200.0).abs() < 0.1);
+
+        assert_eq!(jfif(JfifUnit::NoUnit, 1).physical_size_mm(3000, 2000), None);
+        assert_eq!(
+            jfif(JfifUnit::PerInch, 0).physical_size_mm(3000, 2000),
+            None
+        );
+
+        let units = [JfifUnit::NoUnit, JfifUnit::PerInch, JfifUnit::PerCenti];
+        assert_eq!(units.map(<&str>::from), ["no-unit", "dpi", "dpcm"]);
+    }
+
+    #[test]
+    fn decoding_overwrites_reused_buffers() {
+        let gradient = include_bytes!("../../tests/fixtures/gradient_64x48.jpg");
+        let mut gray = Vec::new();
+        Encoder::new(75)
+            .encode(&[90; 16 * 16], 16, 16, 1, &mut gray)
+            .unwrap();
+
+        for data in [&gradient[..], &gray] {
+            let mut jpeg = JPEGHeader::from_bytes(data).unwrap();
+            let expected = jpeg.decode_huffman().unwrap();
+
+            let dirty = MCU {
+                r: [7; 64],
+                g: [-7; 64],
+                b: [700; 64],
+                is_rbg: false,
+            };
+            let mut mcus = vec![MaybeUninit::new(dirty); expected.len()];
+            jpeg.decode_huffman_into(&mut mcus, None).unwrap();
+
+            for (mcu, expected) in mcus.iter().zip(&expected) {
+                // SAFETY: the buffer started initialized
+                let mcu = unsafe { mcu.assume_init_ref() };
+                assert_eq!((mcu.r, mcu.g, mcu.b), (expected.r, expected.g, expected.b));
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_tables_decode_like_the_search() {
+        let mut bits = [0; 16];
+        bits[2] = 1;
+
+        let single = HuffmanTable::new(&bits, &[0x05]);
+        let empty = HuffmanTable::new(&[0; 16], &[]);
+        assert!(single.is_degenerate() && empty.is_degenerate());
+
+        // Eight 3-bit zero codes then a mismatch
+        let data = [0x00, 0x00, 0x00, 0xFF, 0xFF];
+        let mut fast = BitReader::new(&data);
+        let mut search = BitReader::new(&data);
+
+        for _ in 0..8 {
+            assert_eq!(
+                JPEGHeader::get_next_symbol(&mut fast, &single),
+                JPEGHeader::search_symbol(&mut search, &single)
+            );
+            assert_eq!(fast.snapshot(), search.snapshot());
+        }
+
+        assert_eq!(
+            JPEGHeader::get_next_symbol(&mut fast, &single),
+            Err(HuffmanDecodingError::SymbolNotFound.into())
+        );
+        assert_eq!(
+            JPEGHeader::search_symbol(&mut search, &single),
+            Err(HuffmanDecodingError::SymbolNotFound.into())
+        );
+
+        let mut reader = BitReader::new(&data);
+        assert_eq!(
+            JPEGHeader::get_next_symbol(&mut reader, &empty),
+            JPEGHeader::search_symbol(&mut BitReader::new(&data), &empty)
+        );
+    }
+
+    #[test]
+    fn level_shift_centers_a_flat_block() {
+        let mut mcu = MCU {
+            r: [0; 64],
+            g: [0; 64],
+            b: [0; 64],
+            is_rbg: false,
+        };
+
+        mcu.reconstruct(&[[1; 64]; 3], 8);
+        assert_eq!(mcu.r, [128; 64]);
+        assert_eq!(mcu.g, [128; 64]);
+        assert_eq!(mcu.b, [128; 64]);
+
+        mcu.r = [-200; 64];
+        mcu.g = [200; 64];
+        mcu.apply_level_shift();
+        assert_eq!(mcu.r, [0; 64]);
+        assert_eq!(mcu.g, [255; 64]);
+    }
+
+    #[test]
+    fn blank_blocks_are_filled_with_the_dc() {
+        let mut mcu = MCU {
+            r: [0; 64],
+            g: [0; 64],
+            b: [0; 64],
+            is_rbg: false,
+        };
+        mcu.r[0] = -50;
+        mcu.g[0] = 3;
+        assert!(mcu.is_blank());
+
+        // Every channel filled at once
+        let mut blank = mcu;
+        blank.reconstruct(&[[8; 64]; 3], 8);
+        assert_eq!(
+            [blank.r, blank.g, blank.b],
+            [[78; 64], [131; 64], [128; 64]]
+        );
+
+        mcu.b[63] = 1;
+        assert!(mcu.is_blank_channel(0) && !mcu.is_blank_channel(2));
+        assert!(!mcu.is_blank());
+
+        let mut expected = mcu.b.map(|coefficient| coefficient * 8);
+        aan_idct_2d(&mut expected);
+
+        // -400 / 8 and 24 / 8 filled, the AC coefficient goes through the IDCT
+        mcu.reconstruct(&[[8; 64]; 3], 8);
+        assert_eq!(mcu.r, [78; 64]);
+        assert_eq!(mcu.g, [131; 64]);
+        assert_eq!(mcu.b, expected.map(|sample| sample + 128));
+    }
+
+    #[test]
+    fn segments_hold_at_most_65533_bytes() {
+        let mut bytes = Vec::new();
+
+        push_segment(&mut bytes, 0xFE, &[b'a'; 65533]).unwrap();
+        assert_eq!(bytes[..4], [0xFF, 0xFE, 0xFF, 0xFF]);
+
+        assert_eq!(
+            push_segment(&mut bytes, 0xFE, &[b'a'; 65534]),
+            Err(Error::SegmentTooLong { marker: 0xFE })
+        );
+        assert_eq!(bytes.len(), 65537);
+    }
+
+    #[test]
+    fn aan_idct_matches_the_definition() {
+        for i in 0..64 {
+            for amplitude in [-1024, 1024] {
+                let mut fast = [0; 64];
+                fast[i] = amplitude;
+                let mut reference = fast;
+
+                aan_idct_2d(&mut fast);
+                naive_idct_2d(&mut reference);
+
+                for (fast, reference) in fast.iter().zip(reference) {
+                    assert!(
+                        (fast - reference).abs() <= 1,
+                        "basis function {i} at {amplitude}: {fast} against {reference}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scaling_qtables() {
+        let luma = QTable::new(LUMINANCE_QTABLE);
+        assert!(luma.matches_standard_luma() && !luma.matches_standard_chroma());
+        assert!(QTable::new(CHROMINANCE_QTABLE).matches_standard_chroma());
+
+        assert!(luma.scale(1.0).unwrap().matches_standard_luma());
+        assert!(!luma.scale(2.0).unwrap().matches_standard_luma());
+
+        // 16 * 0.5, 11 * 0.5 rounded, 99 * 0.5 rounded
+        let halved = luma.scale(0.5).unwrap();
+        assert_eq!(halved.table[..2], [8, 6]);
+        assert_eq!(halved.table[63], 50);
+
+        assert_eq!(luma.scale(0.001).unwrap().table, [1; 64]);
+        assert_eq!(luma.scale(100.0).unwrap().table, [255; 64]);
+        assert_eq!(
+            QTable::new([300; 64]).scale(1000.0).unwrap().table,
+            [32767; 64]
+        );
+
+        for factor in [0.0, -1.0, f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            assert_eq!(luma.scale(factor), None, "{factor}");
+        }
+    }
+
+    #[test]
+    fn predictions_reset_between_restart_intervals() {
+        let mut data = Vec::new();
+        Encoder::new(90)
+            .encode(&[200; 64 * 64], 64, 64, 1, &mut data)
+            .unwrap();
+
+        let mut jpeg = JPEGHeader::from_bytes(&data).unwrap();
+        let mut mcus = jpeg.decode_huffman().unwrap();
+        jpeg.restart_interval = 8;
+        jpeg.encode_huffman(&mut mcus);
+        jpeg.build_huffman_lookups();
+
+        // Every MCU has the same DC, so only the first of each interval codes
+        // a difference. Without a reset the stale prediction shows up
+        let dc = mcus[0].r[0];
+        let stale = 100;
+        assert!(dc != 0 && dc != stale);
+
+        let mut reader = BitReader::new(&jpeg.huffman_data);
+        let mut resets = Vec::new();
+
+        for i in 0..64 {
+            let mut mcu = [MCU::default()];
+            let mut previous_dc = [stale; 3];
+            jpeg.decode_next_mcu(&mut reader, i, &mut mcu, &mut previous_dc)
+                .unwrap();
+
+            if mcu[0].r[0] == dc {
+                resets.push(i);
+            }
+        }
+
+        assert_eq!(resets, [8, 16, 24, 32, 40, 48, 56]);
+    }
+
+    proptest! {
+        #[test]
+        fn blank_fill_matches_the_idct(dc in -1024 * 8..1024 * 8) {
+            let mut block = [0; 64];
+            block[0] = dc;
+            aan_idct_2d(&mut block);
+
+            let mut mcu = MCU::default();
+            mcu.r[0] = dc;
+            mcu.reconstruct(&[[1; 64]; 3], 8);
+
+            prop_assert_eq!(mcu.r, block.map(|sample| (sample + 128).clamp(0, 255)));
+        }
+
+        #[test]
+        fn clones_read_the_same_bits(
+            data in prop::collection::vec(any::<u8>(), 1..64),
+            skip in 0..64u8,
+            lengths in prop::collection::vec(1..=16u8, 1..32),
+        ) {
+            let mut reader = BitReader::new(&data);
+            let _ = reader.read_length(skip % 8);
+
+            let mut clone = reader.clone();
+
+            for length in lengths {
+                prop_assert_eq!(reader.read_length(length), clone.read_length(length));
+            }
+        }
+
+        #[test]
+        fn advanced_clones_stay_ahead(
+            data in prop::collection::vec(any::<u8>(), 8..64),
+            ahead in 1..=16u8,
+            length in 1..=16u8,
+        ) {
+            let mut reader = BitReader::new(&data);
+            let mut clone = reader.clone();
+
+            let _ = clone.read_length(ahead);
+
+            prop_assert_eq!(clone.byte_position * 8 + clone.bit_position, ahead as usize);
+
+            // The clone reads the bits right after the first `ahead` bits
+            let combined = reader.read_length(ahead + length).unwrap();
+            let expected = combined & ((1 << length) - 1);
+
+            prop_assert_eq!(clone.read_length(length), Some(expected));
+        }
+
+        #[test]
+        fn peeking_then_skipping_matches_reading(
+            data in prop::collection::vec(any::<u8>(), 0..8),
+            lengths in prop::collection::vec(0..=16u8, 1..32),
+        ) {
+            let mut reader = BitReader::new(&data);
+            let mut peeker = BitReader::new(&data);
+
+            for length in lengths {
+                let peeked = peeker.peek_bits(length);
+                prop_assert_eq!(peeked, reader.read_length(length));
+
+                if peeked.is_none() {
+                    break;
+                }
+
+                peeker.skip_bits(length);
+                prop_assert_eq!(peeker.snapshot(), reader.snapshot());
+            }
+        }
+
+        #[test]
+        fn quantizing_stays_within_half_a_step(
+            coefficients in prop::collection::vec(-2048..2048i32, 64),
+            steps in prop::collection::vec(1..=255u16, 64),
+            component in 0..3usize,
+        ) {
+            let mut mcu = MCU::default();
+            mcu.component(component).copy_from_slice(&coefficients);
+
+            let qtable = QTable::new(steps.clone().try_into().unwrap());
+
+            mcu.quantize(&qtable, component);
+            mcu.dequantize(&qtable, component);
+
+            for ((before, after), step) in coefficients.iter().zip(mcu.component(component)).zip(steps) {
+                prop_assert!((before - *after).abs() <= step as i32 / 2);
+            }
+        }
+
+        #[test]
+        fn restoring_a_snapshot_rereads_the_same_bits(
+            data in prop::collection::vec(any::<u8>(), 1..64),
+            skip in 0..64u8,
+            length in 1..=16u8,
+        ) {
+            let mut reader = BitReader::new(&data);
+            let _ = reader.read_length(skip);
+
+            let snapshot = reader.snapshot();
+            let first = reader.read_length(length);
+
+            reader.restore(snapshot);
+
+            prop_assert_eq!(reader.read_length(length), first);
+        }
+
+        #[test]
+        fn bits_are_read_as_a_big_endian_bit_string(
+            data in prop::collection::vec(any::<u8>(), 0..=4),
+        ) {
+            let expected = data.iter().fold(0, |value, &byte| (value << 8) | byte as u32);
+
+            let mut reader = BitReader::new(&data);
+
+            prop_assert_eq!(reader.read_length(data.len() as u8 * 8), Some(expected));
+            prop_assert_eq!(reader.read_bit(), None);
+        }
+
+        #[test]
+        fn reading_a_byte_matches_reading_its_bits(
+            data in prop::collection::vec(any::<u8>(), 2..16),
+            skip in 0..8u8,
+        ) {
+            let mut reader = BitReader::new(&data);
+            let _ = reader.read_length(skip);
+
+            let mut bits = reader.clone();
+            let expected = (0..8).try_fold(0, |byte, _| Some((byte << 1) | bits.read_bit()?));
+
+            prop_assert_eq!(reader.read_length(8), expected);
+            prop_assert_eq!(reader.snapshot(), bits.snapshot());
+        }
+
+        #[test]
+        fn aligning_starts_the_next_byte(
+            data in prop::collection::vec(any::<u8>(), 0..16),
+            skip in 0..160u8,
+        ) {
+            let mut reader = BitReader::new(&data);
+            let _ = reader.read_length(skip.min(data.len() as u8 * 8));
+            let read = reader.byte_position * 8 + reader.bit_position;
+
+            reader.align();
+
+            prop_assert_eq!(reader.bit_position, 0);
+            prop_assert_eq!(reader.byte_position, read.div_ceil(8));
+        }
+
+        #[test]
+        fn reading_no_bits_does_not_advance(
+            data in prop::collection::vec(any::<u8>(), 0..16),
+            skip in 0..160u8,
+        ) {
+            let mut reader = BitReader::new(&data);
+            let _ = reader.read_length(skip.min(data.len() as u8 * 8));
+            let snapshot = reader.snapshot();
+
+            prop_assert_eq!(reader.read_length(0), Some(0));
+            prop_assert_eq!(reader.snapshot(), snapshot);
+        }
+
+        #[test]
+        fn reading_past_the_end_fails(
+            data in prop::collection::vec(any::<u8>(), 0..4),
+            skip in 0..32u8,
+            extra in 1..=8u8,
+        ) {
+            let total = data.len() as u8 * 8;
+            let skip = skip.min(total);
+
+            let mut reader = BitReader::new(&data);
+            let _ = reader.read_length(skip);
+
+            prop_assert_eq!(reader.read_length(total - skip + extra), None);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Inverse DCT of 8x8 blocks. Blocks are in natural (row major) order, not
+//! zigzag order.
+
+use std::f32::consts::{FRAC_1_SQRT_2, PI, SQRT_2};
+
+/// `cos(k * PI / 16) * sqrt(2)` with the first entry set to 1. The AAN
+/// algorithm expects its input to be pre multiplied by these
+const AAN_SCALE: [f32; 8] = [
+    1.0,
+    1.387_039_8,
+    1.306_563,
+    1.175_875_6,
+    1.0,
+    0.785_694_96,
+    0.541_196_1,
+    0.275_899_38,
+];
+
+/// One dimensional Arai-Agui-Nakajima IDCT. Takes 5 multiplications and 29
+/// additions.
+///
+/// The input must be scaled by [`AAN_SCALE`] and the output is `sqrt(8)`
+/// times the actual IDCT. [`aan_idct_2d`] takes care of both.
+pub fn aan_idct_1d(data: &mut [f32; 8]) {
+    // Even part
+    let tmp10 = data[0] + data[4];
+    let tmp11 = data[0] - data[4];
+
+    let tmp13 = data[2] + data[6];
+    let tmp12 = (data[2] - data[6]) * SQRT_2 - tmp13;
+
+    let tmp0 = tmp10 + tmp13;
+    let tmp3 = tmp10 - tmp13;
+    let tmp1 = tmp11 + tmp12;
+    let tmp2 = tmp11 - tmp12;
+
+    // Odd part
+    let z13 = data[5] + data[3];
+    let z10 = data[5] - data[3];
+    let z11 = data[1] + data[7];
+    let z12 = data[1] - data[7];
+
+    let tmp7 = z11 + z13;
+    let tmp11 = (z11 - z13) * SQRT_2;
+
+    let z5 = (z10 + z12) * 1.847_759;
+    let tmp10 = z12 * 1.082_392_2 - z5;
+    let tmp12 = z10 * -2.613_126 + z5;
+
+    let tmp6 = tmp12 - tmp7;
+    let tmp5 = tmp11 - tmp6;
+    let tmp4 = tmp10 + tmp5;
+
+    data[0] = tmp0 + tmp7;
+    data[7] = tmp0 - tmp7;
+    data[1] = tmp1 + tmp6;
+    data[6] = tmp1 - tmp6;
+    data[2] = tmp2 + tmp5;
+    data[5] = tmp2 - tmp5;
+    data[4] = tmp3 + tmp4;
+    data[3] = tmp3 - tmp4;
+}
+
+/// Separable IDCT using [`aan_idct_1d`] on the columns then the rows.
+/// Results are rounded and not level shifted.
+pub fn aan_idct_2d(block: &mut [i32; 64]) {
+    let mut workspace = [0.0; 64];
+
+    for (i, coefficient) in block.iter().enumerate() {
+        workspace[i] = *coefficient as f32 * AAN_SCALE[i / 8] * AAN_SCALE[i % 8];
+    }
+
+    for x in 0..8 {
+        let mut column = [0.0; 8];
+
+        for y in 0..8 {
+            column[y] = workspace[y * 8 + x];
+        }
+
+        aan_idct_1d(&mut column);
+
+        for y in 0..8 {
+            workspace[y * 8 + x] = column[y];
+        }
+    }
+
+    for row in workspace.chunks_exact_mut(8) {
+        let row: &mut [f32; 8] = row.try_into().unwrap();
+        aan_idct_1d(row);
+    }
+
+    // Each pass left a factor of sqrt(8)
+    for (value, result) in block.iter_mut().zip(workspace) {
+        *value = (result / 8.0).round() as i32;
+    }
+}
+
+/// Straight from the definition. Performs 64 multiplications per output
+/// value and is only kept as a reference for [`aan_idct_2d`]
+pub fn naive_idct_2d(block: &mut [i32; 64]) {
+    let mut output = [0; 64];
+
+    for y in 0..8 {
+        for x in 0..8 {
+            let mut sum = 0.0;
+
+            for v in 0..8 {
+                for u in 0..8 {
+                    let cu = if u == 0 { FRAC_1_SQRT_2 } else { 1.0 };
+                    let cv = if v == 0 { FRAC_1_SQRT_2 } else { 1.0 };
+
+                    sum += cu
+                        * cv
+                        * block[v * 8 + u] as f32
+                        * (((2 * x + 1) as f32 * u as f32 * PI) / 16.0).cos()
+                        * (((2 * y + 1) as f32 * v as f32 * PI) / 16.0).cos();
+                }
+            }
+
+            output[y * 8 + x] = (sum / 4.0).round() as i32;
+        }
+    }
+
+    *block = output;
+}
+
+/// IDCT of only the top left `size` x `size` coefficients, producing a block
+/// scaled down by `8 / size`. The result is stored row major in the first
+/// `size * size` entries. A `size` of 1 is the DC coefficient alone
+pub fn reduced_idct_2d(block: &mut [i32; 64], size: usize) {
+    assert!(matches!(size, 1 | 2 | 4 | 8), "Unsupported IDCT size");
+
+    let mut cosines = [[0.0; 8]; 8];
+
+    for (u, row) in cosines.iter_mut().enumerate().take(size) {
+        let scale = if u == 0 { FRAC_1_SQRT_2 } else { 1.0 };
+
+        for (x, cosine) in row.iter_mut().enumerate().take(size) {
+            *cosine = scale * (((2 * x + 1) * u) as f32 * PI / (2 * size) as f32).cos();
+        }
+    }
+
+    let mut output = [0; 64];
+
+    for y in 0..size {
+        for x in 0..size {
+            let mut sum = 0.0;
+
+            for v in 0..size {
+                for u in 0..size {
+                    sum += block[v * 8 + u] as f32 * cosines[u][x] * cosines[v][y];
+                }
+            }
+
+            output[y * size + x] = (sum / 4.0).round() as i32;
+        }
+    }
+
+    *block = output;
+}
@@ -0,0 +1,28 @@
+//! Conversions into the `image` crate types. Enabled by the `image-interop`
+//! feature.
+
+use super::{Error, JPEGHeader};
+use image::{DynamicImage, RgbImage};
+
+impl TryFrom<JPEGHeader> for RgbImage {
+    type Error = Error;
+
+    fn try_from(mut header: JPEGHeader) -> Result<Self, Self::Error> {
+        // The header can be changed after parsing, so the scan may not
+        // decode anymore
+        let pixels = header.decode_pixels()?;
+
+        let (width, height) = header.output_dimensions();
+
+        Ok(RgbImage::from_raw(width as u32, height as u32, pixels)
+            .expect("Decoded buffer matches the output dimensions"))
+    }
+}
+
+impl TryFrom<JPEGHeader> for DynamicImage {
+    type Error = Error;
+
+    fn try_from(header: JPEGHeader) -> Result<Self, Self::Error> {
+        Ok(DynamicImage::ImageRgb8(header.try_into()?))
+    }
+}
@@ -0,0 +1,169 @@
+use super::header::ColorSpace;
+use std::fmt::{self, Display, Write};
+
+/// Everything [`super::JPEGHeader`] parsed besides the image data, as
+/// returned by [`super::JPEGHeader::metadata`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageMetadata {
+    pub width: u16,
+    pub height: u16,
+    pub color_space: ColorSpace,
+    /// From the IJG tables, see [`super::JPEGHeader::quality_estimate`]
+    pub quality_estimate: Option<u8>,
+    pub jfif: Option<JfifMetadata>,
+    /// Transform flag of the Adobe APP14 segment: 0 = RGB (or CMYK),
+    /// 1 = YCbCr and 2 = YCCK
+    pub adobe_color_transform: Option<u8>,
+    pub comments: Vec<String>,
+    /// The XMP packet without its identifier
+    pub xmp: Option<Vec<u8>>,
+}
+
+/// Contents of the JFIF APP0 segment and its extension
+#[derive(Debug, Clone, PartialEq)]
+pub struct JfifMetadata {
+    /// Major and minor version, like `(1, 2)`
+    pub version: (u8, u8),
+    pub dpi: Option<(f32, f32)>,
+    pub pixel_aspect_ratio: Option<f32>,
+    /// The uncompressed thumbnail as `(rgb_bytes, width, height)`
+    pub thumbnail: Option<(Vec<u8>, u8, u8)>,
+    /// JPEG stream of the thumbnail from a JFXX extension segment
+    pub jpeg_thumbnail: Option<Vec<u8>>,
+}
+
+impl ImageMetadata {
+    /// A single line JSON object of the fields worth logging. Thumbnails and
+    /// the XMP packet are only given by their size and non-finite numbers
+    /// become `null`, so the output is always valid JSON
+    pub fn to_json_lossy(&self) -> String {
+        let mut json = format!(
+            "{{\"width\":{},\"height\":{},\"color_space\":\"{:?}\",\"quality_estimate\":{}",
+            self.width,
+            self.height,
+            self.color_space,
+            json_option(self.quality_estimate)
+        );
+
+        json.push_str(",\"jfif\":");
+        match &self.jfif {
+            Some(jfif) => {
+                let dpi = jfif
+                    .dpi
+                    .map(|(x, y)| format!("[{},{}]", json_number(x), json_number(y)));
+
+                let _ = write!(
+                    json,
+                    "{{\"version\":\"{}.{:02}\",\"dpi\":{},\"pixel_aspect_ratio\":{}",
+                    jfif.version.0,
+                    jfif.version.1,
+                    dpi.unwrap_or_else(|| "null".to_string()),
+                    jfif.pixel_aspect_ratio
+                        .map_or_else(|| "null".to_string(), json_number)
+                );
+                let _ = write!(
+                    json,
+                    ",\"thumbnail\":{},\"jpeg_thumbnail_bytes\":{}}}",
+                    json_option(
+                        jfif.thumbnail
+                            .as_ref()
+                            .map(|(_, width, height)| format!("[{width},{height}]"))
+                    ),
+                    json_option(jfif.jpeg_thumbnail.as_ref().map(Vec::len))
+                );
+            }
+            None => json.push_str("null"),
+        }
+
+        let comments: Vec<String> = self.comments.iter().map(|c| json_string(c)).collect();
+
+        let _ = write!(
+            json,
+            ",\"adobe_color_transform\":{},\"comments\":[{}],\"xmp_bytes\":{}}}",
+            json_option(self.adobe_color_transform),
+            comments.join(","),
+            json_option(self.xmp.as_ref().map(Vec::len))
+        );
+
+        json
+    }
+}
+
+impl Display for ImageMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Dimensions: {}x{}", self.width, self.height)?;
+        writeln!(f, "Color space: {:?}", self.color_space)?;
+
+        if let Some(quality) = self.quality_estimate {
+            writeln!(f, "Estimated quality: {quality}")?;
+        }
+
+        if let Some(jfif) = &self.jfif {
+            writeln!(f, "JFIF version: {}.{:02}", jfif.version.0, jfif.version.1)?;
+
+            if let Some((x, y)) = jfif.dpi {
+                writeln!(f, "Density: {x}x{y} dpi")?;
+            }
+
+            if let Some(ratio) = jfif.pixel_aspect_ratio {
+                writeln!(f, "Pixel aspect ratio: {ratio}")?;
+            }
+
+            if let Some((_, width, height)) = &jfif.thumbnail {
+                writeln!(f, "Thumbnail: {width}x{height} RGB")?;
+            }
+
+            if let Some(thumbnail) = &jfif.jpeg_thumbnail {
+                writeln!(f, "Thumbnail: JPEG, {} bytes", thumbnail.len())?;
+            }
+        }
+
+        if let Some(transform) = self.adobe_color_transform {
+            writeln!(f, "Adobe color transform: {transform}")?;
+        }
+
+        for comment in &self.comments {
+            writeln!(f, "Comment: {comment}")?;
+        }
+
+        if let Some(xmp) = &self.xmp {
+            writeln!(f, "XMP: {} bytes", xmp.len())?;
+        }
+
+        Ok(())
+    }
+}
+
+fn json_option<T: Display>(value: Option<T>) -> String {
+    value.map_or_else(|| "null".to_string(), |value| value.to_string())
+}
+
+fn json_number(value: f32) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+
+    escaped.push('"');
+    escaped
+}
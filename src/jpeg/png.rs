@@ -0,0 +1,83 @@
+//! A minimal PNG encoder for decoded images. Enabled by the `png-output`
+//! feature.
+
+use super::error::Result;
+use super::JPEGHeader;
+use miniz_oxide::deflate::compress_to_vec_zlib;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+/// 8 bit samples
+const BIT_DEPTH: u8 = 8;
+/// Truecolor, three samples a pixel
+const COLOR_TYPE_RGB: u8 = 2;
+const FILTER_NONE: u8 = 0;
+const FILTER_SUB: u8 = 1;
+const COMPRESSION_LEVEL: u8 = 6;
+
+impl JPEGHeader {
+    /// Decodes the image and encodes the pixels as an 8 bit RGB PNG. Each
+    /// row is filtered with None or Sub, whichever leaves the smaller
+    /// residuals, and the rows are compressed into a single IDAT chunk
+    #[must_use = "the encoded PNG is only returned"]
+    pub fn to_png_bytes(&mut self) -> Result<Vec<u8>> {
+        let pixels = self.decode_pixels()?;
+        let (width, height) = self.output_dimensions();
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+        // Deflate compression, adaptive filtering and no interlacing
+        ihdr.extend_from_slice(&[BIT_DEPTH, COLOR_TYPE_RGB, 0, 0, 0]);
+
+        let mut filtered = Vec::with_capacity(pixels.len() + height);
+
+        for row in pixels.chunks_exact(width * 3) {
+            filter_row(row, &mut filtered);
+        }
+
+        let mut bytes = SIGNATURE.to_vec();
+        push_chunk(&mut bytes, b"IHDR", &ihdr);
+        push_chunk(
+            &mut bytes,
+            b"IDAT",
+            &compress_to_vec_zlib(&filtered, COMPRESSION_LEVEL),
+        );
+        push_chunk(&mut bytes, b"IEND", &[]);
+
+        Ok(bytes)
+    }
+}
+
+/// Appends the filter type and the filtered row. Sub stores each byte minus
+/// the same sample of the pixel to its left
+fn filter_row(row: &[u8], out: &mut Vec<u8>) {
+    let sub = |i: usize| match i.checked_sub(3) {
+        Some(left) => row[i].wrapping_sub(row[left]),
+        None => row[i],
+    };
+
+    // Sum of the residuals read as signed bytes, the usual heuristic
+    let cost = |residual: u8| (residual as i8).unsigned_abs() as u32;
+    let none_cost: u32 = row.iter().map(|byte| cost(*byte)).sum();
+    let sub_cost: u32 = (0..row.len()).map(|i| cost(sub(i))).sum();
+
+    if sub_cost < none_cost {
+        out.push(FILTER_SUB);
+        out.extend((0..row.len()).map(sub));
+    } else {
+        out.push(FILTER_NONE);
+        out.extend_from_slice(row);
+    }
+}
+
+/// Appends a chunk: its length, type, data and the CRC of type and data
+fn push_chunk(bytes: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(kind);
+    bytes.extend_from_slice(data);
+
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    bytes.extend_from_slice(&crc.finalize().to_be_bytes());
+}
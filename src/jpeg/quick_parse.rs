@@ -0,0 +1,51 @@
+//! Pulling single values out of a JPEG without parsing the whole header.
+
+use super::diagnostics::MarkerScanner;
+use super::error::{Error, Result};
+use super::header::Marker;
+
+/// The restart interval in MCUs from the DRI segment before the scan, or
+/// `None` without one. Only segment lengths are read on the way there, so
+/// the other segments aren't checked. A DRI after SOS only applies to later
+/// scans and is ignored
+///
+/// # Examples
+///
+/// ```no_run
+/// use images::jpeg::quick_parse;
+///
+/// let data = std::fs::read("photo.jpg").unwrap();
+///
+/// match quick_parse::parse_restart_interval(&data).unwrap() {
+///     Some(interval) => println!("Restart marker every {interval} MCUs"),
+///     None => println!("No restart markers"),
+/// }
+/// ```
+pub fn parse_restart_interval(stream: &[u8]) -> Result<Option<u16>> {
+    let mut markers = MarkerScanner::new(stream);
+
+    match markers.next() {
+        Some(Ok((Marker::SOI, _))) => {}
+        Some(Err(error)) => return Err(error),
+        _ => return Err(Error::StartOfImageNotFound),
+    }
+
+    for item in markers {
+        let (marker, bytes) = item?;
+
+        match marker {
+            Marker::DRI => {
+                // The code, a length of 4 and the interval
+                let [_, 0x00, 0x04, high, low] = bytes else {
+                    return Err(Error::InvalidRestartIntervalMarker);
+                };
+
+                return Ok(Some(u16::from_be_bytes([*high, *low])));
+            }
+            Marker::SOS | Marker::EOI => break,
+            _ => {}
+        }
+    }
+
+    Ok(None)
+}
@@ -0,0 +1,63 @@
+//! Zigzag ordering of the 64 coefficients of an 8x8 block.
+
+/// Maps a zigzag index to its natural (row major) index
+pub(super) const ZIGZAG: [u16; 64] = build_zigzag();
+
+/// Maps a natural (row major) index to its zigzag index
+pub(super) const ZIGZAG_INV: [u16; 64] = invert(&ZIGZAG);
+
+// Both tables must undo each other
+const _: () = {
+    let mut i = 0;
+
+    while i < 64 {
+        assert!(ZIGZAG[ZIGZAG_INV[i] as usize] == i as u16);
+        i += 1;
+    }
+};
+
+/// Walks the anti-diagonals of the block, going up and right on even
+/// diagonals and down and left on odd ones
+const fn build_zigzag() -> [u16; 64] {
+    let mut table = [0; 64];
+    let mut x = 0;
+    let mut y = 0;
+    let mut i = 0;
+
+    while i < 64 {
+        table[i] = (y * 8 + x) as u16;
+        i += 1;
+
+        if (x + y) % 2 == 0 {
+            if x == 7 {
+                y += 1;
+            } else if y == 0 {
+                x += 1;
+            } else {
+                x += 1;
+                y -= 1;
+            }
+        } else if y == 7 {
+            x += 1;
+        } else if x == 0 {
+            y += 1;
+        } else {
+            x -= 1;
+            y += 1;
+        }
+    }
+
+    table
+}
+
+const fn invert(table: &[u16; 64]) -> [u16; 64] {
+    let mut inverse = [0; 64];
+    let mut i = 0;
+
+    while i < 64 {
+        inverse[table[i] as usize] = i as u16;
+        i += 1;
+    }
+
+    inverse
+}
@@ -1,5 +1,4 @@
-mod jpeg;
-use jpeg::JPEGHeader;
+use images::jpeg::JPEGHeader;
 
 fn main() {
     let image = "cat.jpg";
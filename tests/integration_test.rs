@@ -0,0 +1,1800 @@
+use images::jpeg::analyze;
+use images::jpeg::diagnostics::{JPEGDiagnostics, MarkerScanner};
+use images::jpeg::encode::{encode_rgb, Encoder};
+use images::jpeg::quick_parse;
+use images::jpeg::{
+    ColorSpace, CorruptionHint, CorruptionReason, DHTError, DecodeOptions, Error,
+    HuffmanDecodingError, JPEGHeader, JPEGHeaderBuilder, JPEGPixelReader, Marker, OutputFormat,
+    QTable, Rect, Rotation, SOF0MarkerError, SOSError, ScaleFactor, Warning,
+};
+use std::io::Read;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+const SOLID: &[u8] = include_bytes!("fixtures/solid_8x8.jpg");
+const GRADIENT: &[u8] = include_bytes!("fixtures/gradient_64x48.jpg");
+const GRAYSCALE: &[u8] = include_bytes!("fixtures/grayscale_32x32.jpg");
+const SUBSAMPLED: &[u8] = include_bytes!("fixtures/subsampled_420_32x32.jpg");
+const THUMBNAIL: &[u8] = include_bytes!("fixtures/thumbnail_16x16.jpg");
+/// No APP0 or APP14, components with the ids 'R', 'G' and 'B'
+const RGB_NO_APP0: &[u8] = include_bytes!("fixtures/rgb_no_app0_16x16.jpg");
+
+/// Offset of the 0xFF of the first `code` marker in `bytes`
+fn marker_offset(bytes: &[u8], code: u8) -> usize {
+    bytes
+        .windows(2)
+        .position(|window| window == [0xFF, code])
+        .unwrap()
+}
+
+/// Expected RGB values at (0, 0), the center and the bottom right corner.
+/// Taken from the `image` crate decoder
+struct Expected {
+    width: usize,
+    height: usize,
+    color_space: ColorSpace,
+    corners: [[u8; 3]; 3],
+}
+
+fn check(data: &[u8], expected: Expected) -> JPEGHeader {
+    let mut header = JPEGHeader::from_bytes(data).unwrap();
+
+    let (width, height) = header.output_dimensions();
+    assert_eq!((width, height), (expected.width, expected.height));
+    assert_eq!(header.color_space(), expected.color_space);
+
+    let pixels = header.decode_pixels().unwrap();
+    assert_eq!(pixels.len(), width * height * 3);
+
+    let points = [(0, 0), (width / 2, height / 2), (width - 1, height - 1)];
+
+    for ((x, y), rgb) in points.into_iter().zip(expected.corners) {
+        let offset = (y * width + x) * 3;
+        let actual = &pixels[offset..offset + 3];
+
+        for (actual, rgb) in actual.iter().zip(rgb) {
+            assert!(
+                actual.abs_diff(rgb) <= 2,
+                "Pixel ({}, {}) is {:?}, expected {:?}",
+                x,
+                y,
+                &pixels[offset..offset + 3],
+                rgb
+            );
+        }
+    }
+
+    header
+}
+
+#[test]
+fn solid_color() {
+    check(
+        SOLID,
+        Expected {
+            width: 8,
+            height: 8,
+            color_space: ColorSpace::YCbCr,
+            corners: [[200, 100, 50]; 3],
+        },
+    );
+}
+
+#[test]
+fn gradient() {
+    check(
+        GRADIENT,
+        Expected {
+            width: 64,
+            height: 48,
+            color_space: ColorSpace::YCbCr,
+            corners: [[2, 0, 127], [129, 130, 125], [253, 255, 130]],
+        },
+    );
+}
+
+#[test]
+fn grayscale() {
+    check(
+        GRAYSCALE,
+        Expected {
+            width: 32,
+            height: 32,
+            color_space: ColorSpace::Grayscale,
+            corners: [[0, 0, 0], [128, 128, 128], [248, 248, 248]],
+        },
+    );
+}
+
+#[test]
+fn rgb_without_app0() {
+    let mut header = check(
+        RGB_NO_APP0,
+        Expected {
+            width: 16,
+            height: 16,
+            color_space: ColorSpace::RGB,
+            corners: [[160, 110, 110], [128, 128, 128], [128, 128, 128]],
+        },
+    );
+
+    assert_eq!(header.metadata().jfif, None);
+
+    // Top right and bottom left blocks
+    let pixels = header.decode_pixels().unwrap();
+    assert_eq!(&pixels[15 * 3..16 * 3], [110, 160, 110]);
+    assert_eq!(&pixels[15 * 16 * 3..15 * 16 * 3 + 3], [110, 110, 160]);
+
+    let region = header.decode_partial(Rect::new(8, 0, 8, 8)).unwrap();
+    assert_eq!(&region[..3], [110, 160, 110]);
+
+    // The ids are written back as they were
+    let header = JPEGHeader::from_bytes(&header.to_bytes().unwrap()).unwrap();
+    assert_eq!(header.color_space(), ColorSpace::RGB);
+
+    let mut streamed = Vec::new();
+    JPEGPixelReader::new(header)
+        .read_to_end(&mut streamed)
+        .unwrap();
+    assert_eq!(streamed, pixels);
+}
+
+#[test]
+fn rgb_coded_images_skip_the_color_conversion() {
+    let (width, height) = (16u16, 16u16);
+    let target: Vec<[u8; 3]> = (0..width * height)
+        .map(|i| {
+            let (x, y) = ((i % width) as u8, (i / width) as u8);
+            [100 + 4 * x, 120 + y, 140 - x]
+        })
+        .collect();
+
+    // The encoder converts RGB to YCbCr, so feed it the colors that come
+    // out of that conversion as the target samples
+    let pixels: Vec<u8> = target
+        .iter()
+        .flat_map(|&[r, g, b]| {
+            let (y, cb, cr) = (r as f32, g as f32 - 128.0, b as f32 - 128.0);
+            [
+                y + 1.402 * cr,
+                y - 0.344_136 * cb - 0.714_136 * cr,
+                y + 1.772 * cb,
+            ]
+        })
+        .map(|value| value.round() as u8)
+        .collect();
+
+    let mut data = Vec::new();
+    Encoder::new(100)
+        .encode(&pixels, width, height, 3, &mut data)
+        .unwrap();
+
+    // Drop the JFIF segment and name the components 'R', 'G' and 'B'
+    let app0_len = u16::from_be_bytes([data[4], data[5]]) as usize;
+    data.drain(2..4 + app0_len);
+
+    let sof = marker_offset(&data, 0xC0);
+    let sos = marker_offset(&data, 0xDA);
+
+    for (i, id) in b"RGB".iter().enumerate() {
+        data[sof + 10 + 3 * i] = *id;
+        data[sos + 5 + 2 * i] = *id;
+    }
+
+    let mut header = JPEGHeader::new(data).unwrap();
+    assert_eq!(header.infer_color_space(), ColorSpace::RGB);
+    assert_eq!(header.color_space(), ColorSpace::RGB);
+
+    let decoded = header.decode_pixels().unwrap();
+
+    for (pixel, expected) in decoded.chunks_exact(3).zip(&target) {
+        for (&actual, &expected) in pixel.iter().zip(expected) {
+            assert!(actual.abs_diff(expected) <= 2, "{pixel:?} != {expected:?}");
+        }
+    }
+
+    // The average of the top left block: x and y in 0..8
+    let colors = JPEGHeader::from_bytes(&header.to_bytes().unwrap())
+        .unwrap()
+        .block_colors()
+        .unwrap();
+    let [r, g, b] = colors[0].into();
+    assert!(r.abs_diff(114) <= 1 && g.abs_diff(124) <= 1 && b.abs_diff(136) <= 1);
+}
+
+#[test]
+fn color_space_is_inferred_from_the_component_ids() {
+    let header = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    assert_eq!(header.infer_color_space(), ColorSpace::YCbCr);
+
+    let header = JPEGHeader::from_bytes(GRAYSCALE).unwrap();
+    assert_eq!(header.infer_color_space(), ColorSpace::Grayscale);
+}
+
+#[test]
+fn subsampled_420() {
+    check(
+        SUBSAMPLED,
+        Expected {
+            width: 32,
+            height: 32,
+            color_space: ColorSpace::YCbCr,
+            corners: [[0, 0, 64], [127, 126, 69], [248, 247, 71]],
+        },
+    );
+}
+
+#[test]
+fn jfif_thumbnail() {
+    let header = check(
+        THUMBNAIL,
+        Expected {
+            width: 16,
+            height: 16,
+            color_space: ColorSpace::YCbCr,
+            corners: [[30, 160, 220]; 3],
+        },
+    );
+
+    assert_eq!(header.extract_thumbnail(), Some([30, 160, 220].repeat(4)));
+}
+
+/// Replaces the SOF0 segment of `data` with one declaring the given
+/// component ids, all using the first quantization table
+fn with_sof_components(data: &[u8], ids: &[u8]) -> Vec<u8> {
+    let start = marker_offset(data, 0xC0);
+    let length = u16::from_be_bytes([data[start + 2], data[start + 3]]) as usize;
+
+    let mut sof = vec![0xFF, 0xC0];
+    sof.extend_from_slice(&(8 + 3 * ids.len() as u16).to_be_bytes());
+    sof.extend_from_slice(&data[start + 4..start + 9]);
+    sof.push(ids.len() as u8);
+
+    for id in ids {
+        sof.extend_from_slice(&[*id, 0x11, 0x00]);
+    }
+
+    [&data[..start], &sof, &data[start + 2 + length..]].concat()
+}
+
+#[test]
+fn four_components_are_rejected() {
+    let data = with_sof_components(SOLID, &[1, 2, 3, 4]);
+
+    assert_eq!(
+        JPEGHeader::from_bytes(&data),
+        Err(Error::InvalidSOF0Marker(
+            SOF0MarkerError::InvalidComponentNumber
+        ))
+    );
+}
+
+#[test]
+fn zero_dimensions_are_rejected() {
+    let mut data = SOLID.to_vec();
+    let start = marker_offset(&data, 0xC0);
+
+    // Height follows the length and the precision
+    data[start + 5..start + 7].copy_from_slice(&[0, 0]);
+
+    assert_eq!(
+        JPEGHeader::from_bytes(&data),
+        Err(Error::InvalidSOF0Marker(SOF0MarkerError::ZeroDimensions))
+    );
+}
+
+#[test]
+fn twelve_bit_precision_is_rejected() {
+    let mut data = SOLID.to_vec();
+    let start = marker_offset(&data, 0xC0);
+
+    data[start + 4] = 0x0C;
+
+    let error = JPEGHeader::from_bytes(&data).unwrap_err();
+    assert_eq!(
+        error,
+        Error::InvalidSOF0Marker(SOF0MarkerError::InvalidPrecision(0x0C))
+    );
+    assert!(error.to_string().contains("0x0C"));
+}
+
+#[test]
+fn generated_thumbnails_fit_and_keep_the_aspect_ratio() {
+    let mut header = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    let thumbnail = header.generate_thumbnail(16, 90).unwrap();
+
+    let thumbnail = JPEGHeader::new(thumbnail).unwrap();
+    assert_eq!(thumbnail.output_dimensions(), (16, 12));
+}
+
+#[test]
+fn requantizing_to_unit_steps_keeps_the_pixels() {
+    let mut header = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    let pixels = header.decode_pixels().unwrap();
+
+    let unit = QTable::new([1; 64]);
+    header.requantize(&[unit; 4]).unwrap();
+
+    let mut requantized = JPEGHeader::new(header.to_bytes().unwrap()).unwrap();
+    assert_eq!(requantized, header);
+
+    for (actual, expected) in requantized.decode_pixels().unwrap().iter().zip(pixels) {
+        assert!(actual.abs_diff(expected) <= 1);
+    }
+}
+
+#[test]
+fn coarser_qtables_shrink_the_scan() {
+    let mut header = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    let size = header.to_bytes().unwrap().len();
+
+    header.set_qtable(0, QTable::new([99; 64])).unwrap();
+
+    let bytes = header.to_bytes().unwrap();
+    assert!(bytes.len() < size);
+    assert!(JPEGHeader::new(bytes).unwrap().decode_pixels().is_ok());
+}
+
+#[test]
+fn invalid_qtables_are_rejected() {
+    let mut header = JPEGHeader::from_bytes(SOLID).unwrap();
+    let table = QTable::new([1; 64]);
+
+    assert_eq!(header.set_qtable(4, table), Err(Error::InvalidQTableId(4)));
+
+    let mut zero = [1; 64];
+    zero[10] = 0;
+
+    assert_eq!(
+        header.set_qtable(0, QTable::new(zero)),
+        Err(Error::InvalidQTable)
+    );
+}
+
+#[test]
+fn marker_scanner_walks_every_segment() {
+    let markers = MarkerScanner::new(GRADIENT)
+        .map(|item| item.map(|(marker, _)| marker))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+    assert_eq!(markers.first(), Some(&Marker::SOI));
+    assert_eq!(markers.last(), Some(&Marker::EOI));
+    assert!(markers.contains(&Marker::SOS));
+
+    // Matches the offsets the diagnostics dump finds
+    assert_eq!(markers.len(), JPEGDiagnostics::dump_markers(GRADIENT).len());
+}
+
+#[test]
+fn marker_scanner_stops_at_a_truncated_segment() {
+    let start = marker_offset(GRADIENT, 0xDB);
+
+    let mut scanner = MarkerScanner::new(&GRADIENT[..start + 10]);
+
+    while let Some(Ok((marker, _))) = scanner.next() {
+        assert_ne!(marker, Marker::DQT);
+    }
+
+    assert_eq!(scanner.next(), None);
+}
+
+#[test]
+fn unknown_markers_report_their_offset() {
+    let data = [&SOLID[..2], &[0xFF, 0xAB], &SOLID[2..]].concat();
+    let error = JPEGHeader::from_bytes(&data).unwrap_err();
+
+    assert_eq!(
+        error,
+        Error::UnknownMarker {
+            byte: 0xAB,
+            offset: 2
+        }
+    );
+    assert!(error.to_string().contains("0xAB at byte offset 2"));
+
+    // The scanner and the marker dump agree on where the marker starts
+    let scanned = MarkerScanner::new(&data).find_map(Result::err);
+    assert_eq!(scanned, Some(error));
+
+    let dumped = JPEGDiagnostics::dump_markers(&data);
+    assert_eq!(dumped[1].marker, 0xAB);
+    assert_eq!(dumped[1].byte_offset, 2);
+}
+
+#[test]
+fn lossless_rotation_moves_the_pixels() {
+    let mut header = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    let pixels = header.decode_pixels().unwrap();
+
+    header.rotate_lossless(Rotation::Degrees90).unwrap();
+
+    let mut rotated = JPEGHeader::new(header.to_bytes().unwrap()).unwrap();
+    assert_eq!(rotated.output_dimensions(), (48, 64));
+
+    let rotated_pixels = rotated.decode_pixels().unwrap();
+
+    for y in 0..64 {
+        for x in 0..48 {
+            // Clockwise, so the left column comes from the bottom row
+            let source = ((47 - x) * 64 + y) * 3;
+            let target = (y * 48 + x) * 3;
+
+            for c in 0..3 {
+                assert!(rotated_pixels[target + c].abs_diff(pixels[source + c]) <= 2);
+            }
+        }
+    }
+}
+
+#[test]
+fn mirroring_needs_a_full_block() {
+    let mut data = Vec::new();
+    Encoder::new(90)
+        .encode(&[128; 5 * 5 * 3], 5, 5, 3, &mut data)
+        .unwrap();
+
+    let mut header = JPEGHeader::new(data).unwrap();
+
+    assert_eq!(
+        header.rotate_lossless(Rotation::FlipHorizontal),
+        Err(Error::ImageSmallerThanBlock)
+    );
+}
+
+#[test]
+fn decode_report_counts_a_dc_symbol_per_block() {
+    let mut header = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    let pixels = header.decode_pixels().unwrap();
+    let report = header.decode_with_report().unwrap();
+
+    assert_eq!(report.pixels, pixels);
+
+    let dc_symbols: u32 = report.dc_symbol_frequencies.iter().flatten().sum();
+    assert_eq!(dc_symbols, 8 * 6 * 3);
+}
+
+#[test]
+fn stripping_metadata_keeps_the_image() {
+    let mut header = JPEGHeader::from_bytes(THUMBNAIL).unwrap();
+    let pixels = header.decode_pixels().unwrap();
+
+    let stripped = header.strip_metadata().unwrap();
+    assert!(stripped.len() < THUMBNAIL.len());
+
+    let has_metadata = MarkerScanner::new(&stripped).any(|item| {
+        matches!(
+            item.unwrap().0,
+            Marker::APP0 | Marker::APP1 | Marker::APP2 | Marker::APP14 | Marker::APPN | Marker::COM
+        )
+    });
+    assert!(!has_metadata);
+
+    let mut stripped = JPEGHeader::new(stripped).unwrap();
+    assert_eq!(stripped.extract_thumbnail(), None);
+    assert_eq!(stripped.decode_pixels().unwrap(), pixels);
+}
+
+#[test]
+fn stripping_metadata_keeps_the_color_space() {
+    // Adobe RGB with the ids 1, 2 and 3, which alone would mean YCbCr
+    let app0_end = 4 + u16::from_be_bytes([GRADIENT[4], GRADIENT[5]]) as usize;
+    let adobe = [
+        0xFF, 0xEE, 0x00, 0x0E, b'A', b'd', b'o', b'b', b'e', 0x00, 0x64, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ];
+    let data = [&GRADIENT[..2], &adobe, &GRADIENT[app0_end..]].concat();
+
+    let header = JPEGHeader::from_bytes(&data).unwrap();
+    assert_eq!(header.color_space(), ColorSpace::RGB);
+
+    let stripped = JPEGHeader::new(header.strip_metadata().unwrap()).unwrap();
+    assert_eq!(stripped.color_space(), ColorSpace::RGB);
+
+    // JFIF with a thumbnail over 'R', 'G' and 'B' ids
+    let app0 = &THUMBNAIL[2..4 + u16::from_be_bytes([THUMBNAIL[4], THUMBNAIL[5]]) as usize];
+    let data = with_segment_after(RGB_NO_APP0, None, app0);
+    let header = JPEGHeader::from_bytes(&data).unwrap();
+    assert_eq!(header.color_space(), ColorSpace::YCbCr);
+    assert!(header.extract_thumbnail().is_some());
+
+    let stripped = JPEGHeader::new(header.strip_metadata().unwrap()).unwrap();
+    assert_eq!(stripped.color_space(), ColorSpace::YCbCr);
+    assert_eq!(stripped.extract_thumbnail(), None);
+}
+
+#[test]
+fn pixel_reader_handles_reads_shorter_than_a_pixel() {
+    let mut header = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    let pixels = header.decode_pixels().unwrap();
+
+    let mut reader = JPEGPixelReader::new(header);
+    let mut streamed = Vec::new();
+    let mut buffer = [0; 2];
+
+    loop {
+        let length = reader.read(&mut buffer).unwrap();
+
+        if length == 0 {
+            break;
+        }
+
+        streamed.extend_from_slice(&buffer[..length]);
+    }
+
+    assert_eq!(streamed, pixels);
+}
+
+#[test]
+fn spliced_comments_come_first() {
+    let header = JPEGHeader::from_bytes(SOLID).unwrap();
+
+    let data = header.splice_comment("Copyright someone").unwrap();
+    assert_eq!(&data[..4], [0xFF, 0xD8, 0xFF, 0xFE]);
+
+    let spliced = JPEGHeader::new(data).unwrap();
+    assert_eq!(spliced.comments().first().unwrap(), "Copyright someone");
+
+    assert_eq!(
+        header.splice_comment("null\0byte"),
+        Err(Error::InvalidComment)
+    );
+}
+
+fn com_segment(comment: &[u8]) -> Vec<u8> {
+    let mut segment = vec![0xFF, 0xFE];
+    segment.extend_from_slice(&(comment.len() as u16 + 2).to_be_bytes());
+    segment.extend_from_slice(comment);
+
+    segment
+}
+
+#[test]
+fn every_comment_is_kept_in_order() {
+    let data = with_segment_after(SOLID, None, &com_segment(b"second"));
+    let data = with_segment_after(&data, None, &com_segment(b"first"));
+
+    let header = JPEGHeader::from_bytes(&data).unwrap();
+    assert_eq!(header.comments(), ["first", "second"]);
+}
+
+#[test]
+fn empty_comments_are_empty_strings() {
+    let data = with_segment_after(SOLID, None, &com_segment(b""));
+
+    let header = JPEGHeader::from_bytes(&data).unwrap();
+    assert_eq!(header.comments(), [""]);
+
+    // A length too short to cover itself
+    let data = with_segment_after(SOLID, None, &[0xFF, 0xFE, 0x00, 0x01]);
+    assert_eq!(JPEGHeader::from_bytes(&data), Err(Error::InvalidCOMMarker));
+}
+
+#[test]
+fn located_errors_point_into_the_stream() {
+    let start = marker_offset(SOLID, 0xDB);
+
+    // Cut off in the middle of the first quantization table
+    let data = &SOLID[..start + 20];
+    let error = JPEGHeader::parse_located(data, DecodeOptions::default()).unwrap_err();
+
+    assert_eq!(error.offset(), data.len());
+    assert_eq!(Err(error.error()), JPEGHeader::from_bytes(data));
+}
+
+#[test]
+fn built_headers_decode_to_gray() {
+    // A single one bit code for symbol 0
+    let mut bits = [0; 16];
+    bits[0] = 1;
+
+    let builder = JPEGHeaderBuilder::new()
+        .with_dimensions(16, 12)
+        .with_huffman_table_dc(0, bits, &[0])
+        .with_huffman_table_ac(0, bits, &[0])
+        .with_component(1, (1, 1), 0, 0, 0);
+
+    assert_eq!(builder.clone().build(), Err(Error::InvalidQTable));
+
+    let header = builder
+        .with_qtable(0, QTable::new([1; 64]))
+        .build()
+        .unwrap();
+
+    let mut parsed = JPEGHeader::new(header.to_bytes().unwrap()).unwrap();
+    assert_eq!(parsed, header);
+    assert_eq!(parsed.color_space(), ColorSpace::Grayscale);
+    assert!(parsed
+        .decode_pixels()
+        .unwrap()
+        .iter()
+        .all(|value| *value == 128));
+}
+
+#[test]
+fn end_of_image_before_the_frame_or_the_scan() {
+    let tables_only = [&SOLID[..marker_offset(SOLID, 0xC0)], &[0xFF, 0xD9]].concat();
+    assert_eq!(
+        JPEGHeader::from_bytes(&tables_only),
+        Err(Error::EarlyEndOfImage)
+    );
+
+    let no_scan = [&SOLID[..marker_offset(SOLID, 0xDA)], &[0xFF, 0xD9]].concat();
+    assert_eq!(
+        JPEGHeader::from_bytes(&no_scan),
+        Err(Error::EndOfImageBeforeSOS)
+    );
+}
+
+#[test]
+fn progress_is_reported_every_five_percent() {
+    let calls = Arc::new(AtomicU32::new(0));
+    let last = Arc::new(AtomicU32::new(0));
+
+    let options = {
+        let calls = Arc::clone(&calls);
+        let last = Arc::clone(&last);
+
+        DecodeOptions::default().on_progress(move |fraction| {
+            let previous = f32::from_bits(last.swap(fraction.to_bits(), Ordering::SeqCst));
+            assert!(fraction > previous);
+
+            calls.fetch_add(1, Ordering::SeqCst);
+        })
+    };
+
+    // Checking the scan while parsing doesn't report
+    let mut jpeg = JPEGHeader::with_options(GRADIENT, options).unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+    // 48 MCUs, enough for every step
+    jpeg.decode_pixels().unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 20);
+    assert_eq!(f32::from_bits(last.load(Ordering::SeqCst)), 1.0);
+
+    // Each decode goes from 0 to 1.0 again
+    last.store(0, Ordering::SeqCst);
+    jpeg.decode_pixels().unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 40);
+}
+
+#[test]
+fn scans_need_a_frame_first() {
+    let start = marker_offset(SOLID, 0xC0);
+    let length = u16::from_be_bytes([SOLID[start + 2], SOLID[start + 3]]) as usize;
+
+    let without_frame = [&SOLID[..start], &SOLID[start + 2 + length..]].concat();
+
+    assert_eq!(
+        JPEGHeader::from_bytes(&without_frame),
+        Err(Error::InvalidSOSMarker(SOSError::MissingSOFBeforeSOS))
+    );
+}
+
+/// Puts `thumbnail` in a JFXX segment right after the JFIF segment of `jpeg`
+fn with_jpeg_thumbnail(jpeg: &[u8], thumbnail: &[u8]) -> Vec<u8> {
+    let jfif_end = 4 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+
+    let mut extension = vec![0xFF, 0xE0];
+    extension.extend_from_slice(&(thumbnail.len() as u16 + 8).to_be_bytes());
+    extension.extend_from_slice(b"JFXX\0\x10");
+    extension.extend_from_slice(thumbnail);
+
+    [&jpeg[..jfif_end], &extension, &jpeg[jfif_end..]].concat()
+}
+
+#[test]
+fn jfif_extension_thumbnails_are_parsed_one_level_deep() {
+    let data = with_jpeg_thumbnail(GRADIENT, SOLID);
+    let jpeg = JPEGHeader::from_bytes(&data).unwrap();
+
+    assert_eq!(
+        jpeg.thumbnail_jpeg(),
+        Some(JPEGHeader::from_bytes(SOLID).unwrap())
+    );
+    assert_eq!(
+        JPEGHeader::from_bytes(&jpeg.to_bytes().unwrap()).unwrap(),
+        jpeg
+    );
+
+    let nested = with_jpeg_thumbnail(GRADIENT, &data);
+    let thumbnail = JPEGHeader::from_bytes(&nested)
+        .unwrap()
+        .thumbnail_jpeg()
+        .unwrap();
+
+    assert_eq!(thumbnail.thumbnail_jpeg(), None);
+    assert_eq!(
+        JPEGHeader::from_bytes(GRADIENT).unwrap().thumbnail_jpeg(),
+        None
+    );
+}
+
+#[test]
+fn qtables_order_by_quality_score() {
+    let table: [u16; 64] = std::array::from_fn(|i| i as u16 + 1);
+    let original = QTable::new(table);
+    let doubled = QTable::new(table.map(|entry| entry * 2));
+
+    assert_eq!(original.quality_score(), 64 * 65 / 2);
+    assert_eq!(doubled.quality_score(), 2 * original.quality_score());
+    assert!(doubled > original);
+
+    // Same score, different entries
+    let mut swapped = table;
+    swapped.swap(0, 63);
+    let swapped = QTable::new(swapped);
+    assert_eq!(swapped.quality_score(), original.quality_score());
+    assert_ne!(swapped.cmp(&original), std::cmp::Ordering::Equal);
+
+    for (a, b) in [
+        (original, doubled),
+        (doubled, original),
+        (original, original),
+    ] {
+        assert_eq!(a.partial_cmp(&b), Some(a.cmp(&b)));
+        assert_eq!(a.cmp(&b) == std::cmp::Ordering::Equal, a == b);
+    }
+}
+
+#[test]
+fn lenient_parsing_reports_recoverable_errors() {
+    let sof = marker_offset(SOLID, 0xC0);
+
+    // A stray restart marker and a reserved marker before the frame
+    let data = [&SOLID[..sof], &[0xFF, 0xD3, 0xFF, 0x02], &SOLID[sof..]].concat();
+
+    assert_eq!(
+        JPEGHeader::from_bytes(&data),
+        Err(Error::RestartMarkerBeforeSOS)
+    );
+
+    let errors = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let options = {
+        let errors = Arc::clone(&errors);
+
+        DecodeOptions::default()
+            .lenient(true)
+            .on_recoverable_error(move |error| errors.lock().unwrap().push(error))
+    };
+
+    let mut jpeg = JPEGHeader::with_options(&data, options.clone()).unwrap();
+    let mut expected = JPEGHeader::from_bytes(SOLID).unwrap();
+    assert_eq!(jpeg.decode_pixels(), expected.decode_pixels());
+
+    // Offset of the 0xFF before the code
+    let offset = sof + 2;
+    assert_eq!(
+        *errors.lock().unwrap(),
+        [
+            Error::RestartMarkerBeforeSOS,
+            Error::UnknownMarker { byte: 0x02, offset }
+        ]
+    );
+    assert!(errors.lock().unwrap().iter().all(Error::is_recoverable));
+
+    // Structural errors still fail
+    let error = JPEGHeader::with_options(&SOLID[..SOLID.len() / 2], options).unwrap_err();
+    assert!(!error.is_recoverable());
+}
+
+#[test]
+fn size_estimates_follow_the_quality() {
+    let pixels = JPEGHeader::from_bytes(GRADIENT)
+        .unwrap()
+        .decode_pixels()
+        .unwrap();
+
+    let mut encoded = Vec::new();
+    Encoder::new(50)
+        .encode(&pixels, 64, 48, 3, &mut encoded)
+        .unwrap();
+    let jpeg = JPEGHeader::from_bytes(&encoded).unwrap();
+
+    // Same tables as the file already has, so little should change
+    let same = analyze::estimate_file_size_at_quality(&jpeg, 50).unwrap();
+    assert!(same.abs_diff(encoded.len()) * 20 < encoded.len());
+
+    let sizes =
+        [10, 30, 50].map(|quality| analyze::estimate_file_size_at_quality(&jpeg, quality).unwrap());
+    assert!(sizes.windows(2).all(|pair| pair[0] <= pair[1]));
+}
+
+#[test]
+fn block_colors_match_the_decoded_blocks() {
+    let mut jpeg = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    let pixels = jpeg.decode_pixels().unwrap();
+    let colors = jpeg.block_colors().unwrap();
+
+    assert_eq!(colors.len(), 8 * 6);
+
+    for (i, (r, g, b)) in colors.into_iter().enumerate() {
+        let mut sum = [0u32; 3];
+
+        for y in 0..8 {
+            for x in 0..8 {
+                let offset = 3 * ((i / 8 * 8 + y) * 64 + i % 8 * 8 + x);
+
+                for (channel, total) in sum.iter_mut().enumerate() {
+                    *total += pixels[offset + channel] as u32;
+                }
+            }
+        }
+
+        let average = sum.map(|total| (total as f32 / 64.0).round() as i32);
+
+        for (expected, actual) in average.into_iter().zip([r, g, b]) {
+            assert!((expected - actual as i32).abs() <= 2, "block {i}");
+        }
+    }
+}
+
+fn crop(pixels: &[u8], width: usize, region: Rect) -> Vec<u8> {
+    let (x, y) = (region.x as usize, region.y as usize);
+
+    (y..y + region.height as usize)
+        .flat_map(|row| {
+            let start = (row * width + x) * 3;
+            pixels[start..start + region.width as usize * 3]
+                .iter()
+                .copied()
+        })
+        .collect()
+}
+
+#[test]
+fn partial_decodes_match_crops_of_the_full_image() {
+    for scale_factor in [ScaleFactor::Full, ScaleFactor::Half] {
+        let options = DecodeOptions::default().scale_factor(scale_factor);
+        let mut jpeg = JPEGHeader::with_options(GRADIENT, options).unwrap();
+        let (width, height) = jpeg.output_dimensions();
+        let pixels = jpeg.decode_pixels().unwrap();
+
+        let regions = [
+            Rect::new(0, 0, width as u16, height as u16),
+            Rect::new(3, 5, 10, 7),
+            Rect::new(width as u16 - 9, height as u16 - 4, 9, 4),
+            Rect::new(8, 0, 1, height as u16),
+            Rect::new(4, 4, 0, 0),
+        ];
+
+        for region in regions {
+            let partial = jpeg.decode_partial(region).unwrap();
+            assert_eq!(partial, crop(&pixels, width, region), "{region:?}");
+        }
+
+        let region = Rect::new(width as u16 - 2, 0, 4, 4);
+        assert_eq!(jpeg.decode_partial(region), Err(Error::InvalidRegion));
+    }
+}
+
+#[test]
+fn metadata_gathers_every_source() {
+    let jpeg = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    let bytes = jpeg.splice_comment("say \"hi\"\n\\").unwrap();
+    let jpeg = JPEGHeader::from_bytes(&bytes).unwrap();
+    let metadata = jpeg.metadata();
+
+    assert_eq!((metadata.width, metadata.height), (64, 48));
+    assert_eq!(metadata.color_space, ColorSpace::YCbCr);
+    assert_eq!(metadata.quality_estimate, jpeg.quality_estimate());
+    assert_eq!(metadata.comments, jpeg.comments());
+    assert_eq!(metadata.xmp.as_deref(), jpeg.xmp_bytes());
+
+    let jfif = metadata.jfif.as_ref().unwrap();
+    assert_eq!(jfif.version, (1, 2));
+    assert_eq!(jfif.pixel_aspect_ratio, jpeg.pixel_aspect_ratio());
+    assert_eq!(jfif.dpi, None);
+    assert_eq!(jpeg.physical_size_mm(), None);
+
+    assert_eq!(
+        metadata.to_json_lossy(),
+        "{\"width\":64,\"height\":48,\"color_space\":\"YCbCr\",\"quality_estimate\":90,\
+         \"jfif\":{\"version\":\"1.02\",\"dpi\":null,\"pixel_aspect_ratio\":1,\
+         \"thumbnail\":null,\"jpeg_thumbnail_bytes\":null},\"adobe_color_transform\":null,\
+         \"comments\":[\"say \\\"hi\\\"\\n\\\\\"],\"xmp_bytes\":null}"
+    );
+
+    let summary = metadata.to_string();
+    assert!(summary.starts_with("Dimensions: 64x48\nColor space: YCbCr\n"));
+    assert!(summary.contains("JFIF version: 1.02\n"));
+}
+
+/// The entropy coded bytes between the SOS segment and EOI
+fn scan_data(bytes: &[u8]) -> Vec<u8> {
+    let sos = marker_offset(bytes, 0xDA);
+    let length = u16::from_be_bytes([bytes[sos + 2], bytes[sos + 3]]) as usize;
+
+    bytes[sos + 2 + length..bytes.len() - 2].to_vec()
+}
+
+#[test]
+fn scan_data_can_be_replaced() {
+    let mut rotated = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    rotated.rotate_lossless(Rotation::Degrees180).unwrap();
+    let expected = rotated.decode_pixels().unwrap();
+
+    // The rotation keeps the dimensions and the tables
+    let mut jpeg = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    jpeg.replace_scan_data(scan_data(&rotated.to_bytes().unwrap()))
+        .unwrap();
+    assert_eq!(jpeg.decode_pixels().unwrap(), expected);
+    assert_eq!(jpeg.to_bytes().unwrap(), rotated.to_bytes().unwrap());
+
+    let data = scan_data(GRADIENT);
+
+    for bad in [
+        Vec::new(),
+        data[..10].to_vec(),
+        [&data[..], &[0xFF, 0xD9, 0x00]].concat(),
+    ] {
+        assert_eq!(jpeg.replace_scan_data(bad), Err(Error::InvalidScanData));
+    }
+
+    // Long enough, but not coded with the tables
+    assert!(matches!(
+        jpeg.replace_scan_data(vec![0xA5; 4000]),
+        Err(Error::HuffmanDecode(_))
+    ));
+
+    assert_eq!(jpeg.decode_pixels().unwrap(), expected);
+}
+
+#[cfg(feature = "image-interop")]
+#[test]
+fn headers_convert_into_image_buffers() {
+    let mut jpeg = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    let pixels = jpeg.decode_pixels().unwrap();
+
+    let image = image::RgbImage::try_from(jpeg.clone()).unwrap();
+    assert_eq!(image.dimensions(), (64, 48));
+    assert_eq!(image.into_raw(), pixels);
+
+    let image = image::DynamicImage::try_from(jpeg).unwrap();
+    assert_eq!(image.into_bytes(), pixels);
+}
+
+#[cfg(feature = "png-output")]
+#[test]
+fn png_output_decodes_to_the_same_pixels() {
+    for data in [SOLID, GRADIENT] {
+        let mut jpeg = JPEGHeader::from_bytes(data).unwrap();
+        let pixels = jpeg.decode_pixels().unwrap();
+        let (width, height) = jpeg.output_dimensions();
+
+        let png = jpeg.to_png_bytes().unwrap();
+        let mut reader = png::Decoder::new(std::io::Cursor::new(png))
+            .read_info()
+            .unwrap();
+        let mut decoded = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut decoded).unwrap();
+
+        assert_eq!((info.width, info.height), (width as u32, height as u32));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(info.bit_depth, png::BitDepth::Eight);
+        assert_eq!(decoded, pixels);
+    }
+}
+
+#[test]
+fn dc_jumps_between_neighbours_are_hinted() {
+    let mut jpeg = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    assert!(jpeg.detect_corruption().unwrap().is_empty());
+
+    // Black and white blocks side by side, with a quantization step of 1
+    let pixels: Vec<u8> = (0..8 * 16)
+        .map(|i| if i % 16 < 8 { 0 } else { 255 })
+        .collect();
+    let mut data = Vec::new();
+    Encoder::new(100)
+        .encode(&pixels, 16, 8, 1, &mut data)
+        .unwrap();
+
+    let hints = JPEGHeader::new(data).unwrap().detect_corruption().unwrap();
+
+    // -1024 for black and 1016 for white
+    assert_eq!(
+        hints,
+        [CorruptionHint {
+            mcu_index: 1,
+            reason: CorruptionReason::DCJump { delta: 2040 },
+        }]
+    );
+}
+
+#[test]
+fn summary_lists_the_parsed_segments() {
+    let summary = JPEGHeader::from_bytes(GRADIENT).unwrap().summary();
+    let lines: Vec<&str> = summary.lines().collect();
+
+    assert_eq!(lines[0], "Dimensions:       64x48");
+    assert!(lines.contains(&"JFIF version:     1.02"));
+    assert!(lines.contains(&"Density:          1x1 no-unit"));
+    assert!(lines.contains(&"     2      1x1      1   1   1"));
+    assert!(lines.contains(&"  AC 0: 162 symbols"));
+
+    // Two 8x8 quantization tables
+    let grid_rows = lines
+        .iter()
+        .filter(|line| line.split_whitespace().count() == 8);
+    assert_eq!(grid_rows.count(), 16);
+}
+
+#[test]
+fn chroma_subsampling_ratios() {
+    let mut bits = [0; 16];
+    bits[0] = 1;
+
+    // Parsed back from the built bytes, so the scan has to match the layout
+    let ratio = |luma: (u8, u8), chroma: (u8, u8)| {
+        let header = JPEGHeaderBuilder::new()
+            .with_dimensions(32, 32)
+            .with_qtable(0, QTable::new([1; 64]))
+            .with_huffman_table_dc(0, bits, &[0])
+            .with_huffman_table_ac(0, bits, &[0])
+            .with_component(1, luma, 0, 0, 0)
+            .with_component(2, chroma, 0, 0, 0)
+            .with_component(3, chroma, 0, 0, 0)
+            .build()
+            .unwrap();
+
+        JPEGHeader::from_bytes(&header.to_bytes().unwrap())
+            .unwrap()
+            .chroma_subsampling_ratio()
+    };
+
+    assert_eq!(ratio((1, 1), (1, 1)), Some((4, 4)));
+    assert_eq!(ratio((2, 1), (1, 1)), Some((4, 2)));
+    assert_eq!(ratio((2, 2), (1, 1)), Some((4, 0)));
+    // 4:1:1
+    assert_eq!(ratio((4, 1), (1, 1)), None);
+    assert_eq!(ratio((3, 2), (2, 1)), None);
+
+    let jpeg = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    assert_eq!(jpeg.chroma_subsampling_ratio(), Some((4, 4)));
+
+    let gray = JPEGHeaderBuilder::new()
+        .with_dimensions(8, 8)
+        .with_qtable(0, QTable::new([1; 64]))
+        .with_huffman_table_dc(0, bits, &[0])
+        .with_huffman_table_ac(0, bits, &[0])
+        .with_component(1, (1, 1), 0, 0, 0)
+        .build()
+        .unwrap();
+    assert_eq!(gray.chroma_subsampling_ratio(), None);
+}
+
+/// Rewrites every 8 bit DQT segment with 16 bit entries
+fn with_extended_qtables(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut offset = 0;
+
+    while let Some(position) = data[offset..]
+        .windows(2)
+        .position(|pair| pair == [0xFF, 0xDB])
+    {
+        let start = offset + position;
+        let length = u16::from_be_bytes([data[start + 2], data[start + 3]]) as usize;
+        let mut payload = Vec::new();
+
+        for table in data[start + 4..start + 2 + length].chunks_exact(65) {
+            payload.push(0x10 | table[0]);
+            payload.extend(table[1..].iter().flat_map(|step| [0, *step]));
+        }
+
+        bytes.extend_from_slice(&data[offset..start]);
+        bytes.extend_from_slice(&[0xFF, 0xDB]);
+        bytes.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        bytes.extend_from_slice(&payload);
+        offset = start + 2 + length;
+    }
+
+    bytes.extend_from_slice(&data[offset..]);
+    bytes
+}
+
+#[test]
+fn extended_qtables_are_reported() {
+    let mut jpeg = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    assert_eq!(jpeg.precision(), 8);
+    assert!(!jpeg.has_extended_qtables());
+
+    let mut extended = JPEGHeader::new(with_extended_qtables(GRADIENT)).unwrap();
+    assert_eq!(extended.precision(), 8);
+    assert!(extended.has_extended_qtables());
+
+    // Same steps, so the same pixels
+    assert_eq!(extended.decode_pixels(), jpeg.decode_pixels());
+}
+
+/// Moves the tables of every DHT segment into the first one
+fn with_merged_dht_segments(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut tables = Vec::new();
+    let mut first = None;
+    let mut offset = 0;
+
+    while let Some(position) = data[offset..]
+        .windows(2)
+        .position(|pair| pair == [0xFF, 0xC4])
+    {
+        let start = offset + position;
+        let length = u16::from_be_bytes([data[start + 2], data[start + 3]]) as usize;
+
+        bytes.extend_from_slice(&data[offset..start]);
+        first.get_or_insert(bytes.len());
+        tables.extend_from_slice(&data[start + 4..start + 2 + length]);
+        offset = start + 2 + length;
+    }
+
+    bytes.extend_from_slice(&data[offset..]);
+
+    let mut segment = vec![0xFF, 0xC4];
+    segment.extend_from_slice(&(tables.len() as u16 + 2).to_be_bytes());
+    segment.extend_from_slice(&tables);
+
+    let first = first.unwrap();
+    bytes.splice(first..first, segment);
+    bytes
+}
+
+#[test]
+fn dht_segments_can_hold_dc_and_ac_tables() {
+    let merged = with_merged_dht_segments(SOLID);
+    let markers = JPEGDiagnostics::dump_markers(&merged);
+    assert_eq!(markers.iter().filter(|info| info.marker == 0xC4).count(), 1);
+
+    let mut jpeg = JPEGHeader::new(merged).unwrap();
+    let mut expected = JPEGHeader::from_bytes(SOLID).unwrap();
+    assert_eq!(jpeg.decode_pixels(), expected.decode_pixels());
+
+    // An empty segment sets nothing, even after tables were set
+    let sos = marker_offset(SOLID, 0xDA);
+    let data = [&SOLID[..sos], &[0xFF, 0xC4, 0x00, 0x02], &SOLID[sos..]].concat();
+
+    assert_eq!(
+        JPEGHeader::from_bytes(&data),
+        Err(Error::InvalidDHTMarker(DHTError::NoTableSet))
+    );
+}
+
+#[test]
+fn oversized_huffman_tables_are_rejected() {
+    let invalid = Err(Error::InvalidDHTMarker(DHTError::InvalidSymbolsLength));
+    let build = |bits: [u8; 16], values: &[u8]| {
+        JPEGHeaderBuilder::new()
+            .with_dimensions(8, 8)
+            .with_qtable(0, QTable::new([1; 64]))
+            .with_huffman_table_dc(0, bits, values)
+            .with_huffman_table_ac(0, bits, values)
+            .with_component(1, (1, 1), 0, 0, 0)
+            .build()
+            .map(|_| ())
+    };
+
+    let mut bits = [0; 16];
+    bits[15] = 200;
+    assert_eq!(build(bits, &[0; 200]), invalid);
+
+    // Counts that don't match the values, or overflow a byte when summed
+    bits[15] = 3;
+    assert_eq!(build(bits, &[0, 1]), invalid);
+    assert_eq!(build([255; 16], &[0; 162]), invalid);
+
+    // The same counts in a DHT segment
+    let mut dht = vec![0xFF, 0xC4, 0x00, 0x13, 0x00];
+    dht.extend_from_slice(&[0xFF; 16]);
+    let data = with_segment_after(SOLID, Some(0xC4), &dht);
+    assert_eq!(JPEGHeader::from_bytes(&data).map(|_| ()), invalid);
+}
+
+#[test]
+fn dc_planes_hold_the_block_averages() {
+    let mut jpeg = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    let planes = jpeg.decode_dc_plane().unwrap();
+
+    assert_eq!(planes.len(), 3);
+    assert!(planes.iter().all(|plane| plane.len() == 8 * 6));
+
+    // Flat gray blocks at a quantization step of 1
+    let mut data = Vec::new();
+    Encoder::new(100)
+        .encode(&[200; 16 * 8], 16, 8, 1, &mut data)
+        .unwrap();
+
+    let planes = JPEGHeader::new(data).unwrap().decode_dc_plane().unwrap();
+    assert_eq!(planes, [[8 * (200 - 128); 2]]);
+
+    // 4:2:0 at 24x24 codes 4x4 luma blocks, but only 3x3 and 2x2 chroma
+    // cover the image
+    let mut bits = [0; 16];
+    bits[0] = 1;
+
+    let header = JPEGHeaderBuilder::new()
+        .with_dimensions(24, 24)
+        .with_qtable(0, QTable::new([1; 64]))
+        .with_huffman_table_dc(0, bits, &[0])
+        .with_huffman_table_ac(0, bits, &[0])
+        .with_component(1, (2, 2), 0, 0, 0)
+        .with_component(2, (1, 1), 0, 0, 0)
+        .with_component(3, (1, 1), 0, 0, 0)
+        .build()
+        .unwrap();
+
+    let mut jpeg = JPEGHeader::from_bytes(&header.to_bytes().unwrap()).unwrap();
+    let planes = jpeg.decode_dc_plane().unwrap();
+    let sizes: Vec<usize> = planes.iter().map(Vec::len).collect();
+    assert_eq!(sizes, [3 * 3, 2 * 2, 2 * 2]);
+}
+
+fn parse_with_warnings(
+    data: &[u8],
+    options: DecodeOptions,
+) -> (Result<JPEGHeader, Error>, Vec<Warning>) {
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&warnings);
+    let options = options.warning_handler(move |warning| sink.lock().unwrap().push(warning));
+
+    let result = JPEGHeader::with_options(data, options);
+    let warnings = warnings.lock().unwrap().clone();
+
+    (result, warnings)
+}
+
+#[test]
+fn parse_anomalies_are_reported_as_warnings() {
+    let (result, warnings) = parse_with_warnings(GRADIENT, DecodeOptions::default());
+    assert!(result.is_ok());
+    assert!(warnings.is_empty());
+
+    // A second JFIF segment and an ICC profile segment after the first
+    let app0_end = 4 + u16::from_be_bytes([GRADIENT[4], GRADIENT[5]]) as usize;
+    let app0 = &GRADIENT[2..app0_end];
+    let app2 = [0xFF, 0xE2, 0x00, 0x04, 0xAB, 0xCD];
+    let data = [&GRADIENT[..app0_end], app0, &app2, &GRADIENT[app0_end..]].concat();
+
+    let (result, warnings) = parse_with_warnings(&data, DecodeOptions::default());
+    assert_eq!(
+        result.unwrap().decode_pixels(),
+        JPEGHeader::from_bytes(GRADIENT).unwrap().decode_pixels()
+    );
+    assert_eq!(
+        warnings,
+        [
+            Warning::MultipleJFIFSegments,
+            Warning::UnknownAPPnSkipped { marker: 0xE2 }
+        ]
+    );
+
+    // Without its EOI the scan is only accepted when lenient
+    let data = &GRADIENT[..GRADIENT.len() - 2];
+    assert_eq!(JPEGHeader::from_bytes(data), Err(Error::PrematureEnd));
+
+    let (result, warnings) = parse_with_warnings(data, DecodeOptions::default().lenient(true));
+    assert!(result.is_ok());
+    assert_eq!(warnings, [Warning::TruncatedHuffmanData]);
+}
+
+#[test]
+fn huffman_tables_export_to_dht_segments() {
+    let jpeg = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    let tables = jpeg.export_huffman_tables();
+
+    assert!(tables.dc[..2]
+        .iter()
+        .chain(&tables.ac[..2])
+        .all(|table| table.is_set()));
+    assert!(!tables.dc[2].is_set());
+    assert_eq!(tables.ac[0].offsets()[16], 162);
+    assert_eq!(tables.ac[0].symbols().len(), 162);
+    assert_eq!(tables.ac[0].codes()[..2], [0b00, 0b01]);
+
+    let mut segments = Vec::new();
+    tables.write_dht_segments(&mut segments).unwrap();
+
+    // The same segments as the serialized header, back to back
+    let bytes = jpeg.to_bytes().unwrap();
+    let start = marker_offset(&bytes, 0xC4);
+    assert_eq!(&bytes[start..start + segments.len()], segments);
+
+    let dht = JPEGDiagnostics::dump_markers(&segments);
+    assert_eq!(dht.len(), 4);
+    assert!(dht.iter().all(|info| info.marker == 0xC4));
+}
+
+fn with_segment_after(data: &[u8], marker: Option<u8>, segment: &[u8]) -> Vec<u8> {
+    let at = match marker {
+        Some(marker) => {
+            let start = marker_offset(data, marker);
+            start + 2 + u16::from_be_bytes([data[start + 2], data[start + 3]]) as usize
+        }
+        // Right after SOI
+        None => 2,
+    };
+
+    [&data[..at], segment, &data[at..]].concat()
+}
+
+fn app1_segment(payload: &[u8]) -> Vec<u8> {
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+    segment.extend_from_slice(payload);
+
+    segment
+}
+
+#[test]
+fn xmp_segments_round_trip() {
+    let xml = b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF/></x:xmpmeta>";
+    let segment = app1_segment(&[&b"http://ns.adobe.com/xap/1.0/\0"[..], xml].concat());
+    let data = with_segment_after(GRADIENT, Some(0xE0), &segment);
+
+    let jpeg = JPEGHeader::from_bytes(&data).unwrap();
+    assert_eq!(jpeg.xmp_bytes(), Some(&xml[..]));
+    #[cfg(feature = "xmp")]
+    assert_eq!(
+        jpeg.xmp_document().as_deref(),
+        std::str::from_utf8(xml).ok()
+    );
+
+    // Written back as the same segment
+    let written = jpeg.to_bytes().unwrap();
+    assert!(written.windows(segment.len()).any(|bytes| bytes == segment));
+    assert_eq!(
+        JPEGHeader::from_bytes(&written).unwrap().xmp_bytes(),
+        Some(&xml[..])
+    );
+}
+
+#[test]
+fn exif_segments_are_skipped_with_a_warning() {
+    let segment = app1_segment(b"Exif\0\0MM\0\x2A\0\0\0\x08\0\0");
+    let data = with_segment_after(GRADIENT, Some(0xE0), &segment);
+
+    let (result, warnings) = parse_with_warnings(&data, DecodeOptions::default());
+    let mut jpeg = result.unwrap();
+    assert_eq!(warnings, [Warning::ExifSkipped]);
+    assert_eq!(
+        jpeg.decode_pixels(),
+        JPEGHeader::from_bytes(GRADIENT).unwrap().decode_pixels()
+    );
+}
+
+#[test]
+fn quick_parse_restart_interval() {
+    assert_eq!(quick_parse::parse_restart_interval(GRADIENT), Ok(None));
+
+    let dri = [0xFF, 0xDD, 0x00, 0x04, 0x01, 0x02];
+
+    // Before the first DQT
+    let data = with_segment_after(GRADIENT, None, &dri);
+    assert_eq!(quick_parse::parse_restart_interval(&data), Ok(Some(0x0102)));
+    assert!(JPEGHeader::from_bytes(&data).is_ok());
+
+    // After the frame header
+    let data = with_segment_after(GRADIENT, Some(0xC0), &dri);
+    assert_eq!(quick_parse::parse_restart_interval(&data), Ok(Some(0x0102)));
+
+    let data = with_segment_after(GRADIENT, Some(0xC0), &[0xFF, 0xDD, 0x00, 0x03, 0x01]);
+    assert_eq!(
+        quick_parse::parse_restart_interval(&data),
+        Err(Error::InvalidRestartIntervalMarker)
+    );
+
+    assert_eq!(
+        quick_parse::parse_restart_interval(&GRADIENT[2..]),
+        Err(Error::StartOfImageNotFound)
+    );
+}
+
+#[test]
+fn components_must_use_a_defined_qtable() {
+    let start = marker_offset(GRADIENT, 0xC0);
+
+    // First component's QTable selector, no DQT defines table 2
+    let mut data = GRADIENT.to_vec();
+    data[start + 12] = 2;
+
+    let error = JPEGHeader::from_bytes(&data).unwrap_err();
+    assert_eq!(
+        error,
+        // The stream numbers its components from 0, the header from 1
+        Error::ComponentQTableMismatch {
+            component_id: 1,
+            qtable_id: 2
+        }
+    );
+    assert!(error.to_string().contains("uses QTable 2"));
+}
+
+fn icc_segment(index: u8, total: u8, chunk: &[u8]) -> Vec<u8> {
+    let length = (2 + 12 + 2 + chunk.len()) as u16;
+
+    let mut segment = vec![0xFF, 0xE2];
+    segment.extend_from_slice(&length.to_be_bytes());
+    segment.extend_from_slice(b"ICC_PROFILE\0");
+    segment.extend_from_slice(&[index, total]);
+    segment.extend_from_slice(chunk);
+    segment
+}
+
+#[test]
+fn icc_profile_chunks_are_joined() {
+    assert_eq!(
+        JPEGHeader::from_bytes(GRADIENT)
+            .unwrap()
+            .extract_icc_profile(),
+        None
+    );
+
+    let profile: Vec<u8> = (0..=255).collect();
+    let (first, second) = profile.split_at(100);
+
+    // The second chunk comes first
+    let segments = [icc_segment(2, 2, second), icc_segment(1, 2, first)].concat();
+    let data = with_segment_after(GRADIENT, None, &segments);
+
+    let header = JPEGHeader::from_bytes(&data).unwrap();
+    assert_eq!(header.extract_icc_profile(), Some(&profile[..]));
+
+    let header = JPEGHeader::new(header.to_bytes().unwrap()).unwrap();
+    assert_eq!(header.extract_icc_profile(), Some(&profile[..]));
+
+    // Chunk 2 of 2 is missing
+    let data = with_segment_after(GRADIENT, None, &icc_segment(1, 2, first));
+    let (result, warnings) = parse_with_warnings(&data, DecodeOptions::default());
+
+    assert_eq!(result.unwrap().extract_icc_profile(), None);
+    assert_eq!(warnings, [Warning::IncompleteICCProfile]);
+}
+
+#[test]
+fn fill_bytes_at_the_end_are_a_premature_end() {
+    for fill in [1, 2, 64] {
+        let data = [&[0xFF, 0xD8][..], &vec![0xFF; fill]].concat();
+
+        assert_eq!(JPEGHeader::from_bytes(&data), Err(Error::PrematureEnd));
+    }
+
+    // After complete segments
+    let sof = marker_offset(GRADIENT, 0xC0);
+    let data = [&GRADIENT[..sof], &[0xFF; 8]].concat();
+
+    assert_eq!(JPEGHeader::from_bytes(&data), Err(Error::PrematureEnd));
+}
+
+#[test]
+fn tables_can_be_compared_without_decoding() {
+    let mut header = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    let pixels = header.decode_pixels().unwrap();
+
+    let dc: Vec<usize> = header.huffman_tables_dc().map(|(id, _)| id).collect();
+    let ac: Vec<usize> = header.huffman_tables_ac().map(|(id, _)| id).collect();
+    assert_eq!((dc, ac), (vec![0, 1], vec![0, 1]));
+
+    let score = |quality: u8| {
+        let mut bytes = Vec::new();
+        Encoder::new(quality)
+            .encode(&pixels, 64, 48, 3, &mut bytes)
+            .unwrap();
+
+        let header = JPEGHeader::new(bytes).unwrap();
+        let tables: Vec<(usize, &QTable)> = header.quantization_tables().collect();
+        assert_eq!(tables.len(), 2);
+
+        tables
+            .iter()
+            .map(|(_, table)| table.quality_score())
+            .sum::<u64>()
+    };
+
+    assert!(score(90) < score(50));
+}
+
+#[test]
+fn decoding_to_a_writer_streams_the_pixels() {
+    let mut header = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    let pixels = header.decode_pixels().unwrap();
+
+    let mut raw = Vec::new();
+    header
+        .decode_to_writer(&mut raw, OutputFormat::Rgb8Raw)
+        .unwrap();
+    assert_eq!(raw, pixels);
+
+    let mut ppm = Vec::new();
+    header
+        .decode_to_writer(&mut ppm, OutputFormat::Ppm)
+        .unwrap();
+    assert_eq!(ppm, [&b"P6\n64 48\n255\n"[..], &pixels].concat());
+
+    // Gray images keep their samples
+    let mut header = JPEGHeader::from_bytes(GRAYSCALE).unwrap();
+    let gray: Vec<u8> = header
+        .decode_pixels()
+        .unwrap()
+        .into_iter()
+        .step_by(3)
+        .collect();
+
+    let mut pgm = Vec::new();
+    header
+        .decode_to_writer(&mut pgm, OutputFormat::Pgm)
+        .unwrap();
+    assert_eq!(pgm, [&b"P5\n32 32\n255\n"[..], &gray].concat());
+
+    // RGB coded images go through the luma weights
+    let mut pgm = Vec::new();
+    JPEGHeader::from_bytes(RGB_NO_APP0)
+        .unwrap()
+        .decode_to_writer(&mut pgm, OutputFormat::Pgm)
+        .unwrap();
+    assert_eq!(pgm[b"P5\n16 16\n255\n".len()], 125);
+}
+
+#[test]
+fn partial_mcus_round_trip_through_the_encoder() {
+    // One pixel past a whole MCU each way, so the last column and row of
+    // MCUs are a single pixel wide. The edge gets its own color
+    let size = 17u16;
+    let edge = [220, 40, 40];
+    let inside = [60, 120, 180];
+
+    let pixels: Vec<u8> = (0..size * size)
+        .flat_map(|i| match (i % size, i / size) {
+            (16, _) | (_, 16) => edge,
+            _ => inside,
+        })
+        .collect();
+
+    let mut data = Vec::new();
+    Encoder::new(100)
+        .encode(&pixels, size, size, 3, &mut data)
+        .unwrap();
+
+    let mut header = JPEGHeader::new(data).unwrap();
+    assert_eq!(header.output_dimensions(), (17, 17));
+
+    let decoded = header.decode_pixels().unwrap();
+    assert_eq!(decoded.len(), 17 * 17 * 3);
+
+    for i in 0..17 {
+        for (x, y) in [(16, i), (i, 16)] {
+            let offset = (y * 17 + x) * 3;
+            let pixel = &decoded[offset..offset + 3];
+
+            for (&actual, expected) in pixel.iter().zip(edge) {
+                assert!(
+                    actual.abs_diff(expected) <= 8,
+                    "({x}, {y}) is {pixel:?}, expected {edge:?}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn rgb_pixels_round_trip_through_the_encoder() {
+    let (width, height) = (20u16, 12u16);
+    let pixels: Vec<[u8; 3]> = (0..width * height)
+        .map(|i| {
+            let (x, y) = ((i % width) as u8, (i / width) as u8);
+            [40 + 8 * x, 60 + 10 * y, 200 - 4 * x]
+        })
+        .collect();
+
+    let data = encode_rgb(&pixels, width, height).unwrap();
+
+    let markers = MarkerScanner::new(&data)
+        .map(|item| item.map(|(marker, _)| marker))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        markers,
+        [
+            Marker::SOI,
+            Marker::APP0,
+            Marker::DQT,
+            Marker::DQT,
+            Marker::SOF0,
+            Marker::DHT,
+            Marker::DHT,
+            Marker::DHT,
+            Marker::DHT,
+            Marker::SOS,
+            Marker::EOI,
+        ]
+    );
+
+    let mut header = JPEGHeader::new(data).unwrap();
+    let metadata = header.metadata();
+    assert_eq!((metadata.width, metadata.height), (width, height));
+    assert_eq!(metadata.color_space, ColorSpace::YCbCr);
+    assert_eq!(metadata.quality_estimate, Some(50));
+
+    let decoded = header.decode_pixels().unwrap();
+
+    for (pixel, expected) in decoded.chunks_exact(3).zip(&pixels) {
+        for (&actual, &expected) in pixel.iter().zip(expected) {
+            assert!(actual.abs_diff(expected) <= 12, "{pixel:?} != {expected:?}");
+        }
+    }
+
+    // The pixel count has to match the dimensions
+    assert!(encode_rgb(&pixels[1..], width, height).is_err());
+}
+
+#[test]
+fn huffman_errors_keep_their_source() {
+    // Four bytes of scan data run out inside the first MCU
+    let sos = marker_offset(GRADIENT, 0xDA);
+    let sos_len = u16::from_be_bytes([GRADIENT[sos + 2], GRADIENT[sos + 3]]) as usize;
+    let data = [&GRADIENT[..sos + 2 + sos_len + 4], &[0xFF, 0xD9]].concat();
+
+    let error = JPEGHeader::from_bytes(&data).unwrap_err();
+    let Error::HuffmanDecode(source) = error else {
+        panic!("expected a Huffman decoding error, got {error:?}");
+    };
+
+    let error = anyhow::Error::from(error);
+    let messages: Vec<String> = error.chain().map(ToString::to_string).collect();
+
+    assert_eq!(
+        messages,
+        [
+            format!("JPEG Error: The scan data could not be Huffman decoded. {source}"),
+            source.to_string(),
+        ]
+    );
+}
+
+#[test]
+fn truncated_scans_name_the_mcu() {
+    // Every gray component is a one bit DC code and a one bit end of block,
+    // so each MCU takes exactly 6 bits
+    let mut bits = [0; 16];
+    bits[0] = 1;
+
+    let header = JPEGHeaderBuilder::new()
+        .with_dimensions(64, 48)
+        .with_qtable(0, QTable::new([1; 64]))
+        .with_huffman_table_dc(0, bits, &[0])
+        .with_huffman_table_ac(0, bits, &[0])
+        .with_component(1, (1, 1), 0, 0, 0)
+        .with_component(2, (1, 1), 0, 0, 0)
+        .with_component(3, (1, 1), 0, 0, 0)
+        .build()
+        .unwrap();
+    let bytes = header.to_bytes().unwrap();
+
+    // 10 bytes are 13 whole MCUs and the first component of the 14th
+    let sos = marker_offset(&bytes, 0xDA);
+    let sos_len = u16::from_be_bytes([bytes[sos + 2], bytes[sos + 3]]) as usize;
+    let data = [&bytes[..sos + 2 + sos_len + 10], &[0xFF, 0xD9]].concat();
+
+    assert_eq!(
+        JPEGHeader::from_bytes(&data),
+        Err(Error::HuffmanDecode(
+            HuffmanDecodingError::UnexpectedEndOfMCU {
+                mcu_index: 13,
+                component: 1,
+            }
+        ))
+    );
+}
+
+#[test]
+fn lazy_headers_defer_the_scan() {
+    let lazy = JPEGHeader::new_lazy(GRADIENT).unwrap();
+    assert_eq!(lazy.header().output_dimensions(), (64, 48));
+    assert_eq!(lazy.header().color_space(), ColorSpace::YCbCr);
+
+    let mut header = lazy.decode().unwrap();
+    assert_eq!(
+        header.decode_pixels(),
+        JPEGHeader::from_bytes(GRADIENT).unwrap().decode_pixels()
+    );
+
+    // A truncated scan still gives the metadata
+    let sos = marker_offset(GRADIENT, 0xDA);
+    let data = [&GRADIENT[..sos + 20], &[0xFF, 0xD9]].concat();
+
+    let lazy = JPEGHeader::new_lazy(&data).unwrap();
+    assert_eq!(lazy.header().metadata().width, 64);
+    assert!(matches!(lazy.decode(), Err(Error::HuffmanDecode(_))));
+
+    // Errors in the segments are found right away
+    assert_eq!(
+        JPEGHeader::new_lazy(&GRADIENT[..sos]).unwrap_err(),
+        Error::SOSNotFound
+    );
+}
+
+#[test]
+fn writer_errors_are_reported() {
+    let mut header = JPEGHeader::from_bytes(GRADIENT).unwrap();
+    let mut buffer = [0; 100];
+
+    assert_eq!(
+        header.decode_to_writer(&mut buffer[..], OutputFormat::Ppm),
+        Err(Error::Io(std::io::ErrorKind::WriteZero))
+    );
+}
+
+#[test]
+fn truncated_segments_are_a_premature_end() {
+    // An APP5 segment claiming 16 bytes with only 4 left
+    let data = [0xFF, 0xD8, 0xFF, 0xE5, 0x00, 0x10, 1, 2, 3, 4];
+    assert_eq!(JPEGHeader::from_bytes(&data), Err(Error::PrematureEnd));
+
+    // An unknown APP0 and an unknown APP14 cut short
+    let data = [
+        0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'A', b'V', b'I', b'1', 0, 1,
+    ];
+    assert_eq!(JPEGHeader::from_bytes(&data), Err(Error::PrematureEnd));
+
+    let data = [
+        0xFF, 0xD8, 0xFF, 0xEE, 0x00, 0x10, b'O', b't', b'h', b'e', b'r', 1,
+    ];
+    assert_eq!(JPEGHeader::from_bytes(&data), Err(Error::PrematureEnd));
+
+    // A length too short to count itself
+    let data = [&GRADIENT[..2], &[0xFF, 0xE5, 0x00, 0x01], &GRADIENT[2..]].concat();
+    assert_eq!(JPEGHeader::from_bytes(&data), Err(Error::InvalidMarker));
+}
+
+#[test]
+fn app0_lengths_shorter_than_their_fields_are_rejected() {
+    // Too short for the identifier
+    let data = [
+        0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x02, b'J', b'F', b'I', b'F', 0, 1, 2, 0,
+    ];
+    assert_eq!(JPEGHeader::from_bytes(&data), Err(Error::InvalidAPP0Marker));
+
+    // Room for the identifier but not the JFIF fields
+    let data = [
+        0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x08, b'J', b'F', b'I', b'F', 0, 1, 2, 0, 0, 1, 0, 1, 0, 0,
+    ];
+    assert_eq!(JPEGHeader::from_bytes(&data), Err(Error::InvalidAPP0Marker));
+
+    // A JFXX segment without its extension code
+    let data = [
+        0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x07, b'J', b'F', b'X', b'X', 0, 0x10,
+    ];
+    assert_eq!(JPEGHeader::from_bytes(&data), Err(Error::InvalidAPP0Marker));
+}
+
+#[test]
+fn frame_only_components_need_a_set_qtable() {
+    assert_eq!(
+        JPEGHeader::from_bytes(GRADIENT)
+            .unwrap()
+            .validate_qtable_completeness(),
+        Ok(())
+    );
+
+    let mut data = GRADIENT.to_vec();
+
+    // The scan leaves out the third component of the frame
+    let sos = marker_offset(&data, 0xDA);
+    data[sos + 3] -= 2;
+    data[sos + 4] = 2;
+    data.drain(sos + 9..sos + 11);
+
+    assert_eq!(
+        JPEGHeader::from_bytes(&data),
+        Err(Error::InvalidColorComponent)
+    );
+
+    // and uses table 2, which no DQT sets
+    let sof = marker_offset(&data, 0xC0);
+    data[sof + 18] = 2;
+
+    assert_eq!(
+        JPEGHeader::from_bytes(&data),
+        Err(Error::ComponentQTableMismatch {
+            component_id: 3,
+            qtable_id: 2
+        })
+    );
+}